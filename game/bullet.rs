@@ -1,24 +1,30 @@
 use raylib_wasm::GREEN;
 // use raylib_wasm::PINK;
 
-use crate::entity_manager::{EntityId, HasId, NO_ID};
+use crate::enemy::{self, Enemy};
+use crate::entity_manager::{Behaviour, Effects, EntityId, HasId, NO_ID};
+use crate::path::Paths;
+use crate::status::{StatusEffect, StatusKind};
 use crate::vec2::Vector2;
 
 use crate::u32_bool::Bool;
 use crate::{anim, State};
-use crate::{webhacks, SPEED_BULLET, WINDOW_WIDTH};
+use crate::{webhacks, WINDOW_WIDTH};
 
 // use crate::ACTIVE_RADIUS;
 // use crate::ALPHA_BEIGE;
 // use crate::TURRET_RADIUS;
 use crate::WINDOW_HEIGHT;
 
+// How many enemies a piercing projectile can go through, at most
+pub const MAX_PIERCE: usize = 4;
+
 pub struct BulletUpdate {
     pub id: EntityId,
     pub dead: bool,
     pub position: Vector2,
     pub velocity: Vector2,
-    pub hit_request: Option<HitRequest>,
+    pub hit: [EntityId; MAX_PIERCE],
 }
 
 impl From<&Bullet> for BulletUpdate {
@@ -28,31 +34,147 @@ impl From<&Bullet> for BulletUpdate {
             dead: bullet.dead.into(),
             position: bullet.position,
             velocity: bullet.velocity,
-            hit_request: None,
+            hit: bullet.hit,
         }
     }
 }
 
 pub struct HitRequest {
+    // NO_ID for damage from a status effect
+    pub bullet: EntityId,
+    pub source: EntityId,
     pub target: EntityId,
     pub damage: u32,
+    pub status: Option<StatusEffect>,
 }
 
-impl From<&Bullet> for HitRequest {
-    fn from(bullet: &Bullet) -> Self {
-        HitRequest {
-            target: bullet.target,
-            damage: bullet.damage,
-        }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProjectileKind {
+    Bullet,
+    Slug,
+    Shell,
+    FrostBolt,
+    Dart,
+}
+
+// What a projectile does once it's been fired. None of them steer, so all of them can miss.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flight {
+    // hits the first enemy it touches and is gone
+    Straight,
+    // goes through up to `max_hits` enemies, hitting each of them once
+    Piercing { max_hits: usize },
+    // explodes on the first enemy it touches, or when it gets to where it was aimed, and hits
+    // everything within `splash`
+    Shell { splash: f32 },
+}
+
+pub struct ProjectileArchetype {
+    pub speed: f32,
+    pub radius: f32,
+    pub flight: Flight,
+    // put on everything it hits
+    pub status: Option<StatusEffect>,
+}
+
+// Indexed by `ProjectileKind`
+const PROJECTILES: [ProjectileArchetype; 5] = [
+    ProjectileArchetype {
+        speed: 260.0,
+        radius: 15.0,
+        flight: Flight::Straight,
+        status: None,
+    },
+    ProjectileArchetype {
+        speed: 700.0,
+        radius: 8.0,
+        flight: Flight::Piercing { max_hits: 3 },
+        status: Some(StatusEffect {
+            kind: StatusKind::Stun,
+            duration: 0.25,
+            strength: 0.0,
+        }),
+    },
+    ProjectileArchetype {
+        speed: 200.0,
+        radius: 22.0,
+        flight: Flight::Shell { splash: 60.0 },
+        status: Some(StatusEffect {
+            kind: StatusKind::Burn,
+            duration: 2.0,
+            strength: 1.5,
+        }),
+    },
+    ProjectileArchetype {
+        speed: 300.0,
+        radius: 12.0,
+        flight: Flight::Straight,
+        status: Some(StatusEffect {
+            kind: StatusKind::Slow,
+            duration: 2.0,
+            strength: 0.5,
+        }),
+    },
+    ProjectileArchetype {
+        speed: 450.0,
+        radius: 8.0,
+        flight: Flight::Straight,
+        status: Some(StatusEffect {
+            kind: StatusKind::Poison,
+            duration: 3.0,
+            strength: 1.0,
+        }),
+    },
+];
+
+impl ProjectileKind {
+    pub const ALL: [ProjectileKind; 5] = [
+        ProjectileKind::Bullet,
+        ProjectileKind::Slug,
+        ProjectileKind::Shell,
+        ProjectileKind::FrostBolt,
+        ProjectileKind::Dart,
+    ];
+
+    pub fn archetype(self) -> &'static ProjectileArchetype {
+        &PROJECTILES[self as usize]
+    }
+
+    pub fn from_u32(value: u32) -> Option<ProjectileKind> {
+        ProjectileKind::ALL.get(value as usize).copied()
+    }
+}
+
+// Where to shoot from `from` at `speed` to meet `enemy`, assuming it carries on along the path
+// at its current speed, taking the same branches it will. Refines the guess a few times: aim where the enemy is, see how long
+// the shot takes to get there, aim where the enemy will be by then, and so on.
+pub fn lead_target(paths: &Paths, from: Vector2, enemy: &Enemy, speed: f32) -> Vector2 {
+    let mut aim = enemy.position.xy;
+    for _ in 0..LEAD_ITERATIONS {
+        let time = from.dist(&aim) / speed;
+        aim = paths
+            .advance(enemy.position, enemy.speed() * time, enemy.id)
+            .xy;
     }
+    aim
 }
 
+const LEAD_ITERATIONS: usize = 4;
+
 #[derive(Clone, Debug)]
 pub struct Bullet {
+    pub kind: ProjectileKind,
     pub position: Vector2,
+    // where we were at the previous simulation step. for interpolating when drawing
+    pub prev_position: Vector2,
     pub velocity: Vector2,
+    // where it was aimed at. shells go off here
+    pub aim: Vector2,
     pub source: EntityId,
+    // what it was fired at. only for the debug overlay, the hits are whatever it runs into
     pub target: EntityId,
+    // enemies hit so far, NO_ID for the unused ones
+    pub hit: [EntityId; MAX_PIERCE],
     pub dead: Bool,
     pub id: EntityId,
     pub damage: u32,
@@ -61,43 +183,110 @@ pub struct Bullet {
 }
 
 impl Bullet {
-    pub fn new(position: Vector2, source: EntityId, target: Option<EntityId>) -> Bullet {
+    pub fn new(
+        kind: ProjectileKind,
+        position: Vector2,
+        source: EntityId,
+        target: Option<EntityId>,
+    ) -> Bullet {
         Bullet {
+            kind,
             position,
+            prev_position: position,
             velocity: Vector2::zero(),
+            aim: position,
             source: source,
             target: target.unwrap_or(NO_ID),
+            hit: [NO_ID; MAX_PIERCE],
             dead: false.into(),
             damage: 1,
             id: NO_ID,
             anim: None,
-            radius: 15.0,
+            radius: kind.archetype().radius,
         }
     }
 
-    pub fn update(&self, state: &State) -> BulletUpdate {
-        let dt = state.dt();
+    // Fired from `position` at where `enemy` is going to be
+    pub fn aimed(
+        kind: ProjectileKind,
+        position: Vector2,
+        source: EntityId,
+        enemy: &Enemy,
+        paths: &Paths,
+    ) -> Bullet {
+        let speed = kind.archetype().speed;
+        let mut bullet = Bullet::new(kind, position, source, Some(enemy.id));
+        bullet.aim = lead_target(paths, position, enemy, speed);
+        bullet.velocity = (bullet.aim - position).normalize() * speed;
+        bullet
+    }
 
-        let mut update = BulletUpdate::from(self);
+    // Position between the last two simulation steps. `alpha` is in [0, 1].
+    pub fn draw_position(&self, alpha: f32) -> Vector2 {
+        self.prev_position.lerp(&self.position, alpha)
+    }
 
-        let target: Option<(Vector2, f32)> = state.man.get_enemy(self.target).map(|enemy| {
-            let direction = enemy.position.xy - self.position;
-            (direction, enemy.radius)
-        });
+    pub fn hit_request(&self, target: EntityId) -> HitRequest {
+        HitRequest {
+            bullet: self.id,
+            source: self.source,
+            target,
+            damage: self.damage,
+            status: self.kind.archetype().status,
+        }
+    }
+}
+
+// Enemies overlapping a projectile at `position`, in a stable order
+fn touching(state: &State, position: Vector2) -> Vec<&Enemy> {
+    let mut enemies = state.man.enemies_within(position, enemy::MAX_RADIUS);
+    enemies.retain(|enemy| enemy.position.xy.dist(&position) < enemy.radius);
+    enemies.sort_by_key(|enemy| enemy.id);
+    enemies
+}
+
+impl Behaviour for Bullet {
+    type Update = BulletUpdate;
 
-        match target {
-            Some((direction, radius)) => {
-                let velocity = direction.normalize() * SPEED_BULLET;
-                update.velocity = velocity;
-                update.position += velocity * dt;
+    fn update(&self, state: &State, effects: &mut Effects) -> BulletUpdate {
+        let dt = state.dt();
+
+        let mut update = BulletUpdate::from(self);
+        update.position += self.velocity * dt;
 
-                if direction.mag() < radius {
-                    update.hit_request = Some(HitRequest::from(self));
+        match self.kind.archetype().flight {
+            Flight::Straight => {
+                if let Some(enemy) = touching(state, update.position).first() {
+                    effects.hits.push(self.hit_request(enemy.id));
                     update.dead = true;
                 }
             }
-            None => {
-                update.dead = true;
+            Flight::Piercing { max_hits } => {
+                let max_hits = max_hits.min(MAX_PIERCE);
+                for enemy in touching(state, update.position) {
+                    let n_hit = update.hit.iter().filter(|&&id| id != NO_ID).count();
+                    if n_hit >= max_hits {
+                        break;
+                    }
+                    if !update.hit.contains(&enemy.id) {
+                        effects.hits.push(self.hit_request(enemy.id));
+                        update.hit[n_hit] = enemy.id;
+                    }
+                }
+                if update.hit[max_hits - 1] != NO_ID {
+                    update.dead = true;
+                }
+            }
+            Flight::Shell { splash } => {
+                let arrived = (self.aim - update.position).dot(&self.velocity) <= 0.0;
+                if arrived || !touching(state, update.position).is_empty() {
+                    let mut caught = state.man.enemies_within(update.position, splash);
+                    caught.sort_by_key(|enemy| enemy.id);
+                    for enemy in caught {
+                        effects.hits.push(self.hit_request(enemy.id));
+                    }
+                    update.dead = true;
+                }
             }
         }
 
@@ -112,31 +301,43 @@ impl Bullet {
         update
     }
 
-    pub fn apply(&mut self, update: &BulletUpdate) {
+    fn apply(&mut self, update: &BulletUpdate) {
         debug_assert_eq!(self.id, update.id);
+        self.prev_position = self.position;
         self.position = update.position;
         self.velocity = update.velocity;
+        self.hit = update.hit;
         self.dead = update.dead.into();
     }
 
-    pub fn draw_debug(&self, state: &State) {
+    fn is_dead(&self) -> bool {
+        self.dead.into()
+    }
+
+    fn draw_debug(&self, state: &State, alpha: f32) {
         match state.man.get_enemy(self.target) {
             Some(target) => {
-                webhacks::draw_line_ex(self.position, target.position.into(), 2.0, GREEN);
+                webhacks::draw_line_ex(
+                    self.draw_position(alpha),
+                    target.draw_position(alpha),
+                    2.0,
+                    GREEN,
+                );
             }
             None => {}
         }
         // webhacks::draw_circle(self.position, ACTIVE_RADIUS, ALPHA_BEIGE);
     }
 
-    pub fn draw_foreground(&self, state: &State) {
+    fn draw_foreground(&self, state: &State, alpha: f32) {
+        let position = self.draw_position(alpha);
         match self.anim {
             Some(ref anim) => {
                 // anim.draw(self.position, state.curr_time);
 
                 let scale = (2.0 * self.radius) / (anim.meta.avg_width).max(anim.meta.avg_height);
                 anim.draw(
-                    self.position,
+                    position,
                     scale,
                     crate::anim::Anchor::Center,
                     self.velocity.angle(),
@@ -144,7 +345,7 @@ impl Bullet {
                 );
             }
             None => {
-                webhacks::draw_circle(self.position, self.radius, GREEN);
+                webhacks::draw_circle(position, self.radius, GREEN);
             }
         }
     }
@@ -159,3 +360,29 @@ impl HasId for Bullet {
         self.id = id;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enemy::EnemyKind;
+    use crate::path::Path;
+
+    #[test]
+    fn test_lead_target_meets_the_enemy() {
+        let paths = Paths::new(vec![Path::new(vec![
+            Vector2::new(0.0, 0.0),
+            Vector2::new(300.0, 0.0),
+            Vector2::new(300.0, 300.0),
+        ])]);
+        let enemy = Enemy::new(EnemyKind::Slime, paths.lin_to_position(0, 100.0), 0.0);
+        let from = Vector2::new(100.0, 200.0);
+        let speed = 400.0;
+
+        let aim = lead_target(&paths, from, &enemy, speed);
+        // by the time the shot gets to `aim`, the enemy is there too
+        let time = from.dist(&aim) / speed;
+        let enemy_then = paths.lin_to_position(0, 100.0 + enemy.speed() * time).xy;
+        assert!(aim.dist(&enemy_then) < 1.0, "{:?} vs {:?}", aim, enemy_then);
+        assert!(aim.x > enemy.position.xy.x);
+    }
+}
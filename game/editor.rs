@@ -0,0 +1,222 @@
+// Level editing, while the game is paused with `E`.
+//
+// There are two tools, switched with `N`:
+//
+//   path     - click empty space to add a node at the end of the path ending closest to it,
+//              click a segment to insert a node into it, drag a node to move it, `X` deletes
+//              the node under the mouse
+//   turrets  - click empty space to place a turret of the selected build type for free, drag
+//              one to move it, `X` removes the one under the mouse
+//
+// With snapping on (`G`), everything lands on the centers of the grid cells.
+
+use raylib_wasm::KeyboardKey as KEY;
+
+use crate::entity_manager::{EntityId, NO_ID};
+use crate::input::FrameInput;
+use crate::placement;
+use crate::turret::{Turret, TurretKind};
+use crate::vec2::Vector2;
+use crate::State;
+
+// How close the mouse has to be to a node or a segment to pick it
+pub const NODE_PICK_RADIUS: f32 = 10.0;
+pub const SEGMENT_PICK_RADIUS: f32 = 6.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EditorTool {
+    Path,
+    Turrets,
+}
+
+impl EditorTool {
+    pub fn name(self) -> &'static str {
+        match self {
+            EditorTool::Path => "path",
+            EditorTool::Turrets => "turrets",
+        }
+    }
+}
+
+// What is being dragged around while the mouse button is held
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Drag {
+    None,
+    // (path, node)
+    Node(usize, usize),
+    Turret(EntityId),
+}
+
+// The node actions take the index of the path first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EditorAction {
+    AddNode(usize, Vector2),
+    // the new node goes at this index, pushing the rest back
+    InsertNode(usize, usize, Vector2),
+    MoveNode(usize, usize, Vector2),
+    DeleteNode(usize, usize),
+    AddTurret(TurretKind, Vector2),
+    MoveTurret(EntityId, Vector2),
+    RemoveTurret(EntityId),
+}
+
+pub struct EditorUpdate {
+    pub tool: EditorTool,
+    pub drag: Drag,
+    pub actions: Vec<EditorAction>,
+}
+
+// (path, node) of the path node under the mouse
+pub fn hovered_node(state: &State) -> Option<(usize, usize)> {
+    state.paths.paths.iter().enumerate().find_map(|(i, path)| {
+        path.nodes
+            .iter()
+            .position(|n| n.dist(&state.mouse_pos) < NODE_PICK_RADIUS)
+            .map(|node| (i, node))
+    })
+}
+
+// Index of the path whose last node is closest to `position`
+fn nearest_end(state: &State, position: Vector2) -> usize {
+    let distance = |i: &usize| state.paths.paths[*i].end_xy().dist(&position);
+    (0..state.paths.paths.len())
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+        .unwrap_or(0)
+}
+
+pub fn hovered_turret(state: &State) -> EntityId {
+    state
+        .man
+        .turrets
+        .iter()
+        .find(|t| t.position.dist(&state.mouse_pos) < t.radius)
+        .map_or(NO_ID, |t| t.id)
+}
+
+pub fn handle_editor(state: &State, input: &FrameInput) -> EditorUpdate {
+    let mut update = EditorUpdate {
+        tool: state.editor_tool,
+        drag: state.editor_drag,
+        actions: Vec::new(),
+    };
+
+    if input.is_pressed(KEY::N) {
+        update.tool = match update.tool {
+            EditorTool::Path => EditorTool::Turrets,
+            EditorTool::Turrets => EditorTool::Path,
+        };
+        update.drag = Drag::None;
+        return update;
+    }
+
+    let position = if state.snap.into() {
+        placement::snap(state.mouse_pos)
+    } else {
+        state.mouse_pos
+    };
+    let pressed: bool = state.mouse_btn_pressed.into();
+    let delete = input.is_pressed(KEY::X);
+
+    match update.tool {
+        EditorTool::Path => {
+            let node = hovered_node(state);
+            if pressed {
+                let (path, segment, closest, distance) =
+                    state.paths.nearest_segment(state.mouse_pos);
+                if let Some((path, i)) = node {
+                    update.drag = Drag::Node(path, i);
+                } else if distance < SEGMENT_PICK_RADIUS {
+                    update
+                        .actions
+                        .push(EditorAction::InsertNode(path, segment + 1, closest));
+                    update.drag = Drag::Node(path, segment + 1);
+                } else {
+                    let path = nearest_end(state, position);
+                    update.actions.push(EditorAction::AddNode(path, position));
+                }
+            } else if delete {
+                // a path needs both ends
+                let node = node.filter(|&(path, _)| state.paths.paths[path].nodes.len() > 2);
+                if let Some((path, i)) = node {
+                    update.actions.push(EditorAction::DeleteNode(path, i));
+                    update.drag = Drag::None;
+                }
+            }
+        }
+        EditorTool::Turrets => {
+            let turret = hovered_turret(state);
+            if pressed {
+                if turret != NO_ID {
+                    update.drag = Drag::Turret(turret);
+                } else {
+                    update
+                        .actions
+                        .push(EditorAction::AddTurret(state.build_kind, position));
+                }
+            } else if delete && turret != NO_ID {
+                update.actions.push(EditorAction::RemoveTurret(turret));
+                update.drag = Drag::None;
+            }
+        }
+    }
+
+    // follow the mouse until the button is let go. not on the click itself, so that just
+    // clicking a node doesn't move it
+    if (!state.mouse_btn).into() {
+        update.drag = Drag::None;
+    } else if !pressed {
+        match update.drag {
+            Drag::Node(path, i) => update
+                .actions
+                .push(EditorAction::MoveNode(path, i, position)),
+            Drag::Turret(id) => update.actions.push(EditorAction::MoveTurret(id, position)),
+            Drag::None => {}
+        }
+    }
+
+    update
+}
+
+pub fn apply_editor_update(state: &mut State, update: EditorUpdate) {
+    state.editor_tool = update.tool;
+    state.editor_drag = update.drag;
+
+    let mut nodes = state
+        .paths
+        .paths
+        .iter()
+        .map(|p| p.nodes.clone())
+        .collect::<Vec<_>>();
+    for action in update.actions {
+        match action {
+            EditorAction::AddNode(path, position) => nodes[path].push(position),
+            EditorAction::InsertNode(path, i, position) => nodes[path].insert(i, position),
+            EditorAction::MoveNode(path, i, position) => nodes[path][i] = position,
+            EditorAction::DeleteNode(path, i) => {
+                nodes[path].remove(i);
+            }
+            EditorAction::AddTurret(kind, position) => {
+                let mut turret = Turret::new(kind, position);
+                turret.preset = true.into();
+                turret.anim = Some(state.turret_anim_for(kind.archetype().sprite).clone());
+                state.man.add(turret.into());
+            }
+            EditorAction::MoveTurret(id, position) => {
+                if let Some(turret) = state.man.get_turret_mut(id) {
+                    turret.position = position;
+                }
+            }
+            EditorAction::RemoveTurret(id) => {
+                if let Some(turret) = state.man.get_turret_mut(id) {
+                    turret.dead = true.into();
+                }
+                state.man.filter_dead();
+            }
+        }
+    }
+    for (i, nodes) in nodes.into_iter().enumerate() {
+        if nodes != state.paths.paths[i].nodes {
+            state.paths.set_nodes(i, nodes);
+        }
+    }
+}
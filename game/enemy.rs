@@ -6,18 +6,19 @@ use crate::webhacks;
 use raylib_wasm::{PINK, RAYWHITE, RED};
 
 use crate::anim::Anim;
-use crate::entity_manager::{EntityId, HasId};
+use crate::bullet::HitRequest;
+use crate::entity_manager::{Behaviour, Effects, EntityId, HasId, NO_ID};
 use crate::path::PathPosition;
+use crate::status::{StatusKind, Statuses};
 use crate::State;
 use crate::ACTIVE_RADIUS;
 // use crate::ALPHA_BEIGE;
-use crate::SPEED_ENEMY;
 
 pub struct EnemyUpdate {
     pub id: EntityId,
     pub position: PathPosition,
     pub dead: bool,
-    pub damage_done: u32,
+    pub statuses: Statuses,
 }
 
 impl From<&Enemy> for EnemyUpdate {
@@ -26,15 +27,116 @@ impl From<&Enemy> for EnemyUpdate {
             id: enemy.id,
             position: enemy.position,
             dead: enemy.dead.into(),
-            damage_done: 0,
+            statuses: enemy.statuses,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnemyKind {
+    Slime,
+    Runner,
+    Brute,
+    Swarmling,
+}
+
+// Which of the state's anims an enemy is drawn with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnemySprite {
+    Slime,
+}
+
+pub struct EnemyArchetype {
+    pub name: &'static str,
+    pub max_health: u32,
+    pub speed: f32,
+    pub radius: f32,
+    pub sprite: EnemySprite,
+    // lives taken when it gets to the end of the path
+    pub life_damage: u32,
+    // gold for killing it
+    pub bounty: u32,
+}
+
+// Indexed by `EnemyKind`
+const ARCHETYPES: [EnemyArchetype; 4] = [
+    EnemyArchetype {
+        name: "slime",
+        max_health: 3,
+        speed: 210.0,
+        radius: 20.0,
+        sprite: EnemySprite::Slime,
+        life_damage: 1,
+        bounty: 5,
+    },
+    EnemyArchetype {
+        name: "runner",
+        max_health: 2,
+        speed: 360.0,
+        radius: 14.0,
+        sprite: EnemySprite::Slime,
+        life_damage: 1,
+        bounty: 6,
+    },
+    EnemyArchetype {
+        name: "brute",
+        max_health: 12,
+        speed: 120.0,
+        radius: 30.0,
+        sprite: EnemySprite::Slime,
+        life_damage: 3,
+        bounty: 15,
+    },
+    EnemyArchetype {
+        name: "swarmling",
+        max_health: 1,
+        speed: 250.0,
+        radius: 10.0,
+        sprite: EnemySprite::Slime,
+        life_damage: 1,
+        bounty: 1,
+    },
+];
+
+// Of all the archetypes. For finding everything which might overlap a point
+pub const MAX_RADIUS: f32 = {
+    let mut max = 0.0;
+    let mut i = 0;
+    while i < ARCHETYPES.len() {
+        if ARCHETYPES[i].radius > max {
+            max = ARCHETYPES[i].radius;
+        }
+        i += 1;
+    }
+    max
+};
+
+impl EnemyKind {
+    pub const ALL: [EnemyKind; 4] = [
+        EnemyKind::Slime,
+        EnemyKind::Runner,
+        EnemyKind::Brute,
+        EnemyKind::Swarmling,
+    ];
+
+    pub fn archetype(self) -> &'static EnemyArchetype {
+        &ARCHETYPES[self as usize]
+    }
+
+    pub fn from_u32(value: u32) -> Option<EnemyKind> {
+        EnemyKind::ALL.get(value as usize).copied()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Enemy {
+    pub kind: EnemyKind,
     pub position: PathPosition,
+    // where we were at the previous simulation step. for interpolating when drawing
+    pub prev_xy: Vector2,
     pub health: u32,
+    pub max_health: u32,
+    pub statuses: Statuses,
 
     pub spawn_time: f32,
 
@@ -47,59 +149,121 @@ pub struct Enemy {
 }
 
 impl Enemy {
-    pub fn new(position: PathPosition, time: f32) -> Enemy {
+    pub fn new(kind: EnemyKind, position: PathPosition, time: f32) -> Enemy {
+        let archetype = kind.archetype();
         Enemy {
+            kind,
             position: position,
-            health: 3,
+            prev_xy: position.xy,
+            health: archetype.max_health,
+            max_health: archetype.max_health,
+            statuses: Statuses::new(),
             spawn_time: time,
             dead: false.into(),
             id: 0,
-            radius: 20.0,
+            radius: archetype.radius,
             anim: None,
         }
     }
 
-    pub fn update(&self, state: &State) -> EnemyUpdate {
+    pub fn archetype(&self) -> &'static EnemyArchetype {
+        self.kind.archetype()
+    }
+
+    // Along the path, per second. Slowed down or stopped by status effects.
+    pub fn speed(&self) -> f32 {
+        self.archetype().speed * self.statuses.speed_factor()
+    }
+
+    // Position between the last two simulation steps. `alpha` is in [0, 1].
+    pub fn draw_position(&self, alpha: f32) -> Vector2 {
+        self.prev_xy.lerp(&self.position.xy, alpha)
+    }
+
+    pub fn hit(&mut self, damage: u32) {
+        self.health -= std::cmp::min(self.health, damage);
+        if self.health == 0 {
+            self.dead = true.into();
+        }
+    }
+}
+
+impl Behaviour for Enemy {
+    type Update = EnemyUpdate;
+
+    fn update(&self, state: &State, effects: &mut Effects) -> EnemyUpdate {
         let mut update = EnemyUpdate::from(self);
+
+        let (statuses, damage) = self.statuses.tick(state.dt());
+        update.statuses = statuses;
+        for d in damage {
+            effects.hits.push(HitRequest {
+                bullet: NO_ID,
+                source: d.source,
+                target: self.id,
+                damage: d.damage,
+                status: None,
+            });
+        }
         // update.path_position += SPEED_ENEMY * state.dt();
-        update
-            .position
-            .linear_advance(&state.path, SPEED_ENEMY * state.dt());
+        // the id picks the branches at forks
+        update.position = state
+            .paths
+            .advance(self.position, self.speed() * state.dt(), self.id);
 
-        if update.position.linear >= state.path.total_length {
+        if state.paths.is_exit(&update.position) {
             update.dead = true;
-            update.damage_done += 1;
+            effects.life_lost += self.archetype().life_damage;
         }
 
         update
     }
 
-    pub fn apply(&mut self, update: &EnemyUpdate) {
+    fn apply(&mut self, update: &EnemyUpdate) {
         debug_assert_eq!(self.id, update.id);
         self.dead = update.dead.into();
+        self.statuses = update.statuses;
+        self.prev_xy = self.position.xy;
         self.position = update.position;
     }
 
-    pub fn draw_debug(&self, _state: &State) {
-        webhacks::draw_circle(self.position.xy, 0.5, RED);
+    fn is_dead(&self) -> bool {
+        self.dead.into()
     }
 
-    pub fn draw_foreground(&self, state: &State) {
+    fn draw_debug(&self, _state: &State, alpha: f32) {
+        webhacks::draw_circle(self.draw_position(alpha), 0.5, RED);
+    }
+
+    fn draw_foreground(&self, state: &State, alpha: f32) {
+        let position = self.draw_position(alpha);
+
         // draw health bar
         let width = self.radius * 2.0 * 1.5;
-        let pos = self.position.xy + Vector2::new(-width / 2.0, -(self.radius * 1.5));
-        let width = width * (self.health as f32 / 3.0);
+        let pos = position + Vector2::new(-width / 2.0, -(self.radius * 1.5));
+        let width = width * (self.health as f32 / self.max_health.max(1) as f32);
         webhacks::draw_line_ex(pos, pos + Vector2::new(width, 0.0), 5.0, RED);
 
+        // and a dot above it for each status effect
+        let kinds = StatusKind::ALL
+            .into_iter()
+            .filter(|&k| self.statuses.has(k));
+        for (i, kind) in kinds.enumerate() {
+            let dot = pos + Vector2::new(4.0 + 10.0 * i as f32, -8.0);
+            webhacks::draw_circle(dot, 4.0, kind.color());
+        }
+
         match self.anim {
             Some(ref anim) => {
                 // anim.draw(self.position, state.curr_time);
                 let scale = (2.0 * self.radius) / (anim.meta.avg_width).max(anim.meta.avg_height);
+                // facing the way it's going
+                let rotation = self.position.tangent.angle();
                 anim.draw(
-                    self.position.into(),
+                    position,
                     scale,
                     crate::anim::Anchor::Center,
-                    0.0,
+                    rotation,
                     state.curr_time,
                 );
             }
@@ -110,18 +274,11 @@ impl Enemy {
                 } else {
                     RAYWHITE
                 };
-                webhacks::draw_circle(self.position.into(), self.radius, color);
+                webhacks::draw_circle(position, self.radius, color);
                 // webhacks::draw_circle(self.position, self.radius, RAYWHITE);
             }
         }
     }
-
-    pub fn hit(&mut self, damage: u32) {
-        self.health -= std::cmp::min(self.health, damage);
-        if self.health == 0 {
-            self.dead = true.into();
-        }
-    }
 }
 
 impl HasId for Enemy {
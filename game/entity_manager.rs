@@ -1,20 +1,64 @@
+// Entity ids are generational. The low 16 bits are the index of a slot in the manager, and
+// the high 16 bits count how many times that slot has been handed out. When an entity dies its
+// slot is reused, but with the next generation, so an old id (e.g. a bullet's target) never
+// matches the new entity. A slot whose generation has run out is retired instead of wrapping.
 pub type EntityId = u32;
 
 pub const NO_ID: EntityId = 0;
 
-use crate::bullet::Bullet;
-use crate::enemy::Enemy;
-use crate::turret::Turret;
+const INDEX_BITS: u32 = 16;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+const MAX_GENERATION: u32 = u16::MAX as u32;
+
+fn make_id(index: usize, generation: u32) -> EntityId {
+    (generation << INDEX_BITS) | index as u32
+}
+
+fn id_index(id: EntityId) -> usize {
+    (id & INDEX_MASK) as usize
+}
+
+fn id_generation(id: EntityId) -> u32 {
+    id >> INDEX_BITS
+}
+
+use crate::bullet::{Bullet, BulletUpdate, HitRequest, ProjectileKind, MAX_PIERCE};
+use crate::enemy::{Enemy, EnemyKind, EnemyUpdate};
+use crate::path::{PathPosition, Paths};
+use crate::spatial::{Grid, CELL_SIZE};
+use crate::status::{Statuses, STATUSES_WORDS};
+use crate::turret::{Targeting, Turret, TurretKind, TurretUpdate};
 use crate::vec2::Vector2;
-use crate::webhacks;
-use std::collections::HashSet;
+use crate::words::{DecodeError, Reader, Writer};
+use crate::State;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Kind {
+    Turret,
+    Enemy,
+    Bullet,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Slot {
+    // generation of the current (or last) entity in this slot. starts at 1, so no id is NO_ID
+    pub(crate) generation: u32,
+    // which list the entity is in and where. None when the slot is free
+    pub(crate) entry: Option<(Kind, usize)>,
+}
 
 // #[derive(Clone, Debug)]
 pub struct EntityManager {
+    // Free to iterate and modify in place, but entities only get in and out through `add` and
+    // `filter_dead`, since the slots keep track of where everything is.
     pub turrets: Vec<Turret>,
     pub enemies: Vec<Enemy>,
     pub bullets: Vec<Bullet>,
-    pub ids: HashSet<EntityId>,
+    pub(crate) slots: Vec<Slot>,
+    // free slot indices. the last one is handed out next
+    pub(crate) free: Vec<usize>,
+    // enemy positions as of the last `rebuild_index`. indices into `enemies`
+    pub(crate) enemy_grid: Grid,
 }
 
 use std::fmt::Display;
@@ -29,15 +73,88 @@ impl Display for EntityManager {
     }
 }
 
-fn random_id() -> EntityId {
-    webhacks::get_random_value(0, 1000000) as EntityId
-}
-
 pub trait HasId {
     fn id(&self) -> EntityId;
     fn set_id(&mut self, id: EntityId);
 }
 
+// What every entity kind does each simulation step. `update` looks at the state, which is
+// read-only at that point, and works out what should happen to the entity. `apply` then makes
+// it so. Anything which reaches outside the entity itself (damage to others, new entities,
+// lives lost) goes into `Effects` instead, and gets handled once all the updates are in.
+pub trait Behaviour: HasId {
+    type Update;
+
+    fn update(&self, state: &State, effects: &mut Effects) -> Self::Update;
+    fn apply(&mut self, update: &Self::Update);
+    fn is_dead(&self) -> bool;
+
+    fn draw_debug(&self, _state: &State, _alpha: f32) {}
+    fn draw_foreground(&self, state: &State, alpha: f32);
+}
+
+pub struct Effects {
+    pub life_lost: u32,
+    pub hits: Vec<HitRequest>,
+    pub spawns: Vec<Entity>,
+    // some entity reacted to this step's click, so it shouldn't place a turret as well
+    pub click_used: bool,
+    // the turret this step's click landed on
+    pub selected: Option<EntityId>,
+    pub upgrades: Vec<TurretUpgrade>,
+    // gold already committed to purchases this step, which later ones can't spend again
+    pub gold_spent: u32,
+}
+
+// A turret bought its next tier this step
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TurretUpgrade {
+    pub id: EntityId,
+    pub tier: u32,
+    pub cost: u32,
+}
+
+impl Effects {
+    pub fn new() -> Effects {
+        Effects {
+            life_lost: 0,
+            hits: Vec::new(),
+            spawns: Vec::new(),
+            click_used: false,
+            selected: None,
+            upgrades: Vec::new(),
+            gold_spent: 0,
+        }
+    }
+}
+
+// The updates for all the entities, in the same order as the lists in the manager
+pub struct Updates {
+    turrets: Vec<TurretUpdate>,
+    enemies: Vec<EnemyUpdate>,
+    bullets: Vec<BulletUpdate>,
+}
+
+fn update_all<T: Behaviour>(list: &[T], state: &State, effects: &mut Effects) -> Vec<T::Update> {
+    list.iter().map(|e| e.update(state, effects)).collect()
+}
+
+fn apply_all<T: Behaviour>(list: &mut [T], updates: &[T::Update]) {
+    debug_assert_eq!(list.len(), updates.len());
+    std::iter::zip(list.iter_mut(), updates.iter()).for_each(|(e, update)| e.apply(update));
+}
+
+// Drop the dead ones and hand back their ids
+fn remove_dead<T: Behaviour>(list: &mut Vec<T>) -> Vec<EntityId> {
+    let dead = list
+        .iter()
+        .filter(|e| e.is_dead())
+        .map(|e| e.id())
+        .collect();
+    list.retain(|e| !e.is_dead());
+    dead
+}
+
 #[derive(Debug)]
 pub enum Entity {
     Turret(Turret),
@@ -105,213 +222,708 @@ impl EntityManager {
             turrets: Vec::new(),
             enemies: Vec::new(),
             bullets: Vec::new(),
-            ids: HashSet::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            enemy_grid: Grid::new(CELL_SIZE),
         }
     }
 
-    pub fn to_state(self) -> Box<[u32]> {
-        let mut state = Vec::new();
-
-        // let ptr = self.turrets_arr;
-        // let len = self.turrets_n;
-        let ptr = self.turrets.as_ptr();
-        let len = self.turrets.len() as u32;
-        let cap = self.turrets.capacity() as u32;
-        let size = std::mem::size_of::<Turret>();
-        debug_assert!(size % 4 == 0);
-
-        state.push(len);
-        state.push(cap);
-        if len > 0 {
-            state.extend_from_slice(unsafe {
-                std::slice::from_raw_parts(ptr as *const u32, len as usize * size / 4)
-            });
-        }
-
-        // let ptr = self.enemies_arr;
-        // let len = self.enemies_n;
-        let ptr = self.enemies.as_ptr();
-        let len = self.enemies.len() as u32;
-        let cap = self.enemies.capacity() as u32;
-        let size = std::mem::size_of::<Enemy>();
-        debug_assert!(size % 4 == 0);
-
-        state.push(len);
-        state.push(cap);
-        if len > 0 {
-            state.extend_from_slice(unsafe {
-                std::slice::from_raw_parts(ptr as *const u32, len as usize * size / 4)
-            });
-        }
-
-        // let ptr = self.bullets_arr;
-        // let len = self.bullets_n;
-        let ptr = self.bullets.as_ptr();
-        let len = self.bullets.len() as u32;
-        let cap = self.bullets.capacity() as u32;
-        let size = std::mem::size_of::<Bullet>();
-        debug_assert!(size % 4 == 0);
-
-        state.push(len);
-        state.push(cap);
-        if len > 0 {
-            state.extend_from_slice(unsafe {
-                std::slice::from_raw_parts(ptr as *const u32, len as usize * size / 4)
-            });
-        }
-
-        // forget about the Vecs so they don't get dropped
-        std::mem::forget(self.turrets);
-        std::mem::forget(self.enemies);
-        std::mem::forget(self.bullets);
-
-        state.into_boxed_slice()
+    // Flat u32 encoding of all the entities, for save files and the like. Asset handles
+    // (anims) are not included, the caller has to re-attach them after `from_state`.
+    //
+    //   n_slots | generations.. | n_free | free.. |
+    //   n_turrets | turrets.. | n_enemies | enemies.. | n_bullets | bullets..
+    pub fn to_state(&self) -> Box<[u32]> {
+        let mut w = Writer::new();
+        w.u32(self.slots.len() as u32);
+        self.slots.iter().for_each(|slot| w.u32(slot.generation));
+        w.u32(self.free.len() as u32);
+        self.free.iter().for_each(|&index| w.u32(index as u32));
+
+        w.u32(self.turrets.len() as u32);
+        self.turrets.iter().for_each(|t| write_turret(&mut w, t));
+        w.u32(self.enemies.len() as u32);
+        self.enemies.iter().for_each(|e| write_enemy(&mut w, e));
+        w.u32(self.bullets.len() as u32);
+        self.bullets.iter().for_each(|b| write_bullet(&mut w, b));
+        w.words.into_boxed_slice()
     }
 }
 
 impl EntityManager {
-    pub fn from_state(state: &[u32]) -> EntityManager {
+    // Inverse of `to_state`. The whole of `state` has to be used up.
+    pub fn from_state(state: &[u32]) -> Result<EntityManager, DecodeError> {
+        let mut r = Reader::new(state);
         let mut em = EntityManager::new();
 
-        let mut offset = 0;
-        let len = state[offset + 0] as usize;
-        let cap = state[offset + 1] as usize;
-        offset += 2;
-        let size = std::mem::size_of::<Turret>();
-        let data = &state[offset..offset + len * size / 4];
-        offset += len * size / 4;
-
-        let ptr = data.as_ptr() as *mut Turret;
-        em.turrets = unsafe { Vec::from_raw_parts(ptr, len, cap) };
-
-        for turret in em.turrets.iter() {
-            em.ids.insert(turret.id);
+        let n = r.len(1)?;
+        if n > INDEX_MASK as usize + 1 {
+            return Err(DecodeError::Invalid("too many entity slots"));
+        }
+        for _ in 0..n {
+            let generation = r.u32()?;
+            if generation > MAX_GENERATION {
+                return Err(DecodeError::Invalid("entity slot generation out of range"));
+            }
+            em.slots.push(Slot {
+                generation,
+                entry: None,
+            });
+        }
+        let n = r.len(1)?;
+        for _ in 0..n {
+            let index = r.u32()? as usize;
+            if index >= em.slots.len() {
+                return Err(DecodeError::Invalid("free entity slot out of range"));
+            }
+            em.free.push(index);
         }
 
-        let len = state[offset + 0] as usize;
-        let cap = state[offset + 1] as usize;
-        offset += 2;
-        let size = std::mem::size_of::<Enemy>();
-        let data = &state[offset..offset + len * size / 4];
-        offset += len * size / 4;
-
-        let ptr = data.as_ptr() as *mut Enemy;
-        em.enemies = unsafe { Vec::from_raw_parts(ptr, len, cap) };
+        let n = r.len(TURRET_WORDS)?;
+        for _ in 0..n {
+            em.add_decoded(read_turret(&mut r)?.into())?;
+        }
+        let n = r.len(ENEMY_WORDS)?;
+        for _ in 0..n {
+            em.add_decoded(read_enemy(&mut r)?.into())?;
+        }
+        let n = r.len(BULLET_WORDS)?;
+        for _ in 0..n {
+            em.add_decoded(read_bullet(&mut r)?.into())?;
+        }
 
-        for enemy in em.enemies.iter() {
-            em.ids.insert(enemy.id);
+        if r.remaining() != 0 {
+            return Err(DecodeError::Invalid("trailing data after entities"));
         }
 
-        let len = state[offset + 0] as usize;
-        let cap = state[offset + 1] as usize;
-        offset += 2;
+        em.rebuild_index();
 
-        let size = std::mem::size_of::<Bullet>();
-        let data = &state[offset..offset + len * size / 4];
+        let mut seen = vec![false; em.slots.len()];
+        for &index in em.free.iter() {
+            if seen[index] || em.slots[index].entry.is_some() {
+                return Err(DecodeError::Invalid("entity slot both free and in use"));
+            }
+            seen[index] = true;
+        }
 
-        // #[allow(unused_assignments)]
-        // offset += len * size / 4;
+        Ok(em)
+    }
 
-        let ptr = data.as_ptr() as *mut Bullet;
-        em.bullets = unsafe { Vec::from_raw_parts(ptr, len, cap) };
+    // Put an entity back in the slot its id says, rather than handing out a new one
+    fn add_decoded(&mut self, entity: Entity) -> Result<(), DecodeError> {
+        let id = entity.id();
+        match self.slots.get(id_index(id)) {
+            Some(slot)
+                if id != NO_ID && slot.generation == id_generation(id) && slot.entry.is_none() => {}
+            _ => return Err(DecodeError::Invalid("bad or duplicate entity id")),
+        }
+        self.push(entity);
+        Ok(())
+    }
 
-        for bullet in em.bullets.iter() {
-            em.ids.insert(bullet.id);
+    fn alloc_id(&mut self) -> EntityId {
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index];
+            slot.generation += 1;
+            return make_id(index, slot.generation);
         }
 
-        em
+        let index = self.slots.len();
+        assert!(index <= INDEX_MASK as usize, "out of entity slots");
+        self.slots.push(Slot {
+            generation: 1,
+            entry: None,
+        });
+        make_id(index, 1)
     }
 
-    fn gen_id(&self) -> EntityId {
-        let mut id = random_id();
-        while self.ids.contains(&id) {
-            id = random_id();
-        }
+    // Adds the entity under a new id, which is returned. Any id it already has is replaced.
+    pub fn add(&mut self, mut entity: Entity) -> EntityId {
+        let id = self.alloc_id();
+        entity.set_id(id);
+        self.push(entity);
         id
     }
 
-    pub fn add(&mut self, mut entity: Entity) {
-        if entity.id() == 0 {
-            let id = self.gen_id();
-            entity.set_id(id);
-        }
-        self.ids.insert(entity.id());
-        match entity {
+    fn push(&mut self, entity: Entity) {
+        let index = id_index(entity.id());
+        let entry = match entity {
             Entity::Turret(turret) => {
                 self.turrets.push(turret);
+                (Kind::Turret, self.turrets.len() - 1)
             }
             Entity::Enemy(enemy) => {
                 self.enemies.push(enemy);
+                (Kind::Enemy, self.enemies.len() - 1)
             }
             Entity::Bullet(bullet) => {
                 self.bullets.push(bullet);
+                (Kind::Bullet, self.bullets.len() - 1)
             }
+        };
+        self.slots[index].entry = Some(entry);
+    }
+
+    fn release(&mut self, id: EntityId) {
+        let index = id_index(id);
+        self.slots[index].entry = None;
+        if self.slots[index].generation < MAX_GENERATION {
+            self.free.push(index);
         }
     }
 
     pub fn filter_dead(&mut self) {
-        let dead_turrets: Vec<EntityId> = self
-            .turrets
+        let mut dead = remove_dead(&mut self.turrets);
+        dead.extend(remove_dead(&mut self.enemies));
+        dead.extend(remove_dead(&mut self.bullets));
+        for id in dead {
+            self.release(id);
+        }
+
+        // everything after a removed entity has moved down
+        for (i, turret) in self.turrets.iter().enumerate() {
+            self.slots[id_index(turret.id)].entry = Some((Kind::Turret, i));
+        }
+        for (i, enemy) in self.enemies.iter().enumerate() {
+            self.slots[id_index(enemy.id)].entry = Some((Kind::Enemy, i));
+        }
+        for (i, bullet) in self.bullets.iter().enumerate() {
+            self.slots[id_index(bullet.id)].entry = Some((Kind::Bullet, i));
+        }
+    }
+
+    pub fn update(&self, state: &State, effects: &mut Effects) -> Updates {
+        Updates {
+            turrets: update_all(&self.turrets, state, effects),
+            enemies: update_all(&self.enemies, state, effects),
+            bullets: update_all(&self.bullets, state, effects),
+        }
+    }
+
+    pub fn apply(&mut self, updates: &Updates) {
+        apply_all(&mut self.turrets, &updates.turrets);
+        apply_all(&mut self.enemies, &updates.enemies);
+        apply_all(&mut self.bullets, &updates.bullets);
+    }
+
+    pub fn draw_debug(&self, state: &State, alpha: f32) {
+        self.enemies.iter().for_each(|e| e.draw_debug(state, alpha));
+        self.turrets.iter().for_each(|e| e.draw_debug(state, alpha));
+        self.bullets.iter().for_each(|e| e.draw_debug(state, alpha));
+    }
+
+    pub fn draw_foreground(&self, state: &State, alpha: f32) {
+        self.enemies
             .iter()
-            .filter(|turret| turret.dead.into())
-            .map(|turret| turret.id)
-            .collect();
-        let dead_enemies: Vec<EntityId> = self
-            .enemies
+            .for_each(|e| e.draw_foreground(state, alpha));
+        self.turrets
             .iter()
-            .filter(|enemy| enemy.dead.into())
-            .map(|enemy| enemy.id)
-            .collect();
-        let dead_bullets: Vec<EntityId> = self
-            .bullets
+            .for_each(|e| e.draw_foreground(state, alpha));
+        self.bullets
             .iter()
-            .filter(|bullet| bullet.dead.into())
-            .map(|bullet| bullet.id)
-            .collect();
-
-        self.turrets.retain(|turret| (!turret.dead).into());
-        self.enemies.retain(|enemy| (!enemy.dead).into());
-        self.bullets.retain(|bullet| (!bullet.dead).into());
+            .for_each(|e| e.draw_foreground(state, alpha));
+    }
 
-        for id in dead_turrets {
-            self.ids.remove(&id);
-        }
-        for id in dead_enemies {
-            self.ids.remove(&id);
+    // Where the entity with this id is, if it's still alive
+    fn lookup(&self, id: EntityId) -> Option<(Kind, usize)> {
+        if id == NO_ID {
+            return None;
         }
-        for id in dead_bullets {
-            self.ids.remove(&id);
+        let slot = self.slots.get(id_index(id))?;
+        if slot.generation != id_generation(id) {
+            return None;
         }
+        slot.entry
+    }
+
+    pub fn contains(&self, id: EntityId) -> bool {
+        self.lookup(id).is_some()
     }
 
-    pub fn closest_enemy(&self, position: Vector2) -> Option<&Enemy> {
-        let mut closest = None;
-        let mut closest_dist = std::f32::MAX;
-        for enemy in self.enemies.iter() {
-            let dist = enemy.position.xy.dist(&position);
-            if dist < closest_dist {
-                closest = Some(enemy.into());
-                closest_dist = dist;
+    // Call after the enemies have moved, been added or removed. The queries below see the
+    // enemies where they were at the last rebuild.
+    pub fn rebuild_index(&mut self) {
+        let positions = self
+            .enemies
+            .iter()
+            .map(|enemy| enemy.position.xy)
+            .collect::<Vec<_>>();
+        self.enemy_grid.rebuild(&positions);
+    }
+
+    pub fn closest_enemy(&self, position: Vector2, radius: f32) -> Option<&Enemy> {
+        self.enemy_grid
+            .nearest(position, radius, |i| {
+                self.enemies[i].position.xy.dist(&position)
+            })
+            .map(|i| &self.enemies[i])
+    }
+
+    pub fn enemies_within(&self, position: Vector2, radius: f32) -> Vec<&Enemy> {
+        let mut enemies = Vec::new();
+        self.enemy_grid.candidates(position, radius, |i| {
+            let enemy = &self.enemies[i];
+            if enemy.position.xy.dist(&position) < radius {
+                enemies.push(enemy);
             }
+        });
+        enemies
+    }
+
+    // The enemy within `radius` which is closest to getting out
+    pub fn first_enemy_along_path(
+        &self,
+        paths: &Paths,
+        position: Vector2,
+        radius: f32,
+    ) -> Option<&Enemy> {
+        self.target_enemy(paths, position, radius, Targeting::First)
+    }
+
+    // The enemy within `radius` which `targeting` picks. Ties go to the one closest to getting
+    // out. That is measured to the nearest exit, so enemies on different paths compare fine.
+    pub fn target_enemy(
+        &self,
+        paths: &Paths,
+        position: Vector2,
+        radius: f32,
+        targeting: Targeting,
+    ) -> Option<&Enemy> {
+        let within = self.enemies_within(position, radius).into_iter();
+        let along = |a: &&Enemy, b: &&Enemy| {
+            paths
+                .remaining(&b.position)
+                .total_cmp(&paths.remaining(&a.position))
+        };
+        match targeting {
+            Targeting::First => within.max_by(along),
+            Targeting::Last => within.min_by(along),
+            Targeting::Strongest => within.max_by(|a, b| a.health.cmp(&b.health).then(along(a, b))),
+            Targeting::Weakest => within.max_by(|a, b| b.health.cmp(&a.health).then(along(a, b))),
+            Targeting::Closest => self.closest_enemy(position, radius),
         }
-        closest
     }
 
     pub fn get_enemy(&self, id: EntityId) -> Option<&Enemy> {
-        for enemy in self.enemies.iter() {
-            if enemy.id == id {
-                return Some(enemy);
-            }
+        match self.lookup(id) {
+            Some((Kind::Enemy, i)) => Some(&self.enemies[i]),
+            _ => None,
         }
-        None
     }
 
     pub fn get_enemy_mut(&mut self, id: EntityId) -> Option<&mut Enemy> {
-        for enemy in self.enemies.iter_mut() {
-            if enemy.id == id {
-                return Some(enemy);
+        match self.lookup(id) {
+            Some((Kind::Enemy, i)) => Some(&mut self.enemies[i]),
+            _ => None,
+        }
+    }
+
+    pub fn get_turret(&self, id: EntityId) -> Option<&Turret> {
+        match self.lookup(id) {
+            Some((Kind::Turret, i)) => Some(&self.turrets[i]),
+            _ => None,
+        }
+    }
+
+    pub fn get_turret_mut(&mut self, id: EntityId) -> Option<&mut Turret> {
+        match self.lookup(id) {
+            Some((Kind::Turret, i)) => Some(&mut self.turrets[i]),
+            _ => None,
+        }
+    }
+}
+
+//==================
+// Entity encoding for `to_state` / `from_state`
+//==================
+
+const TURRET_WORDS: usize = 15;
+const ENEMY_WORDS: usize = 15 + STATUSES_WORDS;
+const BULLET_WORDS: usize = 19;
+
+fn write_turret(w: &mut Writer, turret: &Turret) {
+    w.u32(turret.id);
+    w.u32(turret.kind as u32);
+    w.u32(turret.targeting as u32);
+    w.u32(turret.target);
+    w.u32(turret.tier);
+    w.u32(turret.spent);
+    w.bool(turret.preset.into());
+    w.vec2(turret.position);
+    w.bool(turret.dead.into());
+    w.bool(turret.hover.into());
+    w.f32(turret.fire_cooldown);
+    w.vec2(turret.facing);
+    w.f32(turret.radius);
+}
+
+fn read_turret(r: &mut Reader) -> Result<Turret, DecodeError> {
+    let id = r.u32()?;
+    let kind = TurretKind::from_u32(r.u32()?).ok_or(DecodeError::Invalid("unknown turret kind"))?;
+    let targeting =
+        Targeting::from_u32(r.u32()?).ok_or(DecodeError::Invalid("unknown targeting"))?;
+    let target = r.u32()?;
+    let tier = r.u32()?;
+    if tier as usize >= kind.archetype().tiers.len() {
+        return Err(DecodeError::Invalid("turret tier out of range"));
+    }
+    let spent = r.u32()?;
+    let preset = r.bool()?;
+    let mut turret = Turret::new(kind, r.vec2()?);
+    turret.targeting = targeting;
+    turret.target = target;
+    turret.tier = tier;
+    turret.spent = spent;
+    turret.preset = preset.into();
+    turret.id = id;
+    turret.dead = r.bool()?.into();
+    turret.hover = r.bool()?.into();
+    turret.fire_cooldown = r.f32()?;
+    turret.facing = r.vec2()?;
+    turret.radius = r.f32()?;
+    Ok(turret)
+}
+
+fn write_enemy(w: &mut Writer, enemy: &Enemy) {
+    w.u32(enemy.id);
+    w.u32(enemy.kind as u32);
+    w.vec2(enemy.position.xy);
+    w.f32(enemy.position.linear);
+    w.u32(enemy.position.path as u32);
+    w.vec2(enemy.position.tangent);
+    w.vec2(enemy.prev_xy);
+    w.u32(enemy.health);
+    w.u32(enemy.max_health);
+    enemy.statuses.write(w);
+    w.f32(enemy.spawn_time);
+    w.bool(enemy.dead.into());
+    w.f32(enemy.radius);
+}
+
+fn read_enemy(r: &mut Reader) -> Result<Enemy, DecodeError> {
+    let id = r.u32()?;
+    let kind = EnemyKind::from_u32(r.u32()?).ok_or(DecodeError::Invalid("unknown enemy kind"))?;
+    let position = PathPosition {
+        xy: r.vec2()?,
+        linear: r.f32()?,
+        path: r.u32()? as usize,
+        tangent: r.vec2()?,
+    };
+    let prev_xy = r.vec2()?;
+    let health = r.u32()?;
+    let max_health = r.u32()?;
+    let statuses = Statuses::read(r)?;
+    let mut enemy = Enemy::new(kind, position, r.f32()?);
+    enemy.id = id;
+    enemy.prev_xy = prev_xy;
+    enemy.health = health;
+    enemy.max_health = max_health;
+    enemy.statuses = statuses;
+    enemy.dead = r.bool()?.into();
+    enemy.radius = r.f32()?;
+    Ok(enemy)
+}
+
+fn write_bullet(w: &mut Writer, bullet: &Bullet) {
+    w.u32(bullet.id);
+    w.u32(bullet.kind as u32);
+    w.vec2(bullet.position);
+    w.vec2(bullet.prev_position);
+    w.vec2(bullet.velocity);
+    w.vec2(bullet.aim);
+    w.u32(bullet.source);
+    w.u32(bullet.target);
+    bullet.hit.iter().for_each(|&id| w.u32(id));
+    w.bool(bullet.dead.into());
+    w.u32(bullet.damage);
+    w.f32(bullet.radius);
+}
+
+fn read_bullet(r: &mut Reader) -> Result<Bullet, DecodeError> {
+    let id = r.u32()?;
+    let kind = ProjectileKind::from_u32(r.u32()?)
+        .ok_or(DecodeError::Invalid("unknown projectile kind"))?;
+    let position = r.vec2()?;
+    let prev_position = r.vec2()?;
+    let velocity = r.vec2()?;
+    let aim = r.vec2()?;
+    let source = r.u32()?;
+    let target = r.u32()?;
+    let mut hit = [NO_ID; MAX_PIERCE];
+    for id in hit.iter_mut() {
+        *id = r.u32()?;
+    }
+    let mut bullet = Bullet::new(kind, position, source, Some(target));
+    bullet.id = id;
+    bullet.prev_position = prev_position;
+    bullet.velocity = velocity;
+    bullet.aim = aim;
+    bullet.hit = hit;
+    bullet.dead = r.bool()?.into();
+    bullet.damage = r.u32()?;
+    bullet.radius = r.f32()?;
+    Ok(bullet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::Path;
+
+    fn enemy_at(linear: f32) -> Enemy {
+        let position = PathPosition {
+            xy: Vector2::new(linear, 5.0),
+            linear,
+            path: 0,
+            tangent: Vector2::new(1.0, 0.0),
+        };
+        Enemy::new(EnemyKind::Slime, position, 1.5)
+    }
+
+    // one turret, two enemies and a bullet going for the second enemy
+    fn some_manager() -> EntityManager {
+        let mut em = EntityManager::new();
+
+        let mut turret = Turret::new(TurretKind::Gun, Vector2::new(10.0, 20.0));
+        turret.fire_cooldown = 0.25;
+        em.add(turret.into());
+
+        for (kind, health) in [(EnemyKind::Brute, 2), (EnemyKind::Slime, 3)] {
+            let position = PathPosition {
+                xy: Vector2::new(0.5 * health as f32, 5.0),
+                linear: 0.5 * health as f32,
+                path: 0,
+                tangent: Vector2::new(1.0, 0.0),
+            };
+            let mut enemy = Enemy::new(kind, position, 1.5);
+            enemy.health = health;
+            em.add(enemy.into());
+        }
+
+        let target = em.enemies[1].id;
+        let mut bullet = Bullet::new(
+            ProjectileKind::Slug,
+            Vector2::new(1.0, 2.0),
+            em.turrets[0].id,
+            Some(target),
+        );
+        bullet.velocity = Vector2::new(-3.0, 4.0);
+        em.add(bullet.into());
+
+        em
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let em = some_manager();
+        let state = em.to_state();
+        let decoded = EntityManager::from_state(&state).unwrap();
+
+        assert_eq!(decoded.turrets.len(), 1);
+        assert_eq!(decoded.turrets[0].fire_cooldown, 0.25);
+        assert_eq!(decoded.enemies.len(), 2);
+        assert_eq!(decoded.enemies[0].kind, EnemyKind::Brute);
+        assert_eq!(decoded.enemies[0].max_health, 12);
+        let target = decoded.bullets[0].target;
+        assert_eq!(decoded.get_enemy(target).unwrap().health, 3);
+        assert_eq!(decoded.get_enemy(target).unwrap().position.linear, 1.5);
+        assert_eq!(decoded.bullets[0].velocity, Vector2::new(-3.0, 4.0));
+        for e in em.enemies.iter() {
+            assert!(decoded.contains(e.id));
+        }
+
+        // encoding is stable
+        assert_eq!(decoded.to_state(), state);
+    }
+
+    #[test]
+    fn test_round_trip_keeps_allocator() {
+        let mut em = some_manager();
+        em.enemies[0].dead = true.into();
+        em.filter_dead();
+
+        let mut decoded = EntityManager::from_state(&em.to_state()).unwrap();
+        em.add(enemy_at(0.0).into());
+        decoded.add(enemy_at(0.0).into());
+        assert_eq!(decoded.enemies[1].id, em.enemies[1].id);
+    }
+
+    #[test]
+    fn test_empty_round_trip() {
+        let state = EntityManager::new().to_state();
+        assert_eq!(&*state, &[0, 0, 0, 0, 0]);
+        let decoded = EntityManager::from_state(&state).unwrap();
+        assert!(decoded.slots.is_empty());
+    }
+
+    #[test]
+    fn test_from_state_rejects_bad_input() {
+        let state = some_manager().to_state();
+
+        for len in 0..state.len() {
+            assert_eq!(
+                EntityManager::from_state(&state[..len]).err(),
+                Some(DecodeError::Truncated),
+                "prefix of length {}",
+                len
+            );
+        }
+
+        // a huge length must not be trusted
+        let mut bad = state.to_vec();
+        bad[0] = u32::MAX;
+        assert_eq!(
+            EntityManager::from_state(&bad).err(),
+            Some(DecodeError::Truncated)
+        );
+
+        let mut bad = state.to_vec();
+        bad.push(0);
+        assert!(matches!(
+            EntityManager::from_state(&bad),
+            Err(DecodeError::Invalid(_))
+        ));
+
+        // 4 slots, all generation 1, nothing free
+        assert_eq!(&state[..6], &[4, 1, 1, 1, 1, 0]);
+
+        // the second enemy reuses the first one's id
+        let mut bad = state.to_vec();
+        let first_enemy = 6 + 1 + TURRET_WORDS + 1;
+        bad[first_enemy + ENEMY_WORDS] = bad[first_enemy];
+        assert!(matches!(
+            EntityManager::from_state(&bad),
+            Err(DecodeError::Invalid(_))
+        ));
+
+        let mut bad = state.to_vec();
+        bad[first_enemy + 1] = EnemyKind::ALL.len() as u32;
+        assert_eq!(
+            EntityManager::from_state(&bad).err(),
+            Some(DecodeError::Invalid("unknown enemy kind"))
+        );
+
+        // an id from an older generation of its slot
+        let mut bad = state.to_vec();
+        bad[2] = 2;
+        assert!(matches!(
+            EntityManager::from_state(&bad),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_ids_are_never_no_id() {
+        let mut em = EntityManager::new();
+        em.add(enemy_at(0.0).into());
+        assert_ne!(em.enemies[0].id, NO_ID);
+        assert!(!em.contains(NO_ID));
+        assert!(em.get_enemy(NO_ID).is_none());
+    }
+
+    #[test]
+    fn test_stale_id_is_detected() {
+        let mut em = EntityManager::new();
+        em.add(enemy_at(0.0).into());
+        let old = em.enemies[0].id;
+
+        em.enemies[0].dead = true.into();
+        em.filter_dead();
+        assert!(em.get_enemy(old).is_none());
+
+        // the new enemy gets the same slot, but not the same id
+        em.add(enemy_at(1.0).into());
+        let new = em.enemies[0].id;
+        assert_eq!(id_index(new), id_index(old));
+        assert_ne!(new, old);
+        assert!(em.get_enemy(old).is_none());
+        assert_eq!(em.get_enemy(new).unwrap().position.linear, 1.0);
+    }
+
+    #[test]
+    fn test_lookup_after_removal() {
+        let mut em = EntityManager::new();
+        for i in 0..5 {
+            em.add(enemy_at(i as f32).into());
+        }
+        let ids: Vec<EntityId> = em.enemies.iter().map(|e| e.id).collect();
+
+        em.enemies[0].dead = true.into();
+        em.enemies[3].dead = true.into();
+        em.filter_dead();
+
+        for (i, &id) in ids.iter().enumerate() {
+            match em.get_enemy(id) {
+                Some(enemy) => assert_eq!(enemy.position.linear, i as f32),
+                None => assert!(i == 0 || i == 3),
             }
         }
-        None
+        // and an enemy id is not a turret or a bullet
+        em.add(Turret::new(TurretKind::Gun, Vector2::zero()).into());
+        assert!(em.get_enemy(em.turrets[0].id).is_none());
+    }
+
+    #[test]
+    fn test_enemy_queries() {
+        let mut em = EntityManager::new();
+        // enemy_at puts them at (linear, 5)
+        for linear in [0.0, 40.0, 100.0, 400.0] {
+            em.add(enemy_at(linear).into());
+        }
+        em.rebuild_index();
+
+        let at = |linear: f32| Vector2::new(linear, 5.0);
+        let linear = |enemy: Option<&Enemy>| enemy.map(|e| e.position.linear);
+
+        assert_eq!(linear(em.closest_enemy(at(30.0), 150.0)), Some(40.0));
+        assert_eq!(linear(em.closest_enemy(at(300.0), 50.0)), None);
+        assert_eq!(linear(em.closest_enemy(at(300.0), f32::MAX)), Some(400.0));
+
+        let mut within = em
+            .enemies_within(at(0.0), 150.0)
+            .iter()
+            .map(|e| e.position.linear)
+            .collect::<Vec<_>>();
+        within.sort_by(f32::total_cmp);
+        assert_eq!(within, vec![0.0, 40.0, 100.0]);
+
+        let paths = Paths::new(vec![Path::new(vec![at(0.0), at(1000.0)])]);
+        assert_eq!(
+            linear(em.first_enemy_along_path(&paths, at(0.0), 150.0)),
+            Some(100.0)
+        );
+
+        em.enemies[0].health = 1;
+        em.enemies[2].health = 5;
+        let target = |em: &EntityManager, targeting| {
+            linear(em.target_enemy(&paths, at(0.0), 150.0, targeting))
+        };
+        assert_eq!(target(&em, Targeting::First), Some(100.0));
+        assert_eq!(target(&em, Targeting::Last), Some(0.0));
+        assert_eq!(target(&em, Targeting::Strongest), Some(100.0));
+        assert_eq!(target(&em, Targeting::Weakest), Some(0.0));
+        assert_eq!(target(&em, Targeting::Closest), Some(0.0));
+        // a tie on health goes to the one further along
+        em.enemies[0].health = 3;
+        assert_eq!(target(&em, Targeting::Weakest), Some(40.0));
+
+        // removals shift the list, the index follows after a rebuild
+        em.enemies[1].dead = true.into();
+        em.filter_dead();
+        em.rebuild_index();
+        assert_eq!(linear(em.closest_enemy(at(30.0), 150.0)), Some(0.0));
+    }
+
+    #[test]
+    fn test_exhausted_slot_is_retired() {
+        let mut em = EntityManager::new();
+        em.add(enemy_at(0.0).into());
+        em.slots[0].generation = MAX_GENERATION;
+        em.enemies[0].id = make_id(0, MAX_GENERATION);
+
+        em.enemies[0].dead = true.into();
+        em.filter_dead();
+        em.add(enemy_at(0.0).into());
+        assert_eq!(id_index(em.enemies[0].id), 1);
     }
 }
@@ -0,0 +1,124 @@
+// Things that happened during a simulation step, for anything that wants to react to them
+// (sound, particles, stats, UI) without reaching into the entity code.
+//
+// `apply_entities_update` pushes events into `State::events` as it applies the step, and
+// they are handed to each of the `EVENT_HANDLERS` (see `game.rs`) right after. The queue is empty
+// again at the start of the next step.
+
+use crate::entity_manager::EntityId;
+use crate::vec2::Vector2;
+use crate::words::{DecodeError, Reader, Writer};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GameEvent {
+    EnemySpawned {
+        id: EntityId,
+    },
+    // `by` is the turret whose bullet did it
+    EnemyKilled {
+        id: EntityId,
+        by: EntityId,
+        bounty: u32,
+    },
+    BulletHit {
+        bullet: EntityId,
+        target: EntityId,
+        damage: u32,
+    },
+    LifeLost {
+        amount: u32,
+    },
+    TurretPlaced {
+        id: EntityId,
+        position: Vector2,
+        cost: u32,
+    },
+    TurretSold {
+        id: EntityId,
+        position: Vector2,
+        refund: u32,
+    },
+    // `tier` is the one it went up to
+    TurretUpgraded {
+        id: EntityId,
+        tier: u32,
+        cost: u32,
+    },
+    WaveStarted {
+        wave: u32,
+    },
+    WaveCleared {
+        wave: u32,
+    },
+}
+
+pub struct EventQueue {
+    pub(crate) events: Vec<GameEvent>,
+}
+
+impl EventQueue {
+    pub fn new() -> EventQueue {
+        EventQueue { events: Vec::new() }
+    }
+
+    pub fn push(&mut self, event: GameEvent) {
+        self.events.push(event);
+    }
+
+    pub fn take(&mut self) -> Vec<GameEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+// Running totals for the session. Kept up to date by `on_event`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub enemies_spawned: u32,
+    pub enemies_killed: u32,
+    pub bullets_hit: u32,
+    pub lives_lost: u32,
+    pub turrets_placed: u32,
+    pub turrets_sold: u32,
+    pub turrets_upgraded: u32,
+    pub waves_cleared: u32,
+}
+
+impl Stats {
+    pub fn on_event(&mut self, event: &GameEvent) {
+        match event {
+            GameEvent::EnemySpawned { .. } => self.enemies_spawned += 1,
+            GameEvent::EnemyKilled { .. } => self.enemies_killed += 1,
+            GameEvent::BulletHit { .. } => self.bullets_hit += 1,
+            GameEvent::LifeLost { amount } => self.lives_lost += amount,
+            GameEvent::TurretPlaced { .. } => self.turrets_placed += 1,
+            GameEvent::TurretSold { .. } => self.turrets_sold += 1,
+            GameEvent::TurretUpgraded { .. } => self.turrets_upgraded += 1,
+            GameEvent::WaveStarted { .. } => {}
+            GameEvent::WaveCleared { .. } => self.waves_cleared += 1,
+        }
+    }
+
+    pub fn write(&self, w: &mut Writer) {
+        w.u32(self.enemies_spawned);
+        w.u32(self.enemies_killed);
+        w.u32(self.bullets_hit);
+        w.u32(self.lives_lost);
+        w.u32(self.turrets_placed);
+        w.u32(self.turrets_sold);
+        w.u32(self.turrets_upgraded);
+        w.u32(self.waves_cleared);
+    }
+
+    pub fn read(r: &mut Reader) -> Result<Stats, DecodeError> {
+        Ok(Stats {
+            enemies_spawned: r.u32()?,
+            enemies_killed: r.u32()?,
+            bullets_hit: r.u32()?,
+            lives_lost: r.u32()?,
+            turrets_placed: r.u32()?,
+            turrets_sold: r.u32()?,
+            turrets_upgraded: r.u32()?,
+            waves_cleared: r.u32()?,
+        })
+    }
+}
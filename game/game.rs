@@ -1,9 +1,9 @@
 // #![deny(unused_results)]
 
 use anim::Anchor;
-use entity_manager::{Entity, EntityManager};
-use raylib::{KeyboardKey as KEY, MouseButton, RAYWHITE};
-use raylib_wasm::{self as raylib, Color, BLUE};
+use entity_manager::{Entity, EntityManager, NO_ID};
+use raylib::{KeyboardKey as KEY, RAYWHITE};
+use raylib_wasm::{self as raylib, Color, BLUE, RED};
 use u32_bool::Bool;
 
 mod log;
@@ -11,32 +11,60 @@ mod log;
 mod anim;
 mod bullet;
 mod defer;
+mod editor;
 mod enemy;
 mod entity_manager;
+mod events;
+#[cfg(feature = "headless")]
+pub mod headless;
+pub mod input;
+pub mod layout;
+mod level;
 mod path;
+mod placement;
+mod save;
+mod spatial;
+mod status;
 mod turret;
 mod u32_bool;
 mod vec2;
+mod waves;
 mod webhacks;
+mod words;
 
-use crate::enemy::Enemy;
-use crate::turret::Turret;
+use crate::enemy::{Enemy, EnemySprite};
+use crate::events::GameEvent;
+use crate::input::FrameInput;
+use crate::turret::{Turret, TurretKind, TurretSprite};
 use crate::vec2::Vector2;
 // use crate::vec2::Vector2Ext;
 
+pub const DEFAULT_SEED: u32 = 42;
+
 const WINDOW_WIDTH: i32 = 800;
 const WINDOW_HEIGHT: i32 = 600;
 
 const SPEED_DEFAULT: f32 = 850.0;
 const SPEED_BOOSTED: f32 = 1550.0;
 
-const SPAWN_INTERVAL: f32 = 1.0;
-const SPEED_ENEMY: f32 = 210.0;
-const SPEED_BULLET: f32 = SPEED_ENEMY + 50.0;
-// const SPEED_ENEMY: f32 = 1340.0;
+// The simulation runs at a fixed rate, independent of the render framerate. A slow frame
+// runs several simulation steps to catch up, but never more than MAX_SIM_STEPS.
+const SIM_HZ: f32 = 120.0;
+const SIM_DT: f32 = 1.0 / SIM_HZ;
+const MAX_SIM_STEPS: u32 = 8;
 
 const ACTIVE_RADIUS: f32 = 150.0;
 
+// Select the turret type to build, in `TurretKind::ALL` order
+const BUILD_KEYS: [KEY; 6] = [
+    KEY::One,
+    KEY::Two,
+    KEY::Three,
+    KEY::Four,
+    KEY::Five,
+    KEY::Six,
+];
+
 const ALPHA_BEIGE: Color = Color {
     r: 211,
     g: 176,
@@ -57,11 +85,18 @@ pub struct State {
     pub all_loaded: Bool,
     pub curr_time: f32,
     pub prev_time: f32,
+    pub sim_time: f32,
+    pub sim_accumulator: f32,
     pub frame_count: u32,
     pub slime_pos: Vector2,
     pub mouse_pos: Vector2,
     pub mouse_btn: Bool,
     pub mouse_btn_pressed: Bool,
+    // U or the panel's upgrade button was pressed, latched like the click. upgrades the
+    // selected turret
+    pub upgrade_pressed: Bool,
+    // the panel's sell button was pressed, latched like the click. sells the selected turret
+    pub sell_pressed: Bool,
     pub music: webhacks::Music,
     pub font: webhacks::Font,
     pub slime_anim: anim::Anim,
@@ -69,18 +104,51 @@ pub struct State {
     pub turret_anim: anim::Anim,
     pub bkg: Option<webhacks::Image>,
     pub bkg_texture: webhacks::Texture,
-    pub path: path::Path,
+    pub paths: path::Paths,
     pub mute: Bool,
     pub debug: Bool,
     pub life: u32,
     pub man: EntityManager,
     pub editor: Bool,
+    pub events: events::EventQueue,
+    pub stats: events::Stats,
+    pub waves: waves::Waves,
+    // what gets placed on click
+    pub build_kind: TurretKind,
+    // turret whose panel is open, NO_ID for none. clicking a turret selects it
+    pub selected: entity_manager::EntityId,
+    pub gold: u32,
+    // place turrets in the middle of grid cells rather than right at the mouse
+    pub snap: Bool,
+    pub editor_tool: editor::EditorTool,
+    pub editor_drag: editor::Drag,
+    // what the level started out as. the editor writes it back with its changes
+    pub level: level::Level,
+    pub level_loaded: Bool,
 }
 
 impl State {
+    // Length of one simulation step. This is what entity updates should advance by.
     fn dt(&self) -> f32 {
+        SIM_DT
+    }
+
+    // Wall-clock time since the last rendered frame
+    fn frame_dt(&self) -> f32 {
         self.curr_time - self.prev_time
     }
+
+    fn enemy_anim(&self, sprite: EnemySprite) -> &anim::Anim {
+        match sprite {
+            EnemySprite::Slime => &self.slime_anim,
+        }
+    }
+
+    fn turret_anim_for(&self, sprite: TurretSprite) -> &anim::Anim {
+        match sprite {
+            TurretSprite::Turret => &self.turret_anim,
+        }
+    }
 }
 
 #[no_mangle]
@@ -88,41 +156,102 @@ pub fn get_state_size() -> usize {
     std::mem::size_of::<State>()
 }
 
-fn make_initial_path() -> path::Path {
-    let w = WINDOW_WIDTH as f32;
-    let h = WINDOW_HEIGHT as f32;
-    let p = 80.0;
-    let d = 150.0;
-
-    let path_points = vec![
-        Vector2::new(0.0, p),
-        Vector2::new(p, p),
-        Vector2::new(p, p + d),
-        Vector2::new(w - p - d, p + d),
-        Vector2::new(w - p - d, p),
-        Vector2::new(w - p, p),
-        Vector2::new(w - p, h - p),
-        Vector2::new(p + d, h - p),
-        Vector2::new(p + d, h - p - d),
-        Vector2::new(p, h - p - d),
-        Vector2::new(p, h - p),
-    ];
+fn level_paths(level: &level::Level) -> path::Paths {
+    path::Paths::new(
+        level
+            .paths
+            .iter()
+            .map(|p| {
+                let mut path = path::Path::new(p.nodes.clone());
+                path.set_curved(p.curved);
+                path.branches = p.branches.clone();
+                path
+            })
+            .collect(),
+    )
+}
 
-    path::Path::new(path_points)
+// Start the level over from `level`: paths, preset turrets, life, gold, waves and background.
+// The anims are attached once everything is loaded, see `game_load`.
+fn apply_level(state: &mut State, level: level::Level) {
+    state.paths = level_paths(&level);
+    state.man = EntityManager::new();
+    for preset in level.turrets.iter() {
+        let mut turret = Turret::new(preset.kind, preset.position);
+        turret.preset = true.into();
+        state.man.add(turret.into());
+    }
+    state.life = level.life;
+    state.gold = level.gold;
+    state.waves = waves::Waves::new(level.waves.clone());
+    state.bkg = level.background.as_deref().map(webhacks::load_image);
+    state.bkg_texture = webhacks::null_texture();
+    state.level = level;
 }
 
-fn make_initial_turrets(man: &mut EntityManager) {
-    let t1 = Turret::new(Vector2::new(200.0, 150.0));
-    let t2 = Turret::new(Vector2::new(400.0, 180.0));
+// Try to get the level file. Until it's there the built-in level stays in place. A broken
+// file is reported and the built-in level is kept. A level saved from the editor is found here
+// too, on the web as well (see `webhacks::fetch_file`).
+fn load_level(state: &mut State) {
+    let text = match webhacks::fetch_file(level::LEVEL_PATH) {
+        webhacks::FileFetch::Pending => return,
+        webhacks::FileFetch::Ready(data) => String::from_utf8_lossy(&data).into_owned(),
+        webhacks::FileFetch::Failed => {
+            log::warning(
+                format!("No level at {}, using the built-in one", level::LEVEL_PATH).as_str(),
+            );
+            state.level_loaded = true.into();
+            return;
+        }
+    };
+    match level::Level::parse(&text) {
+        Ok(level) => {
+            apply_level(state, level);
+            log::info(format!("Loaded level from {}", level::LEVEL_PATH).as_str());
+        }
+        Err(err) => log::error(format!("Failed to load {}: {}", level::LEVEL_PATH, err).as_str()),
+    }
+    state.level_loaded = true.into();
+}
 
-    man.add(Entity::Turret(t1));
-    man.add(Entity::Turret(t2));
+// The level as it is in the editor: the current paths and preset turrets, the rest as it was
+// loaded. Turrets the player bought stay out of it.
+fn edited_level(state: &State) -> level::Level {
+    let mut level = state.level.clone();
+    level.paths = state
+        .paths
+        .paths
+        .iter()
+        .map(|p| level::LevelPath {
+            nodes: p.nodes.clone(),
+            curved: p.curved,
+            branches: p.branches.clone(),
+        })
+        .collect();
+    level.turrets = state
+        .man
+        .turrets
+        .iter()
+        .filter(|t| bool::from(t.preset))
+        .map(|t| level::PresetTurret {
+            kind: t.kind,
+            position: t.position,
+        })
+        .collect();
+    level
 }
 
 pub type GameInit = fn() -> State;
+pub type GameInitSeeded = fn(seed: u32) -> State;
 
 #[no_mangle]
 pub fn game_init() -> State {
+    game_init_seeded(DEFAULT_SEED)
+}
+
+// Same as `game_init`, but with a specific seed for the RNG. Used when replaying a recording.
+#[no_mangle]
+pub fn game_init_seeded(seed: u32) -> State {
     // We do not cap the framerate, since it leads to sluggish mouse input, since raylib cannot detect mouse input
     // between the frames and we don't really want to dig down to the GLFW layer and poll for events ourselves.
     // See: https://github.com/raysan5/raylib/issues/3354
@@ -136,58 +265,182 @@ pub fn game_init() -> State {
     log::trace("game_init");
     log::warning("im a warning");
 
-    raylib::init_window(WINDOW_WIDTH, WINDOW_HEIGHT, "game");
+    webhacks::init_window(WINDOW_WIDTH, WINDOW_HEIGHT, "game");
 
     webhacks::init_audio_device();
-    webhacks::set_random_seed(42);
+    webhacks::set_random_seed(seed);
+
+    initial_state()
+}
 
+// A fresh state with all the assets requested, but not loaded yet. Expects the window and
+// the audio device to be up already.
+fn initial_state() -> State {
     let music = webhacks::load_music_stream("assets_private/hello_03.wav");
     let font = webhacks::load_font("assets/romulus.png");
 
-    let path = make_initial_path();
-    let mut man = EntityManager::new();
-
-    make_initial_turrets(&mut man);
+    // replaced by the level file in `load_level`
+    let builtin = level::Level::parse(level::BUILTIN_LEVEL).expect("the built-in level is valid");
 
     let slime_anim = anim::Anim::new(webhacks::load_image("assets/slime_green-mag.png"));
     let bullet_anim = anim::Anim::new(webhacks::load_image("assets/bullet-mag.png"));
     let turret_anim = anim::Anim::new(webhacks::load_image("assets/turret-mag.png"));
 
-    // let bkg = Some(webhacks::load_image(
-    //     "assets_private/bkg_screenshot_debug.png",
-    // ));
-    let bkg = None;
-
-    State {
+    let mut state = State {
         all_loaded: false.into(),
         curr_time: webhacks::get_time() as f32,
         prev_time: 0.0,
+        sim_time: 0.0,
+        sim_accumulator: 0.0,
         frame_count: 99,
         slime_pos: Vector2::new(WINDOW_WIDTH as f32 / 2.0, WINDOW_HEIGHT as f32 / 2.0 + 50.0),
         mouse_pos: Vector2::new(0.0, 0.0),
         mouse_btn: false.into(),
         mouse_btn_pressed: false.into(),
+        upgrade_pressed: false.into(),
+        sell_pressed: false.into(),
         music: music,
         font: font,
         slime_anim: slime_anim,
         bullet_anim: bullet_anim,
         turret_anim: turret_anim,
-        bkg: bkg,
+        bkg: None,
         bkg_texture: webhacks::null_texture(),
-        path: path,
+        paths: level_paths(&builtin),
         mute: true.into(),
         debug: true.into(),
-        life: 20,
-        man: man,
+        life: 0,
+        man: EntityManager::new(),
         editor: false.into(),
+        events: events::EventQueue::new(),
+        stats: events::Stats::default(),
+        waves: waves::Waves::new(Vec::new()),
+        build_kind: TurretKind::Gun,
+        selected: NO_ID,
+        gold: 0,
+        snap: false.into(),
+        editor_tool: editor::EditorTool::Path,
+        editor_drag: editor::Drag::None,
+        level: builtin.clone(),
+        level_loaded: false.into(),
+    };
+    apply_level(&mut state, builtin);
+    // done right away everywhere but on the web, where `game_load` waits for it
+    load_level(&mut state);
+    state
+}
+
+pub type GameStateLayout = fn() -> layout::StateLayout;
+
+#[no_mangle]
+pub fn game_state_layout() -> layout::StateLayout {
+    layout::state_layout()
+}
+
+pub type GameSave = unsafe fn(state: *const State) -> Vec<u8>;
+
+/// The state in the save file format. Used by the native runner to carry the game data over
+/// to a build with a different `State` layout.
+///
+/// # Safety
+///
+/// `state_ptr` has to point to a live `State` of this build.
+#[no_mangle]
+pub unsafe fn game_save(state_ptr: *const State) -> Vec<u8> {
+    save::save_state(unsafe { &*state_ptr })
+}
+
+pub type GameSaveChecked = unsafe fn(state: *const State) -> Result<Vec<u8>, String>;
+
+/// Same as `game_save`, but a panic comes back as the error instead of unwinding into the
+/// runner.
+///
+/// # Safety
+///
+/// Same as `game_save`.
+#[no_mangle]
+pub unsafe fn game_save_checked(state_ptr: *const State) -> Result<Vec<u8>, String> {
+    catch_panic(|| unsafe { game_save(state_ptr) })
+}
+
+pub type GameMigrate =
+    unsafe fn(old_layout: &layout::StateLayout, data: &[u8], state_ptr: *mut State) -> bool;
+
+/// Build a new state in `state_ptr` from `data`, as written by `game_save` of an older build.
+/// The assets are loaded again by `game_load`. Returns false, and leaves `state_ptr` alone, if
+/// the data can't be read by this build.
+///
+/// # Safety
+///
+/// `state_ptr` has to point to `get_state_size()` bytes of writable memory, aligned for
+/// `State`. Whatever is there is overwritten without being dropped.
+#[no_mangle]
+pub unsafe fn game_migrate(
+    old_layout: &layout::StateLayout,
+    data: &[u8],
+    state_ptr: *mut State,
+) -> bool {
+    log::info(
+        format!(
+            "Migrating state from layout {} to {}",
+            old_layout,
+            layout::state_layout()
+        )
+        .as_str(),
+    );
+
+    let mut state = initial_state();
+    if let Err(err) = save::load_state(&mut state, data) {
+        log::error(format!("Failed to migrate state: {}", err).as_str());
+        return false;
+    }
+    state.curr_time = webhacks::get_time() as f32;
+    state.prev_time = state.curr_time;
+
+    unsafe { std::ptr::write(state_ptr, state) };
+    true
+}
+
+pub type GameMigrateChecked = unsafe fn(
+    old_layout: &layout::StateLayout,
+    data: &[u8],
+    state_ptr: *mut State,
+) -> Result<(), String>;
+
+/// Same as `game_migrate`, but a panic comes back as the error instead of unwinding into the
+/// runner. `state_ptr` is only written once the new state is complete, so it's left alone on
+/// a panic too.
+///
+/// # Safety
+///
+/// Same as `game_migrate`.
+#[no_mangle]
+pub unsafe fn game_migrate_checked(
+    old_layout: &layout::StateLayout,
+    data: &[u8],
+    state_ptr: *mut State,
+) -> Result<(), String> {
+    match catch_panic(|| unsafe { game_migrate(old_layout, data, state_ptr) }) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("the save can't be read by this build".to_string()),
+        Err(message) => Err(message),
     }
 }
 
+pub type GameReloaded = fn();
+
+// Called by the native runner once it has switched over to this build. Anything the old build
+// handed to raylib by pointer has to be handed over again, since the old code is gone.
+#[no_mangle]
+pub fn game_reloaded() {
+    log::set_trace_log_callback();
+}
+
 pub type GameLoad = fn(state: *mut State);
 
 #[no_mangle]
 pub fn game_load(_state: *mut State) {
-    let mut state = unsafe { std::ptr::read(_state) };
+    let mut state = StateGuard::read(_state);
     state.prev_time = state.curr_time;
     state.curr_time = webhacks::get_time() as f32;
 
@@ -197,6 +450,13 @@ pub fn game_load(_state: *mut State) {
 
     let mut any_not_loaded = false;
 
+    if (!state.level_loaded).into() {
+        load_level(&mut state);
+        if (!state.level_loaded).into() {
+            any_not_loaded = true;
+        }
+    }
+
     // check if the music is loaded
     if !webhacks::is_music_loaded(state.music) {
         println!("music not loaded: {:?}", state.music);
@@ -292,15 +552,19 @@ pub fn game_load(_state: *mut State) {
             .as_str(),
         );
 
-        for turret in state.man.turrets.iter_mut() {
-            turret.anim = Some(state.turret_anim.clone());
-        }
+        save::attach_anims(&mut state);
     }
 
-    // wrtie back the state
-    unsafe {
-        std::ptr::write(_state, state);
-    }
+    // the guard writes back the state
+}
+
+pub type GameLoadChecked = fn(state: *mut State) -> Result<(), String>;
+
+/// Same as `game_load`, but a panic comes back as the error instead of unwinding into the
+/// runner. The state keeps whatever was loaded before the panic.
+#[no_mangle]
+pub fn game_load_checked(state_ptr: *mut State) -> Result<(), String> {
+    catch_panic(|| game_load(state_ptr))
 }
 
 struct HandleKeysUpdate {
@@ -308,6 +572,12 @@ struct HandleKeysUpdate {
     mute: bool,
     debug: bool,
     editor: bool,
+    save: bool,
+    load: bool,
+    build_kind: TurretKind,
+    cycle_targeting: bool,
+    upgrade: bool,
+    snap: bool,
 }
 
 impl From<&State> for HandleKeysUpdate {
@@ -317,28 +587,29 @@ impl From<&State> for HandleKeysUpdate {
             mute: state.mute.into(),
             debug: state.debug.into(),
             editor: state.editor.into(),
+            save: false,
+            load: false,
+            build_kind: state.build_kind,
+            cycle_targeting: false,
+            upgrade: false,
+            snap: state.snap.into(),
         }
     }
 }
 
-fn handle_keys(state: &State) -> HandleKeysUpdate {
-    let speed = unsafe {
-        if raylib::IsKeyDown(KEY::Space) {
-            SPEED_BOOSTED
-        } else {
-            SPEED_DEFAULT
-        }
+fn handle_keys(state: &State, input: &FrameInput) -> HandleKeysUpdate {
+    let speed = if input.is_down(KEY::Space) {
+        SPEED_BOOSTED
+    } else {
+        SPEED_DEFAULT
     };
 
-    let dt = unsafe { raylib::GetFrameTime() };
+    let dt = input.dt;
 
-    let (w, s, a, d);
-    unsafe {
-        w = raylib::IsKeyDown(KEY::W);
-        s = raylib::IsKeyDown(KEY::S);
-        a = raylib::IsKeyDown(KEY::A);
-        d = raylib::IsKeyDown(KEY::D);
-    }
+    let w = input.is_down(KEY::W);
+    let s = input.is_down(KEY::S);
+    let a = input.is_down(KEY::A);
+    let d = input.is_down(KEY::D);
 
     let mut update = HandleKeysUpdate::from(state);
 
@@ -360,18 +631,34 @@ fn handle_keys(state: &State) -> HandleKeysUpdate {
         update.slime_pos.y = WINDOW_HEIGHT as f32;
     }
 
-    if webhacks::is_key_pressed(KEY::M) {
+    if input.is_pressed(KEY::M) {
         update.mute = !update.mute;
     }
 
-    if webhacks::is_key_pressed(KEY::P) {
+    if input.is_pressed(KEY::P) {
         update.debug = !update.debug;
     }
 
-    if webhacks::is_key_pressed(KEY::E) {
+    if input.is_pressed(KEY::E) {
         update.editor = !update.editor;
     }
 
+    update.save = input.is_pressed(KEY::K);
+    update.load = input.is_pressed(KEY::L);
+
+    update.cycle_targeting = input.is_pressed(KEY::T);
+    update.upgrade = input.is_pressed(KEY::U);
+
+    if input.is_pressed(KEY::G) {
+        update.snap = !update.snap;
+    }
+
+    for (key, kind) in BUILD_KEYS.iter().zip(TurretKind::ALL) {
+        if input.is_pressed(*key) {
+            update.build_kind = kind;
+        }
+    }
+
     update
 }
 
@@ -388,11 +675,55 @@ fn apply_keys_update(state: &mut State, update: HandleKeysUpdate) {
     state.mute = update.mute.into();
     state.debug = update.debug.into();
     state.editor = update.editor.into();
+    state.build_kind = update.build_kind;
+    state.snap = update.snap.into();
+    // handled by the selected turret in the next simulation step, see `game_frame_input`
+    state.upgrade_pressed = (update.upgrade || state.upgrade_pressed.into()).into();
+
+    if update.cycle_targeting {
+        if let Some(turret) = state.man.get_turret_mut(state.selected) {
+            turret.targeting = turret.targeting.next();
+        }
+    }
+
+    if update.save && state.editor.into() {
+        let level = edited_level(state);
+        if webhacks::save_file_data(level::LEVEL_PATH, level.write().as_bytes()) {
+            log::info(format!("Saved level to {}", level::LEVEL_PATH).as_str());
+            state.level = level;
+        } else {
+            log::error(format!("Failed to save level to {}", level::LEVEL_PATH).as_str());
+        }
+    } else if update.save {
+        let data = save::save_state(state);
+        if webhacks::save_file_data(save::SAVE_PATH, &data) {
+            log::info(format!("Saved game to {}", save::SAVE_PATH).as_str());
+        } else {
+            log::error(format!("Failed to save game to {}", save::SAVE_PATH).as_str());
+        }
+    }
+
+    if update.load {
+        let mute = state.mute;
+        match webhacks::load_file_data(save::SAVE_PATH) {
+            Some(data) => match save::load_state(state, &data) {
+                Ok(()) => log::info(format!("Loaded game from {}", save::SAVE_PATH).as_str()),
+                Err(err) => log::error(format!("Failed to load game: {}", err).as_str()),
+            },
+            None => log::warning(format!("No save found at {}", save::SAVE_PATH).as_str()),
+        }
+        if state.mute != mute {
+            webhacks::set_music_volume(state.music, if state.mute.into() { 0.0 } else { 1.0 });
+        }
+    }
 }
 struct HandleMouseUpdate {
     mouse_pos: Vector2,
     mouse_btn: bool,
     mouse_btn_pressed: bool,
+    // the buttons of the selected turret's panel
+    upgrade: bool,
+    sell: bool,
 }
 
 impl From<&State> for HandleMouseUpdate {
@@ -401,12 +732,58 @@ impl From<&State> for HandleMouseUpdate {
             mouse_pos: state.mouse_pos,
             mouse_btn: state.mouse_btn.into(),
             mouse_btn_pressed: state.mouse_btn_pressed.into(),
+            upgrade: false,
+            sell: false,
         }
     }
 }
 
-fn handle_mouse(state: &State) -> HandleMouseUpdate {
-    let mut mouse_pos = webhacks::get_mouse_position();
+// The panel of the selected turret: a line of text, with the upgrade and sell buttons under
+// it. Above the turret, or below it if there's no room at the top of the screen.
+struct TurretPanel {
+    area: raylib::Rectangle,
+    upgrade: raylib::Rectangle,
+    sell: raylib::Rectangle,
+}
+
+const PANEL_WIDTH: f32 = 260.0;
+const PANEL_LINE: f32 = 22.0;
+
+fn turret_panel(turret: &Turret) -> TurretPanel {
+    let height = 2.0 * PANEL_LINE + 6.0;
+    let mut top = turret.position.y - turret.radius * 1.5 - height;
+    if top < 0.0 {
+        top = turret.position.y + turret.radius * 1.5;
+    }
+    let left =
+        (turret.position.x - PANEL_WIDTH / 2.0).clamp(0.0, WINDOW_WIDTH as f32 - PANEL_WIDTH);
+    let button = |x: f32| raylib::Rectangle {
+        x,
+        y: top + PANEL_LINE + 2.0,
+        width: PANEL_WIDTH / 2.0 - 4.0,
+        height: PANEL_LINE,
+    };
+    TurretPanel {
+        area: raylib::Rectangle {
+            x: left,
+            y: top,
+            width: PANEL_WIDTH,
+            height,
+        },
+        upgrade: button(left + 2.0),
+        sell: button(left + PANEL_WIDTH / 2.0 + 2.0),
+    }
+}
+
+fn rect_contains(rect: &raylib::Rectangle, point: Vector2) -> bool {
+    point.x >= rect.x
+        && point.y >= rect.y
+        && point.x < rect.x + rect.width
+        && point.y < rect.y + rect.height
+}
+
+fn handle_mouse(state: &State, input: &FrameInput) -> HandleMouseUpdate {
+    let mut mouse_pos = input.mouse_pos;
     let is_outside = mouse_pos.x < 0.0
         || mouse_pos.y < 0.0
         || mouse_pos.x > WINDOW_WIDTH as f32
@@ -417,224 +794,260 @@ fn handle_mouse(state: &State) -> HandleMouseUpdate {
 
     let mut update = HandleMouseUpdate::from(state);
     update.mouse_pos = mouse_pos;
-    update.mouse_btn = webhacks::is_mouse_button_down(MouseButton::Left as i32);
-    update.mouse_btn_pressed = webhacks::is_mouse_button_pressed(MouseButton::Left as i32);
+    update.mouse_btn = input.mouse_btn.into();
+    update.mouse_btn_pressed = input.mouse_btn_pressed.into();
+
+    // clicks on the panel go to its buttons, not to what's under it
+    let panel = state.man.get_turret(state.selected).map(turret_panel);
+    if let Some(panel) = panel.filter(|_| update.mouse_btn_pressed && (!state.editor).into()) {
+        if rect_contains(&panel.area, mouse_pos) {
+            update.mouse_btn_pressed = false;
+            update.upgrade = rect_contains(&panel.upgrade, mouse_pos);
+            update.sell = rect_contains(&panel.sell, mouse_pos);
+        }
+    }
 
     update
 }
 
 struct HandleEntitiesUpdate {
-    life_lost: u32,
-    new_enemies: Vec<Enemy>,
-    enemy_updates: Vec<enemy::EnemyUpdate>,
-    bullet_updates: Vec<bullet::BulletUpdate>,
-    turret_updates: Vec<turret::TurretUpdate>,
-    new_bullets: Vec<bullet::Bullet>,
-    new_turrets: Vec<turret::Turret>,
-}
-
-impl HandleEntitiesUpdate {
-    fn new() -> HandleEntitiesUpdate {
-        HandleEntitiesUpdate {
-            life_lost: 0,
-            new_enemies: vec![],
-            enemy_updates: vec![],
-            bullet_updates: vec![],
-            turret_updates: vec![],
-            new_bullets: vec![],
-            new_turrets: vec![],
-        }
-    }
+    updates: entity_manager::Updates,
+    effects: entity_manager::Effects,
+    waves: waves::WavesUpdate,
 }
 
 fn handle_entities(state: &State) -> HandleEntitiesUpdate {
-    let mut update = HandleEntitiesUpdate::new();
-
-    {
-        if match state.man.enemies.last() {
-            Some(Enemy {
-                spawn_time: last_spawn_time,
-                ..
-            }) if state.curr_time - last_spawn_time > SPAWN_INTERVAL => true,
-            None => true,
-            _ => false,
-        } {
-            let mut new_enemy = Enemy::new(state.path.start(), state.curr_time);
-            new_enemy.anim = Some(state.slime_anim.clone());
-            update.new_enemies.push(new_enemy.into());
-        }
+    let mut effects = entity_manager::Effects::new();
+
+    let waves = state
+        .waves
+        .update(state.dt(), !state.man.enemies.is_empty());
+    for &(_, kind, path, spawn) in waves.spawns.iter() {
+        let position = state.paths.lin_to_position(path, spawn);
+        let mut new_enemy = Enemy::new(kind, position, state.sim_time);
+        new_enemy.anim = Some(state.enemy_anim(kind.archetype().sprite).clone());
+        effects.spawns.push(new_enemy.into());
     }
 
-    {
-        update.enemy_updates = state
-            .man
-            .enemies
-            .iter()
-            .map(|enemy| enemy.update(state))
-            .collect::<Vec<_>>();
-
-        // Calculate the total damage done by the enemies
-        update.life_lost = update
-            .enemy_updates
-            .iter()
-            .map(|update| update.damage_done)
-            .sum::<u32>();
-        update.life_lost = std::cmp::min(update.life_lost, state.life);
+    let updates = state.man.update(state, &mut effects);
+
+    effects.life_lost = std::cmp::min(effects.life_lost, state.life);
+
+    let cost = state.build_kind.archetype().build_cost();
+    let affordable = state.gold >= effects.gold_spent + cost;
+    let position = build_position(state);
+    let valid = check_placement(state, position).is_ok();
+    // with a panel open, a click anywhere else only closes it
+    let panel_open = state.man.get_turret(state.selected).is_some();
+    let build = !effects.click_used && !panel_open && state.mouse_btn_pressed.into();
+    if build && affordable && valid {
+        effects.gold_spent += cost;
+        let mut new_turret = Turret::new(state.build_kind, position);
+        new_turret.anim = Some(
+            state
+                .turret_anim_for(state.build_kind.archetype().sprite)
+                .clone(),
+        );
+        effects.spawns.push(new_turret.into());
     }
 
-    {
-        update.bullet_updates = state
-            .man
-            .bullets
-            .iter()
-            .map(|bullet| bullet.update(state))
-            .collect::<Vec<_>>();
-
-        // update.hit_requests = update
-        //     .bullet_updates
-        //     .iter()
-        //     .filter_map(|update| match &update.hit_request {
-        //         Some(hit_request) => Some(hit_request.clone()),
-        //         None => None,
-        //     })
-        //     .collect::<Vec<_>>();
+    HandleEntitiesUpdate {
+        updates,
+        effects,
+        waves,
     }
+}
 
-    {
-        // Get all the turret updates
-        update.turret_updates = state
-            .man
-            .turrets
-            .iter()
-            .map(|turret| turret.update(state))
-            .collect::<Vec<_>>();
-
-        update.new_bullets = update
-            .turret_updates
-            .iter()
-            .filter_map(|update| match &update.bullet_request {
-                Some(bullet_request) => {
-                    let mut bullet = bullet::Bullet::new(
-                        bullet_request.position,
-                        bullet_request.source,
-                        bullet_request.target,
-                    );
-                    bullet.anim = Some(state.bullet_anim.clone());
-                    Some(bullet.into())
-                }
-                None => None,
-            })
-            .collect::<Vec<_>>();
-
-        // TODO: Should handle this better
-
-        // Check whether any turrets are dead
-        let any_dead = update
-            .turret_updates
-            .iter()
-            .any(|update| update.dead.into());
-
-        if !any_dead && { state.mouse_btn_pressed.into() } {
-            let mut new_turret = Turret::new(state.mouse_pos);
-            new_turret.anim = Some(state.turret_anim.clone());
-            update.new_turrets.push(new_turret.into());
-        }
+// Where a turret of the selected type would be placed with a click right now
+fn build_position(state: &State) -> Vector2 {
+    if state.snap.into() {
+        placement::snap(state.mouse_pos)
+    } else {
+        state.mouse_pos
     }
+}
 
-    update
+fn check_placement(state: &State, position: Vector2) -> Result<(), placement::PlacementError> {
+    placement::check(
+        position,
+        state.build_kind.archetype().radius,
+        Vector2::new(WINDOW_WIDTH as f32, WINDOW_HEIGHT as f32),
+        &state.paths,
+        &state.man.turrets,
+    )
 }
 
 fn apply_entities_update(state: &mut State, update: HandleEntitiesUpdate) {
-    state.life -= update.life_lost;
+    let HandleEntitiesUpdate {
+        updates,
+        effects,
+        waves,
+    } = update;
+
+    state.life -= effects.life_lost;
+    if effects.life_lost > 0 {
+        state.events.push(GameEvent::LifeLost {
+            amount: effects.life_lost,
+        });
+    }
 
     // Apply self updates to all entities
-    std::iter::Iterator::zip(state.man.enemies.iter_mut(), update.enemy_updates.iter())
-        .for_each(|(enemy, update)| enemy.apply(update));
-
-    std::iter::Iterator::zip(state.man.bullets.iter_mut(), update.bullet_updates.iter())
-        .for_each(|(bullet, update)| bullet.apply(update));
+    state.man.apply(&updates);
+
+    // The only way for a turret to die is to be sold, which closes its panel
+    for turret in state.man.turrets.iter().filter(|t| t.dead.into()) {
+        state.events.push(GameEvent::TurretSold {
+            id: turret.id,
+            position: turret.position,
+            refund: turret.refund(),
+        });
+        if turret.id == state.selected {
+            state.selected = NO_ID;
+        }
+    }
+    for upgrade in effects.upgrades.iter() {
+        state.events.push(GameEvent::TurretUpgraded {
+            id: upgrade.id,
+            tier: upgrade.tier,
+            cost: upgrade.cost,
+        });
+    }
 
-    std::iter::Iterator::zip(state.man.turrets.iter_mut(), update.turret_updates.iter())
-        .for_each(|(turret, update)| turret.apply(update));
+    // a click selects the turret it lands on, and anywhere else clears the selection
+    if state.mouse_btn_pressed.into() {
+        state.selected = effects.selected.unwrap_or(NO_ID);
+    }
 
     // Handle interactions between entities
-    let hit_requests = update.bullet_updates.iter().filter_map(|update| {
-        if let Some(hit_request) = &update.hit_request {
-            Some(hit_request)
-        } else {
-            None
-        }
-    });
-
-    for hit_request in hit_requests {
+    for hit_request in effects.hits.iter() {
         let target = state.man.get_enemy_mut(hit_request.target);
         if let Some(target) = target {
+            let was_dead: bool = target.dead.into();
             target.hit(hit_request.damage);
+            if let Some(effect) = hit_request.status {
+                target.statuses.apply(effect, hit_request.source);
+            }
+            let killed = !was_dead && target.dead.into();
+            let bounty = target.archetype().bounty;
+
+            // the rest is from status effects ticking
+            if hit_request.bullet != NO_ID {
+                state.events.push(GameEvent::BulletHit {
+                    bullet: hit_request.bullet,
+                    target: hit_request.target,
+                    damage: hit_request.damage,
+                });
+            }
+            if killed {
+                state.events.push(GameEvent::EnemyKilled {
+                    id: hit_request.target,
+                    by: hit_request.source,
+                    bounty,
+                });
+            }
         }
     }
 
     // Spawn new entities
-    update
-        .new_enemies
-        .into_iter()
-        .for_each(|enemy| state.man.add(enemy.into()));
+    for entity in effects.spawns.into_iter() {
+        let placed = match &entity {
+            Entity::Turret(turret) => Some((turret.position, turret.spent)),
+            _ => None,
+        };
+        let is_enemy = matches!(entity, Entity::Enemy(_));
+        let id = state.man.add(entity);
+        if let Some((position, cost)) = placed {
+            state
+                .events
+                .push(GameEvent::TurretPlaced { id, position, cost });
+        } else if is_enemy {
+            state.events.push(GameEvent::EnemySpawned { id });
+        }
+    }
 
-    update
-        .new_bullets
-        .into_iter()
-        .for_each(|bullet| state.man.add(bullet.into()));
+    state.man.filter_dead();
+    state.man.rebuild_index();
 
-    update
-        .new_turrets
-        .into_iter()
-        .for_each(|turret| state.man.add(turret.into()));
+    state.waves.apply(&waves);
+    if let Some(wave) = waves.started {
+        state
+            .events
+            .push(GameEvent::WaveStarted { wave: wave as u32 });
+    }
+    if let Some(wave) = waves.cleared {
+        state
+            .events
+            .push(GameEvent::WaveCleared { wave: wave as u32 });
+    }
 
-    state.man.filter_dead();
+    dispatch_events(state);
 }
 
-fn draw_entities_debug(state: &State) {
-    // draw lines from enemies to turrets if they are within range
-    for enemy in state.man.enemies.iter() {
-        for turret in state.man.turrets.iter() {
-            let distance = enemy.position.xy.dist(&turret.position);
-            if distance < ACTIVE_RADIUS {
-                let enemy_pos = enemy.position;
-                webhacks::draw_line_ex(enemy_pos.into(), turret.position, 2.0, RAYWHITE);
-            }
+// Everything that reacts to game events. Each one gets all of the step's events, in order.
+const EVENT_HANDLERS: &[fn(&mut State, &GameEvent)] = &[update_stats, update_gold, log_event];
+
+fn dispatch_events(state: &mut State) {
+    let events = state.events.take();
+    for handler in EVENT_HANDLERS {
+        for event in events.iter() {
+            handler(state, event);
         }
     }
+}
 
-    // draw line to mouse if it's within range
-    for enemy in state.man.enemies.iter() {
-        let distance = enemy.position.xy.dist(&state.mouse_pos);
-        if distance < ACTIVE_RADIUS {
-            let enemy_pos = enemy.position;
-            webhacks::draw_line_ex(enemy_pos.into(), state.mouse_pos, 2.0, RAYWHITE);
+fn update_stats(state: &mut State, event: &GameEvent) {
+    state.stats.on_event(event);
+}
+
+fn update_gold(state: &mut State, event: &GameEvent) {
+    match *event {
+        GameEvent::EnemyKilled { bounty, .. } => state.gold += bounty,
+        // purchases are only committed with the gold for them, see `Effects::gold_spent`
+        GameEvent::TurretPlaced { cost, .. } | GameEvent::TurretUpgraded { cost, .. } => {
+            debug_assert!(
+                state.gold >= cost,
+                "spent {} with {} gold",
+                cost,
+                state.gold
+            );
+            state.gold -= cost;
         }
+        GameEvent::TurretSold { refund, .. } => state.gold += refund,
+        _ => {}
     }
+}
 
-    for enemy in state.man.enemies.iter() {
-        enemy.draw_debug(state);
-    }
+fn log_event(_state: &mut State, event: &GameEvent) {
+    log::trace(format!("{:?}", event).as_str());
+}
+
+fn draw_entities_debug(state: &State, alpha: f32) {
+    // draw lines from enemies to turrets if they are within range
     for turret in state.man.turrets.iter() {
-        turret.draw_debug(state);
+        for enemy in state
+            .man
+            .enemies_within(turret.position, turret.stats().range)
+        {
+            let enemy_pos = enemy.draw_position(alpha);
+            webhacks::draw_line_ex(enemy_pos, turret.position, 2.0, RAYWHITE);
+        }
+        // and a thicker one to what it's actually aiming at
+        if let Some(enemy) = state.man.get_enemy(turret.target) {
+            webhacks::draw_line_ex(enemy.draw_position(alpha), turret.position, 4.0, RED);
+        }
     }
 
-    for bullet in state.man.bullets.iter() {
-        bullet.draw_debug(state);
+    // draw line to mouse if it's within range
+    for enemy in state.man.enemies_within(state.mouse_pos, ACTIVE_RADIUS) {
+        let enemy_pos = enemy.draw_position(alpha);
+        webhacks::draw_line_ex(enemy_pos, state.mouse_pos, 2.0, RAYWHITE);
     }
+
+    state.man.draw_debug(state, alpha);
 }
 
-fn draw_entities_foreground(state: &State) {
-    for enemy in state.man.enemies.iter() {
-        enemy.draw_foreground(state);
-    }
-    for turret in state.man.turrets.iter() {
-        turret.draw_foreground(state);
-    }
-    for bullet in state.man.bullets.iter() {
-        bullet.draw_foreground(state);
-    }
+fn draw_entities_foreground(state: &State, alpha: f32) {
+    state.man.draw_foreground(state, alpha);
 }
 
 fn draw_mouse(_state: &State) {
@@ -648,7 +1061,47 @@ fn draw_mouse(_state: &State) {
 }
 
 fn draw_path(state: &State) {
-    state.path.draw(state);
+    state.paths.draw(state);
+}
+
+// Grid, and the path nodes with their indices. Paths other than the first get theirs prefixed
+// with the path index.
+fn draw_editor(state: &State) {
+    let grid = Color {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 25,
+    };
+    let (w, h) = (WINDOW_WIDTH as f32, WINDOW_HEIGHT as f32);
+    let size = placement::GRID_SIZE;
+    for i in 1..(w / size).ceil() as i32 {
+        let x = i as f32 * size;
+        webhacks::draw_line_ex(Vector2::new(x, 0.0), Vector2::new(x, h), 1.0, grid);
+    }
+    for i in 1..(h / size).ceil() as i32 {
+        let y = i as f32 * size;
+        webhacks::draw_line_ex(Vector2::new(0.0, y), Vector2::new(w, y), 1.0, grid);
+    }
+
+    let hovered = editor::hovered_node(state);
+    for (p, path) in state.paths.paths.iter().enumerate() {
+        for (i, &node) in path.nodes.iter().enumerate() {
+            let color = if hovered == Some((p, i)) { RED } else { BLUE };
+            webhacks::draw_circle(node, editor::NODE_PICK_RADIUS / 2.0, color);
+            let label = if p == 0 {
+                format!("{}", i)
+            } else {
+                format!("{}.{}", p, i)
+            };
+            draw_text(
+                state.font,
+                label.as_str(),
+                node + Vector2::new(8.0, 4.0),
+                DrawTextArgs::default().size(16).into(),
+            );
+        }
+    }
 }
 
 struct DrawTextArgs {
@@ -731,11 +1184,13 @@ fn draw_text_overlay(state: &State) {
     draw_text(
         state.font,
         format! {
-            "slime: [{x}, {y}]\nmouse: [{mx}, {my}]",
+            "slime: [{x}, {y}]\nmouse: [{mx}, {my}]\nkills: {kills}\n{wave}",
             x = state.slime_pos.x.round(),
             y = state.slime_pos.y.round(),
             mx = state.mouse_pos.x.round(),
-            my = state.mouse_pos.y.round()
+            my = state.mouse_pos.y.round(),
+            kills = state.stats.enemies_killed,
+            wave = wave_text(&state.waves),
         }
         .as_str(),
         Vector2::new(10.0, 10.0),
@@ -748,7 +1203,11 @@ fn draw_text_overlay(state: &State) {
         format!(
             "music: {}\neditor: {}",
             if state.mute.into() { "off" } else { "on" },
-            if state.editor.into() { "on" } else { "off" }
+            if state.editor.into() {
+                state.editor_tool.name()
+            } else {
+                "off"
+            }
         )
         .as_str(),
         Vector2::new(WINDOW_WIDTH as f32 - 10.0, 10.0),
@@ -756,9 +1215,14 @@ fn draw_text_overlay(state: &State) {
     );
 
     // Draw the legend in bottom-right corner
+    let legend = if state.editor.into() {
+        "N: switch tool\nX: delete\n1-6: turret type\nG: snap\nK: save level\nE: back to game"
+    } else {
+        "M: mute\nP: debug\nE: editor\nK: save\nL: load\n1-6: build\nT: targeting of selected\nU: upgrade selected\nG: snap"
+    };
     draw_text(
         state.font,
-        "M: mute\nP: debug\nE: editor",
+        legend,
         Vector2::new(WINDOW_WIDTH as f32 - 10.0, WINDOW_HEIGHT as f32 - 10.0),
         DrawTextArgs::default()
             .anchor(Anchor::BottomRight)
@@ -771,16 +1235,19 @@ fn draw_text_overlay(state: &State) {
             .into(),
     );
 
-    draw_text(
-        state.font,
-        format!("life: {}", state.life).as_str(),
-        state.path.nodes[state.path.nodes.len() - 1],
-        DrawTextArgs::default()
-            .anchor(Anchor::BottomCenter)
-            .size(30)
-            .spacing(2.4)
-            .into(),
-    );
+    // life at each exit, since that's where it's lost, with the gold next to it
+    for exit in state.paths.exits() {
+        draw_text(
+            state.font,
+            format!("life: {}  gold: {}", state.life, state.gold).as_str(),
+            exit,
+            DrawTextArgs::default()
+                .anchor(Anchor::BottomCenter)
+                .size(30)
+                .spacing(2.4)
+                .into(),
+        );
+    }
 
     if state.debug.into() {
         draw_text(
@@ -802,21 +1269,167 @@ fn draw_text_overlay(state: &State) {
     }
 }
 
-fn draw_game_over_overlay(state: &State) {
-    // draw a shaded rectangle over the screen
-    unsafe {
-        raylib::DrawRectangle(0, 0, WINDOW_WIDTH, WINDOW_HEIGHT, ALPHA_BLACK);
+// The turret that a click would place, with its range. Red where it can't go.
+fn draw_placement_ghost(state: &State) {
+    let hovering = state.man.turrets.iter().any(|t| t.hover.into());
+    // a click only closes an open panel
+    let panel_open = state.man.get_turret(state.selected).is_some();
+    if hovering || panel_open || state.mouse_pos.x < 0.0 || state.editor.into() {
+        return;
+    }
+    let position = build_position(state);
+    let archetype = state.build_kind.archetype();
+    let color = match check_placement(state, position) {
+        Ok(()) => RAYWHITE,
+        Err(_) => RED,
+    };
+    webhacks::draw_circle(position, archetype.tiers[0].range, Color { a: 30, ..color });
+    webhacks::draw_circle(position, archetype.radius, Color { a: 120, ..color });
+}
+
+// Type, level and targeting mode of the turret under the mouse, and what the next tier costs
+fn draw_turret_info(state: &State) {
+    // hovering shows what a turret is, clicking it opens its panel
+    for turret in state.man.turrets.iter() {
+        if turret.hover.into() && turret.id != state.selected {
+            draw_text(
+                state.font,
+                format!(
+                    "{} lv {}\nclick for upgrades",
+                    turret.archetype().name,
+                    turret.tier + 1
+                )
+                .as_str(),
+                turret.position - Vector2::new(0.0, turret.radius * 1.5 + 48.0),
+                DrawTextArgs::default().anchor(Anchor::TopCenter).into(),
+            );
+        }
     }
 
-    // draw the game over text
-    let text = "Game Over!";
+    let Some(turret) = state.man.get_turret(state.selected) else {
+        return;
+    };
+    if state.editor.into() {
+        return;
+    }
+    let panel = turret_panel(turret);
+    draw_rect(&panel.area, ALPHA_BLACK);
+    draw_text(
+        state.font,
+        format!(
+            "{} lv {}, target {} (T)",
+            turret.archetype().name,
+            turret.tier + 1,
+            turret.targeting.name()
+        )
+        .as_str(),
+        Vector2::new(panel.area.x + 4.0, panel.area.y + 2.0),
+        DrawTextArgs::default().into(),
+    );
+
+    let grey = raylib::Color {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 100,
+    };
+    let (upgrade, affordable) = match turret.next_tier() {
+        Some(next) => (format!("U: +1 ({}g)", next.cost), state.gold >= next.cost),
+        None => ("max level".to_string(), false),
+    };
+    let buttons = [
+        (&panel.upgrade, upgrade, affordable),
+        (&panel.sell, format!("sell (+{}g)", turret.refund()), true),
+    ];
+    for (rect, label, enabled) in buttons {
+        let hover = rect_contains(rect, state.mouse_pos);
+        draw_rect(
+            rect,
+            raylib::Color {
+                r: 80,
+                g: 80,
+                b: 80,
+                a: if enabled && hover { 255 } else { 160 },
+            },
+        );
+        draw_text(
+            state.font,
+            label.as_str(),
+            Vector2::new(rect.x + rect.width / 2.0, rect.y + 2.0),
+            DrawTextArgs::default()
+                .anchor(Anchor::TopCenter)
+                .color(if enabled { RAYWHITE } else { grey })
+                .into(),
+        );
+    }
+}
+
+fn draw_rect(rect: &raylib::Rectangle, color: Color) {
+    webhacks::draw_rectangle(
+        rect.x as i32,
+        rect.y as i32,
+        rect.width as i32,
+        rect.height as i32,
+        color,
+    );
+}
+
+// One line per turret type, bottom left, with the selected one highlighted
+fn draw_build_menu(state: &State) {
+    let line_height = 22.0;
+    let bottom = WINDOW_HEIGHT as f32 - 10.0 - line_height * TurretKind::ALL.len() as f32;
+    for (i, kind) in TurretKind::ALL.iter().enumerate() {
+        let selected = *kind == state.build_kind;
+        draw_text(
+            state.font,
+            format!(
+                "{}{}: {} ({}g)",
+                if selected { "> " } else { "  " },
+                i + 1,
+                kind.archetype().name,
+                kind.archetype().build_cost()
+            )
+            .as_str(),
+            Vector2::new(10.0, bottom + line_height * i as f32),
+            DrawTextArgs::default()
+                .color(if selected {
+                    RAYWHITE
+                } else {
+                    raylib::Color {
+                        r: 255,
+                        g: 255,
+                        b: 255,
+                        a: 100,
+                    }
+                })
+                .into(),
+        );
+    }
+}
+
+fn wave_text(waves: &waves::Waves) -> String {
+    if waves.is_complete() {
+        return "all waves cleared".to_string();
+    }
+    let wave = format!("wave: {}/{}", waves.current + 1, waves.waves.len());
+    match waves.time_to_next() {
+        Some(t) => format!("{} in {}s", wave, t.ceil()),
+        None => wave,
+    }
+}
+
+fn draw_end_overlay(state: &State, title: &str) {
+    // draw a shaded rectangle over the screen
+    webhacks::draw_rectangle(0, 0, WINDOW_WIDTH, WINDOW_HEIGHT, ALPHA_BLACK);
+
+    // draw the title
     let font_size = 50;
-    let text_size = webhacks::measure_text(state.font, text, font_size, 2.0);
+    let text_size = webhacks::measure_text(state.font, title, font_size, 2.0);
     let position = Vector2::new(
         ((WINDOW_WIDTH - text_size.x as i32) / 2) as f32,
         ((WINDOW_HEIGHT - font_size) / 2) as f32,
     );
-    webhacks::draw_text(state.font, text, position, font_size, 2.0, RAYWHITE);
+    webhacks::draw_text(state.font, title, position, font_size, 2.0, RAYWHITE);
 
     let text = "Press R to restart";
     let font_size = 20;
@@ -828,35 +1441,119 @@ fn draw_game_over_overlay(state: &State) {
     webhacks::draw_text(state.font, text, position, font_size, 2.0, RAYWHITE);
 }
 
-pub type GameFrame = fn(state: *mut State);
+pub type GameFrame = unsafe fn(state: *mut State);
+pub type GamePollInput = unsafe fn(state: *const State) -> FrameInput;
+pub type GameFrameInput = unsafe fn(state: *mut State, input: *const FrameInput);
 
+/// One frame with the live input devices
+///
+/// # Safety
+///
+/// Same as `game_frame_input`.
 #[no_mangle]
-pub fn game_frame(state_ptr: *mut State) {
-    let mut state = unsafe { std::ptr::read(state_ptr) };
+pub unsafe fn game_frame(state_ptr: *mut State) {
+    unsafe {
+        let input = game_poll_input(state_ptr);
+        game_frame_input(state_ptr, &input);
+    }
+}
+
+/// Snapshot of the live input devices for the next frame
+///
+/// # Safety
+///
+/// `state_ptr` has to point to a live `State`.
+#[no_mangle]
+pub unsafe fn game_poll_input(state_ptr: *const State) -> FrameInput {
+    let curr_time = unsafe { (*state_ptr).curr_time };
+    FrameInput::poll(webhacks::get_time() as f32 - curr_time)
+}
+
+pub type GamePollInputChecked = unsafe fn(state: *const State) -> Result<FrameInput, String>;
+
+/// Same as `game_poll_input`, but a panic comes back as the error instead of unwinding into
+/// the runner.
+///
+/// # Safety
+///
+/// Same as `game_poll_input`.
+#[no_mangle]
+pub unsafe fn game_poll_input_checked(state_ptr: *const State) -> Result<FrameInput, String> {
+    catch_panic(|| unsafe { game_poll_input(state_ptr) })
+}
+
+/// Run one frame with the given input rather than the live devices. All time in the game
+/// comes from `input.dt`, so the same inputs always give the same game.
+///
+/// # Safety
+///
+/// `state_ptr` has to point to a live `State` that nothing else uses during the call, and
+/// `input_ptr` to a `FrameInput`.
+#[no_mangle]
+pub unsafe fn game_frame_input(state_ptr: *mut State, input_ptr: *const FrameInput) {
+    let input = unsafe { *input_ptr };
+    let mut state = StateGuard::read(state_ptr);
     state.prev_time = state.curr_time;
-    state.curr_time = webhacks::get_time() as f32;
+    state.curr_time += input.dt;
 
-    let update = handle_keys(&state);
+    let update = handle_keys(&state, &input);
     apply_keys_update(&mut state, update);
 
-    let update = handle_mouse(&state);
+    let update = handle_mouse(&state, &input);
     {
         state.mouse_pos = update.mouse_pos;
         state.mouse_btn = update.mouse_btn.into();
-        state.mouse_btn_pressed = update.mouse_btn_pressed.into();
+        // Latch the click until a simulation step gets to see it. Otherwise a frame with
+        // zero steps would drop it and a frame with several would handle it several times.
+        state.mouse_btn_pressed =
+            (update.mouse_btn_pressed || state.mouse_btn_pressed.into()).into();
+        state.upgrade_pressed = (update.upgrade || state.upgrade_pressed.into()).into();
+        state.sell_pressed = (update.sell || state.sell_pressed.into()).into();
     }
 
     let mut game_over = false;
+    let mut victory = false;
     if (!state.editor).into() {
-        let update = handle_entities(&state);
-        apply_entities_update(&mut state, update);
+        state.sim_accumulator += state.frame_dt();
+
+        let mut steps = 0;
+        while state.sim_accumulator >= SIM_DT && steps < MAX_SIM_STEPS {
+            let update = handle_entities(&state);
+            apply_entities_update(&mut state, update);
+            state.mouse_btn_pressed = false.into();
+            state.upgrade_pressed = false.into();
+            state.sell_pressed = false.into();
+
+            state.sim_time += SIM_DT;
+            state.sim_accumulator -= SIM_DT;
+            steps += 1;
+        }
+
+        if steps == MAX_SIM_STEPS {
+            // We've hit the catch-up limit (e.g. after a long hitch). Drop the backlog
+            // rather than trying to make up for it over the next frames.
+            state.sim_accumulator = state.sim_accumulator.min(SIM_DT);
+        }
+
         game_over = state.life == 0;
+        victory = !game_over && state.waves.is_complete();
+    } else {
+        let update = editor::handle_editor(&state, &input);
+        editor::apply_editor_update(&mut state, update);
+
+        state.sim_accumulator = 0.0;
+        state.mouse_btn_pressed = false.into();
+        state.upgrade_pressed = false.into();
+        state.sell_pressed = false.into();
     }
 
-    unsafe { raylib::BeginDrawing() };
+    // How far we are between the last simulation step and the next one
+    let alpha = (state.sim_accumulator / SIM_DT).clamp(0.0, 1.0);
+
+    webhacks::begin_drawing();
 
     {
-        unsafe { raylib::ClearBackground(BLUE) };
+        webhacks::clear_background(BLUE);
 
         // draw the background image
         if !webhacks::is_null_texture(state.bkg_texture) {
@@ -878,20 +1575,28 @@ pub fn game_frame(state_ptr: *mut State) {
         );
 
         draw_text_overlay(&state);
+        draw_build_menu(&state);
         if state.debug.into() {
-            draw_entities_debug(&state);
+            draw_entities_debug(&state, alpha);
         }
         draw_path(&state);
-        draw_entities_foreground(&state);
+        if state.editor.into() {
+            draw_editor(&state);
+        }
+        draw_entities_foreground(&state, alpha);
+        draw_placement_ghost(&state);
+        draw_turret_info(&state);
 
         draw_mouse(&state);
 
         if game_over {
-            draw_game_over_overlay(&state);
+            draw_end_overlay(&state, "Game Over!");
+        } else if victory {
+            draw_end_overlay(&state, "Victory!");
         }
     }
 
-    unsafe { raylib::EndDrawing() };
+    webhacks::end_drawing();
 
     {
         // Update the music stream
@@ -901,15 +1606,160 @@ pub fn game_frame(state_ptr: *mut State) {
         state.frame_count += 1;
     }
 
-    // Write back the state
-    unsafe {
-        std::ptr::write(state_ptr, state);
+    // the guard writes back the state
+}
+
+// A `ptr::read` copy of the state which gets written back when dropped, including when
+// unwinding from a panic. A plain copy would be dropped on unwind while the original still
+// points at the same allocations.
+struct StateGuard {
+    ptr: *mut State,
+    state: std::mem::ManuallyDrop<State>,
+}
+
+impl StateGuard {
+    fn read(ptr: *mut State) -> StateGuard {
+        let state = unsafe { std::ptr::read(ptr) };
+        StateGuard {
+            ptr,
+            state: std::mem::ManuallyDrop::new(state),
+        }
+    }
+}
+
+impl std::ops::Deref for StateGuard {
+    type Target = State;
+    fn deref(&self) -> &State {
+        &self.state
     }
 }
 
+impl std::ops::DerefMut for StateGuard {
+    fn deref_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+}
+
+impl Drop for StateGuard {
+    fn drop(&mut self) {
+        unsafe { std::ptr::write(self.ptr, std::mem::ManuallyDrop::take(&mut self.state)) };
+    }
+}
+
+thread_local! {
+    static LAST_PANIC: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+// Runs `f`, and turns a panic in it into the panic message. Everything the runner calls has to
+// go through here (or `game_frame_checked`, which uses it): the runner has its own copy of std
+// and can't catch panics coming from ours. The callers make sure the state is still sound after
+// a panic, which is why `f` is taken as unwind safe.
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    static HOOK: std::sync::Once = std::sync::Once::new();
+    HOOK.call_once(|| {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            LAST_PANIC.with(|p| *p.borrow_mut() = Some(info.to_string()));
+            default_hook(info);
+        }));
+    });
+
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).map_err(|_| {
+        LAST_PANIC
+            .with(|p| p.borrow_mut().take())
+            .unwrap_or_else(|| "the game panicked".to_string())
+    })
+}
+
+pub type GameFrameChecked =
+    unsafe fn(state_ptr: *mut State, input_ptr: *const FrameInput) -> Result<(), String>;
+
+// Frames between two snapshots taken by `game_frame_checked`
+pub const SNAPSHOT_FRAMES: u32 = 60;
+
+// The save of a state at the start of a frame, for `game_frame_checked` to go back to
+struct Snapshot {
+    state: *const State,
+    frame_count: u32,
+    data: Vec<u8>,
+}
+
+thread_local! {
+    static SNAPSHOT: std::cell::RefCell<Option<Snapshot>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Same as `game_frame_input`, but a panic doesn't go past the library. On a panic the state is
+/// put back to the last snapshot, and the panic message comes back as the error. Taking the
+/// snapshot is part of the checked frame too: if that panics, the state is left as it is.
+///
+/// Saving the state every frame would cost as much as the frame itself, so the snapshot is only
+/// retaken every SNAPSHOT_FRAMES frames, and whenever it's for a different state or from the
+/// future (after loading a save). A fresh library has none, so the first frame after a reload
+/// always takes one. A panic therefore loses:
+///
+///   - up to SNAPSHOT_FRAMES frames of everything in the save (see `save.rs`), editor changes
+///     to the paths included
+///   - the events not handled yet, which belong to frames that are rolled back
+///   - the click, upgrade and sell latches, the selected turret, and whatever the editor was
+///     dragging
+///
+/// What isn't in the save (build selection, editor tool, the loaded level, the assets) is kept
+/// as it was when the panic hit.
+///
+/// # Safety
+///
+/// Same as `game_frame_input`.
+#[no_mangle]
+pub unsafe fn game_frame_checked(
+    state_ptr: *mut State,
+    input_ptr: *const FrameInput,
+) -> Result<(), String> {
+    let result = catch_panic(|| {
+        SNAPSHOT.with(|snapshot| {
+            let state = unsafe { &*state_ptr };
+            let mut snapshot = snapshot.borrow_mut();
+            let stale = snapshot.as_ref().is_none_or(|s| {
+                !std::ptr::eq(s.state, state)
+                    || state.frame_count < s.frame_count
+                    || state.frame_count - s.frame_count >= SNAPSHOT_FRAMES
+            });
+            if stale {
+                // a stale snapshot is no use to go back to, even if saving the new one panics
+                *snapshot = None;
+                *snapshot = Some(Snapshot {
+                    state,
+                    frame_count: state.frame_count,
+                    data: save::save_state(state),
+                });
+            }
+        });
+        unsafe { game_frame_input(state_ptr, input_ptr) }
+    });
+    let Err(message) = result else {
+        return Ok(());
+    };
+
+    let state = unsafe { &mut *state_ptr };
+    let restored = SNAPSHOT.with(|snapshot| match snapshot.borrow().as_ref() {
+        Some(snapshot) => save::load_state(state, &snapshot.data),
+        None => Ok(()),
+    });
+    if let Err(err) = restored {
+        return Err(format!(
+            "{}\n(and failed to restore the state: {})",
+            message, err
+        ));
+    }
+    state.mouse_btn_pressed = false.into();
+    state.upgrade_pressed = false.into();
+    state.sell_pressed = false.into();
+
+    Err(message)
+}
+
 #[no_mangle]
 pub unsafe fn game_over() {
-    raylib::CloseWindow();
+    webhacks::close_window();
 }
 
 // CAREFUL!
@@ -920,6 +1770,10 @@ pub unsafe fn game_over() {
 #[cfg(feature = "web")]
 #[no_mangle]
 pub fn from_js_malloc(size: usize) -> *mut u8 {
+    // allocating 0 bytes is UB. an aligned dangling pointer is fine to read 0 bytes from
+    if size == 0 {
+        return std::ptr::NonNull::<u32>::dangling().as_ptr() as *mut u8;
+    }
     let layout = std::alloc::Layout::from_size_align(size, 4).unwrap();
     let ptr = unsafe { std::alloc::alloc(layout) };
     log::trace(format!("[from_js_malloc] size: {}, ptr: {:?}", size, ptr).as_str());
@@ -929,7 +1783,832 @@ pub fn from_js_malloc(size: usize) -> *mut u8 {
 #[cfg(feature = "web")]
 #[no_mangle]
 pub fn from_js_free(ptr: *mut u8, size: usize) {
+    // see `from_js_malloc`, nothing was allocated
+    if size == 0 {
+        return;
+    }
     let layout = std::alloc::Layout::from_size_align(size, 4).unwrap();
     unsafe { std::alloc::dealloc(ptr, layout) }
     log::trace(format!("[from_js_free] size: {}, ptr: {:?}", size, ptr).as_str());
 }
+
+#[cfg(all(test, feature = "headless"))]
+mod tests {
+    use super::*;
+    use crate::bullet::{Bullet, ProjectileKind};
+    use crate::enemy::EnemyKind;
+    use crate::entity_manager::EntityId;
+    use crate::turret::Targeting;
+    use raylib::MouseButton;
+
+    const DT: f32 = 1.0 / 60.0;
+
+    fn init_and_load() -> State {
+        headless::reset();
+        let mut state = game_init();
+        while !bool::from(state.all_loaded) {
+            game_load(&mut state);
+            headless::advance(DT);
+        }
+        state
+    }
+
+    fn run_frames(state: &mut State, n: usize) {
+        for _ in 0..n {
+            unsafe { game_frame(state) };
+            headless::advance(DT);
+        }
+    }
+
+    #[test]
+    fn test_simulated_game_spawns_and_shoots() {
+        let mut state = init_and_load();
+        assert_eq!(state.man.turrets.len(), 2);
+
+        let mut max_bullets = 0;
+        for _ in 0..600 {
+            run_frames(&mut state, 1);
+            max_bullets = max_bullets.max(state.man.bullets.len());
+        }
+
+        assert!(!state.man.enemies.is_empty());
+        assert!(max_bullets > 0);
+    }
+
+    #[test]
+    fn test_click_places_turret() {
+        let mut state = init_and_load();
+        headless::set_mouse_position(Vector2::new(600.0, 400.0));
+        headless::set_mouse_button_down(MouseButton::Left as i32, true);
+        run_frames(&mut state, 1);
+        headless::set_mouse_button_down(MouseButton::Left as i32, false);
+        run_frames(&mut state, 1);
+
+        assert_eq!(state.man.turrets.len(), 3);
+    }
+
+    #[test]
+    fn test_build_menu_selects_turret_type() {
+        let mut state = init_and_load();
+        press(&mut state, KEY::Three);
+        assert_eq!(state.build_kind, TurretKind::Sniper);
+
+        headless::set_mouse_position(Vector2::new(600.0, 400.0));
+        headless::set_mouse_button_down(MouseButton::Left as i32, true);
+        run_frames(&mut state, 1);
+        headless::set_mouse_button_down(MouseButton::Left as i32, false);
+        run_frames(&mut state, 1);
+
+        let placed = state.man.turrets.last().unwrap();
+        assert_eq!(placed.kind, TurretKind::Sniper);
+        assert_eq!(placed.radius, TurretKind::Sniper.archetype().radius);
+    }
+
+    #[test]
+    fn test_targeting_cycles_on_selected_turret() {
+        let mut state = init_and_load();
+        let position = state.man.turrets[0].position;
+        click(&mut state, position);
+        assert_eq!(state.selected, state.man.turrets[0].id);
+
+        // hovering the other one doesn't matter, the selection does
+        headless::set_mouse_position(state.man.turrets[1].position);
+        run_frames(&mut state, 2);
+        assert!(bool::from(state.man.turrets[1].hover));
+
+        assert_eq!(state.man.turrets[0].targeting, Targeting::Closest);
+        press(&mut state, KEY::T);
+        assert_eq!(state.man.turrets[0].targeting, Targeting::First);
+        assert_eq!(state.man.turrets[1].targeting, Targeting::Closest);
+    }
+
+    // No turrets, no waves, just what the test puts in
+    fn empty_field() -> State {
+        let mut state = init_and_load();
+        state.man = EntityManager::new();
+        state.waves = waves::Waves::new(Vec::new());
+        state
+    }
+
+    fn add_slime(state: &mut State, linear: f32) -> EntityId {
+        let position = state.paths.lin_to_position(0, linear);
+        state
+            .man
+            .add(Enemy::new(EnemyKind::Slime, position, 0.0).into())
+    }
+
+    fn step(state: &mut State, n: usize) {
+        for _ in 0..n {
+            let update = handle_entities(state);
+            apply_entities_update(state, update);
+        }
+    }
+
+    fn health(state: &State, id: EntityId) -> u32 {
+        state.man.get_enemy(id).unwrap().health
+    }
+
+    #[test]
+    fn test_straight_bullets_can_miss() {
+        let mut state = empty_field();
+        let enemy = add_slime(&mut state, 30.0);
+        state.man.rebuild_index();
+
+        // fired straight up, away from the enemy
+        let mut bullet = Bullet::new(ProjectileKind::Bullet, Vector2::new(300.0, 300.0), 0, None);
+        bullet.velocity = Vector2::new(0.0, -260.0);
+        state.man.add(bullet.into());
+        step(&mut state, 200);
+        assert!(state.man.bullets.is_empty());
+        assert_eq!(health(&state, enemy), 3);
+
+        // and aimed at it
+        let bullet = Bullet::aimed(
+            ProjectileKind::Bullet,
+            Vector2::new(300.0, 300.0),
+            0,
+            state.man.get_enemy(enemy).unwrap(),
+            &state.paths,
+        );
+        state.man.add(bullet.into());
+        step(&mut state, 200);
+        assert!(state.man.bullets.is_empty());
+        assert_eq!(health(&state, enemy), 2);
+    }
+
+    #[test]
+    fn test_piercing_bullets_hit_several() {
+        let mut state = empty_field();
+        // in a row along the first bit of the path, which goes right
+        let enemies = [5.0, 30.0, 55.0, 75.0].map(|linear| add_slime(&mut state, linear));
+        state.man.rebuild_index();
+
+        let start = state.paths.lin_to_position(0, 78.0).xy;
+        let mut slug = Bullet::new(ProjectileKind::Slug, start, 0, None);
+        slug.velocity = Vector2::new(-700.0, 0.0);
+        state.man.add(slug.into());
+        step(&mut state, 20);
+
+        assert!(state.man.bullets.is_empty());
+        let healths = enemies.map(|id| health(&state, id));
+        // three of them, each once, from the front
+        assert_eq!(healths, [3, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_shells_splash() {
+        let mut state = empty_field();
+        let near = [30.0, 40.0, 50.0].map(|linear| add_slime(&mut state, linear));
+        let far = add_slime(&mut state, 250.0);
+        state.man.rebuild_index();
+
+        let mut shell = Bullet::aimed(
+            ProjectileKind::Shell,
+            Vector2::new(300.0, 80.0),
+            0,
+            state.man.get_enemy(near[1]).unwrap(),
+            &state.paths,
+        );
+        shell.damage = 2;
+        state.man.add(shell.into());
+        step(&mut state, 200);
+
+        assert!(state.man.bullets.is_empty());
+        assert_eq!(near.map(|id| health(&state, id)), [1, 1, 1]);
+        assert_eq!(health(&state, far), 3);
+        assert_eq!(state.stats.bullets_hit, 3);
+    }
+
+    #[test]
+    fn test_status_effects_from_projectiles() {
+        let mut state = empty_field();
+        let slowed = add_slime(&mut state, 30.0);
+        let free = add_slime(&mut state, 30.0);
+        state.man.rebuild_index();
+
+        let bolt = Bullet::aimed(
+            ProjectileKind::FrostBolt,
+            Vector2::new(300.0, 300.0),
+            0,
+            state.man.get_enemy(slowed).unwrap(),
+            &state.paths,
+        );
+        state.man.add(bolt.into());
+        // the bolt only hits one of the two, which are on top of each other
+        step(&mut state, 100);
+        assert!(state.man.bullets.is_empty());
+        let linear = |state: &State, id| state.man.get_enemy(id).unwrap().position.linear;
+        let (a, b) = (linear(&state, slowed), linear(&state, free));
+        assert!(a < b, "{} should be behind {}", a, b);
+
+        // poison from a turret which isn't there any more still gets the kill
+        let turret = state
+            .man
+            .add(Turret::new(TurretKind::Venom, Vector2::zero()).into());
+        let poisoned = state.man.get_enemy_mut(free).unwrap();
+        for _ in 0..3 {
+            poisoned
+                .statuses
+                .apply(ProjectileKind::Dart.archetype().status.unwrap(), turret);
+        }
+        state.man.turrets[0].dead = true.into();
+        step(&mut state, (SIM_HZ * 1.5) as usize);
+        assert!(state.man.get_enemy(free).is_none());
+        assert_eq!(state.stats.enemies_killed, 1);
+        assert_eq!(state.stats.bullets_hit, 1);
+    }
+
+    fn click(state: &mut State, position: Vector2) {
+        headless::set_mouse_position(position);
+        headless::set_mouse_button_down(MouseButton::Left as i32, true);
+        run_frames(state, 1);
+        headless::set_mouse_button_down(MouseButton::Left as i32, false);
+        run_frames(state, 1);
+    }
+
+    fn rect_center(rect: &raylib::Rectangle) -> Vector2 {
+        Vector2::new(rect.x + rect.width / 2.0, rect.y + rect.height / 2.0)
+    }
+
+    // click the turret at `position` to open its panel, then its sell button
+    fn sell(state: &mut State, position: Vector2) {
+        click(state, position);
+        let turret = state.man.get_turret(state.selected).unwrap();
+        let button = rect_center(&turret_panel(turret).sell);
+        click(state, button);
+    }
+
+    #[test]
+    fn test_gold_pays_for_turrets() {
+        let mut state = init_and_load();
+        state.waves = waves::Waves::new(Vec::new());
+        assert_eq!(state.gold, state.level.gold);
+
+        let gun = TurretKind::Gun.archetype().build_cost();
+        click(&mut state, Vector2::new(600.0, 400.0));
+        assert_eq!(state.man.turrets.len(), 3);
+        assert_eq!(state.gold, state.level.gold - gun);
+
+        // selling it gives some back
+        sell(&mut state, Vector2::new(600.0, 400.0));
+        assert_eq!(state.man.turrets.len(), 2);
+        let refund = (gun as f32 * turret::SELL_REFUND) as u32;
+        assert_eq!(state.gold, state.level.gold - gun + refund);
+
+        // can't place what we can't afford
+        state.gold = gun - 1;
+        click(&mut state, Vector2::new(600.0, 400.0));
+        assert_eq!(state.man.turrets.len(), 2);
+        assert_eq!(state.gold, gun - 1);
+    }
+
+    #[test]
+    fn test_upgrades_cost_gold_and_count_toward_refund() {
+        let mut state = init_and_load();
+        state.waves = waves::Waves::new(Vec::new());
+        let position = Vector2::new(600.0, 400.0);
+        click(&mut state, position);
+        headless::set_mouse_position(position);
+        run_frames(&mut state, 2);
+        let turret = state.man.turrets.last().unwrap();
+        assert!(bool::from(turret.hover));
+        let tiers = turret.archetype().tiers;
+
+        // U only upgrades the selected turret
+        state.gold = tiers[1].cost + tiers[2].cost - 1;
+        press(&mut state, KEY::U);
+        assert_eq!(state.man.turrets.last().unwrap().tier, 0);
+
+        click(&mut state, position);
+        let id = state.man.turrets.last().unwrap().id;
+        assert_eq!(state.selected, id);
+        press(&mut state, KEY::U);
+        let turret = state.man.turrets.last().unwrap();
+        assert_eq!(turret.tier, 1);
+        assert_eq!(turret.stats(), &tiers[1]);
+        assert_eq!(state.gold, tiers[2].cost - 1);
+        assert_eq!(state.stats.turrets_upgraded, 1);
+
+        // not enough for the next one, from the panel's button either
+        let panel = turret_panel(state.man.get_turret(id).unwrap());
+        click(&mut state, rect_center(&panel.upgrade));
+        assert_eq!(state.man.turrets.last().unwrap().tier, 1);
+        assert_eq!(state.selected, id);
+
+        // selling refunds part of the build and the upgrade
+        state.gold = 0;
+        sell(&mut state, position);
+        let spent = tiers[0].cost + tiers[1].cost;
+        assert_eq!(state.gold, (spent as f32 * turret::SELL_REFUND) as u32);
+    }
+
+    #[test]
+    fn test_clicking_selects_turrets() {
+        let mut state = init_and_load();
+        state.waves = waves::Waves::new(Vec::new());
+        let position = Vector2::new(600.0, 400.0);
+        click(&mut state, position);
+        let turrets = state.man.turrets.len();
+        let id = state.man.turrets.last().unwrap().id;
+
+        // clicking a turret selects it instead of selling it
+        click(&mut state, position);
+        assert_eq!(state.selected, id);
+        assert_eq!(state.man.turrets.len(), turrets);
+
+        // the panel takes the click, so nothing gets placed under it
+        let panel = turret_panel(state.man.get_turret(id).unwrap());
+        let gold = state.gold;
+        click(
+            &mut state,
+            Vector2::new(panel.area.x + 4.0, panel.area.y + 4.0),
+        );
+        assert_eq!(state.selected, id);
+        assert_eq!(state.man.turrets.len(), turrets);
+        assert_eq!(state.gold, gold);
+
+        // clicking anywhere else only clears it, even where a turret could go
+        state.gold = 1000;
+        let elsewhere = position + Vector2::new(-100.0, 0.0);
+        click(&mut state, elsewhere);
+        assert_eq!(state.selected, NO_ID);
+        assert_eq!(state.man.turrets.len(), turrets);
+        click(&mut state, elsewhere);
+        assert_eq!(state.man.turrets.len(), turrets + 1);
+    }
+
+    #[test]
+    fn test_placement_is_validated_and_snapped() {
+        let mut state = init_and_load();
+        state.waves = waves::Waves::new(Vec::new());
+        let turrets = state.man.turrets.len();
+
+        // on the path, on top of another turret, and half off-screen
+        let existing = state.man.turrets[0].position;
+        for position in [
+            Vector2::new(40.0, 80.0),
+            existing + Vector2::new(30.0, 0.0),
+            Vector2::new(790.0, 300.0),
+        ] {
+            click(&mut state, position);
+            assert_eq!(state.man.turrets.len(), turrets);
+        }
+
+        press(&mut state, KEY::G);
+        assert!(bool::from(state.snap));
+        click(&mut state, Vector2::new(605.0, 395.0));
+        assert_eq!(state.man.turrets.len(), turrets + 1);
+        let placed = state.man.turrets.last().unwrap();
+        assert_eq!(placed.position, Vector2::new(620.0, 380.0));
+    }
+
+    #[test]
+    fn test_editor_edits_path_and_turrets() {
+        let mut state = init_and_load();
+        press(&mut state, KEY::E);
+        assert!(bool::from(state.editor));
+        let nodes = state.paths.paths[0].nodes.len();
+        let length =
+            |state: &State| path::Path::new(state.paths.paths[0].nodes.clone()).total_length;
+
+        // add a node at the end, and insert one into the segment along y = 230
+        click(&mut state, Vector2::new(400.0, 400.0));
+        assert_eq!(state.paths.paths[0].nodes.len(), nodes + 1);
+        assert_eq!(
+            state.paths.paths[0].nodes[nodes],
+            Vector2::new(400.0, 400.0)
+        );
+        click(&mut state, Vector2::new(300.0, 232.0));
+        assert_eq!(state.paths.paths[0].nodes.len(), nodes + 2);
+        assert_eq!(state.paths.paths[0].nodes[3], Vector2::new(300.0, 230.0));
+        assert_eq!(state.paths.paths[0].total_length, length(&state));
+
+        // drag the second node somewhere else, then delete it
+        headless::set_mouse_position(Vector2::new(80.0, 80.0));
+        headless::set_mouse_button_down(MouseButton::Left as i32, true);
+        run_frames(&mut state, 1);
+        headless::set_mouse_position(Vector2::new(120.0, 100.0));
+        run_frames(&mut state, 1);
+        headless::set_mouse_button_down(MouseButton::Left as i32, false);
+        run_frames(&mut state, 1);
+        assert_eq!(state.paths.paths[0].nodes[1], Vector2::new(120.0, 100.0));
+        assert_eq!(state.paths.paths[0].total_length, length(&state));
+        press(&mut state, KEY::X);
+        assert_eq!(state.paths.paths[0].nodes.len(), nodes + 1);
+        assert_eq!(state.paths.paths[0].nodes[1], Vector2::new(80.0, 230.0));
+
+        // preset turrets are free
+        press(&mut state, KEY::N);
+        let (turrets, gold) = (state.man.turrets.len(), state.gold);
+        click(&mut state, Vector2::new(600.0, 400.0));
+        assert_eq!(state.man.turrets.len(), turrets + 1);
+        assert_eq!(state.gold, gold);
+        press(&mut state, KEY::X);
+        assert_eq!(state.man.turrets.len(), turrets);
+    }
+
+    fn init_with_level(text: &str) -> State {
+        headless::reset();
+        headless::save_file_data(level::LEVEL_PATH, text.as_bytes());
+        let mut state = game_init();
+        while !bool::from(state.all_loaded) {
+            game_load(&mut state);
+            headless::advance(DT);
+        }
+        state
+    }
+
+    #[test]
+    fn test_level_file_is_loaded() {
+        let state =
+            init_with_level("life 3\ngold 7\nnode 0 0\nnode 50 0\nturret cannon 10 40\nwave 1\n");
+        assert_eq!((state.life, state.gold), (3, 7));
+        assert_eq!(state.paths.paths[0].total_length, 50.0);
+        assert_eq!(state.man.turrets.len(), 1);
+        assert_eq!(state.man.turrets[0].kind, TurretKind::Cannon);
+        assert!(state.man.turrets[0].anim.is_some());
+        assert_eq!(state.waves.waves.len(), 1);
+
+        // a broken file leaves the built-in level in place
+        let state = init_with_level("node 0 0\nnode 50 zero\n");
+        let builtin = level::Level::parse(level::BUILTIN_LEVEL).unwrap();
+        assert_eq!(state.paths.paths[0].nodes, builtin.paths[0].nodes);
+        assert_eq!(state.man.turrets.len(), builtin.turrets.len());
+    }
+
+    #[test]
+    fn test_editor_saves_level() {
+        let mut state = init_and_load();
+        // bought in the game, so not part of the level
+        let turrets = state.man.turrets.len();
+        click(&mut state, Vector2::new(600.0, 400.0));
+        assert_eq!(state.man.turrets.len(), turrets + 1);
+
+        press(&mut state, KEY::E);
+        click(&mut state, Vector2::new(400.0, 400.0));
+        press(&mut state, KEY::N);
+        click(&mut state, Vector2::new(500.0, 450.0));
+        press(&mut state, KEY::K);
+
+        let data = headless::load_file_data(level::LEVEL_PATH).unwrap();
+        let saved = level::Level::parse(std::str::from_utf8(&data).unwrap()).unwrap();
+        assert_eq!(saved.paths[0].nodes, state.paths.paths[0].nodes);
+        assert_eq!(saved.turrets.len(), turrets + 1);
+        assert_eq!(saved.turrets[turrets].position, Vector2::new(500.0, 450.0));
+        assert_eq!(saved.waves, state.waves.waves);
+        assert_eq!(saved.life, state.level.life);
+        // and it's what the next game starts with
+        let text = String::from_utf8(data).unwrap();
+        assert_eq!(
+            init_with_level(&text).paths.paths[0].nodes,
+            state.paths.paths[0].nodes
+        );
+    }
+
+    #[test]
+    fn test_enemies_take_both_ways_at_a_fork() {
+        let mut state = init_with_level(
+            "life 50\n\
+            path\nnode 0 300\nnode 100 300\nbranch 1 0 1\nbranch 2 0 1\n\
+            path\nnode 100 300\nnode 100 200\n\
+            path\nnode 100 300\nnode 100 400\n\
+            wave 0\ngroup slime 10 0.1 0 90\n",
+        );
+        assert_eq!(state.paths.exits().count(), 2);
+
+        let mut taken = [false; 3];
+        for _ in 0..600 {
+            run_frames(&mut state, 1);
+            for enemy in state.man.enemies.iter() {
+                taken[enemy.position.path] = true;
+            }
+            if state.stats.enemies_spawned == 10 && state.man.enemies.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(taken, [true, true, true]);
+        // every one of them made it out one way or the other
+        assert_eq!(state.life, 40);
+    }
+
+    #[test]
+    fn test_kills_pay_bounty() {
+        let mut state = empty_field();
+        let enemy = add_slime(&mut state, 30.0);
+        state.man.get_enemy_mut(enemy).unwrap().health = 1;
+        state.man.rebuild_index();
+        let gold = state.gold;
+
+        let bullet = Bullet::aimed(
+            ProjectileKind::Bullet,
+            Vector2::new(300.0, 300.0),
+            0,
+            state.man.get_enemy(enemy).unwrap(),
+            &state.paths,
+        );
+        state.man.add(bullet.into());
+        step(&mut state, 200);
+        assert!(state.man.enemies.is_empty());
+        assert_eq!(state.gold, gold + EnemyKind::Slime.archetype().bounty);
+    }
+
+    #[test]
+    fn test_events_are_counted() {
+        let mut state = init_and_load();
+
+        // place a turret, then sell it from its panel
+        click(&mut state, Vector2::new(600.0, 400.0));
+        sell(&mut state, Vector2::new(600.0, 400.0));
+        assert_eq!(state.stats.turrets_placed, 1);
+        assert_eq!(state.stats.turrets_sold, 1);
+
+        run_frames(&mut state, 600);
+        assert!(state.stats.enemies_spawned > 0);
+        assert!(state.stats.bullets_hit >= state.stats.enemies_killed);
+        // everything was handled in the step it happened in
+        assert!(state.events.take().is_empty());
+    }
+
+    #[test]
+    fn test_last_wave_cleared_wins() {
+        let mut state = init_and_load();
+        // a single enemy which appears right by the end of the path
+        let end = state.paths.paths[0].total_length - 5.0;
+        state.waves = waves::Waves::new(vec![waves::Wave {
+            pause: 0.5,
+            groups: vec![waves::Group {
+                enemy: crate::enemy::EnemyKind::Slime,
+                count: 1,
+                spacing: 0.0,
+                delay: 0.0,
+                spawn: end,
+                path: 0,
+            }],
+        }]);
+
+        run_frames(&mut state, 20);
+        assert!(state.man.enemies.is_empty());
+        assert!(!state.waves.is_complete());
+
+        run_frames(&mut state, 40);
+        assert_eq!(state.stats.enemies_spawned, 1);
+        assert_eq!(state.life, 19);
+        assert_eq!(state.stats.waves_cleared, 1);
+        assert!(state.waves.is_complete());
+
+        // and nothing else turns up
+        run_frames(&mut state, 120);
+        assert_eq!(state.stats.enemies_spawned, 1);
+    }
+
+    #[test]
+    fn test_simulation_independent_of_framerate() {
+        // Both run for the same two seconds of wall-clock time
+        let mut slow = init_and_load();
+        for _ in 0..=60 {
+            unsafe { game_frame(&mut slow) };
+            headless::advance(1.0 / 30.0);
+        }
+
+        let mut fast = init_and_load();
+        for _ in 0..=240 {
+            unsafe { game_frame(&mut fast) };
+            headless::advance(1.0 / 120.0);
+        }
+
+        let steps = |state: &State| (state.sim_time / SIM_DT).round() as i32;
+        assert!((steps(&slow) - steps(&fast)).abs() <= 1);
+        assert_eq!(slow.man.enemies.len(), fast.man.enemies.len());
+    }
+
+    #[test]
+    fn test_hitch_is_clamped() {
+        let mut state = init_and_load();
+        run_frames(&mut state, 1);
+        let sim_time = state.sim_time;
+
+        headless::advance(5.0);
+        unsafe { game_frame(&mut state) };
+
+        let steps = ((state.sim_time - sim_time) / SIM_DT).round() as u32;
+        assert_eq!(steps, MAX_SIM_STEPS);
+        assert!(state.sim_accumulator <= SIM_DT);
+    }
+
+    #[test]
+    fn test_replay_reproduces_session() {
+        let mut live = init_and_load();
+        let mut recording = input::encode_header(DEFAULT_SEED).to_vec();
+        for i in 0..300 {
+            // click somewhere every now and then to place/remove turrets
+            let click = i % 50 == 0;
+            headless::set_mouse_position(Vector2::new(300.0 + i as f32, 300.0));
+            headless::set_mouse_button_down(MouseButton::Left as i32, click);
+            headless::set_key_down(KEY::D, i % 30 < 10);
+
+            let input = unsafe { game_poll_input(&live) };
+            recording.extend_from_slice(&input::encode_frame(&input));
+            unsafe { game_frame_input(&mut live, &input) };
+            headless::advance(DT * (1.0 + (i % 3) as f32));
+        }
+
+        let recording = input::decode_recording(&recording).unwrap();
+        headless::reset();
+        let mut replay = game_init_seeded(recording.seed);
+        while !bool::from(replay.all_loaded) {
+            game_load(&mut replay);
+        }
+        for input in recording.frames.iter() {
+            unsafe { game_frame_input(&mut replay, input) };
+        }
+
+        assert_eq!(live.slime_pos, replay.slime_pos);
+        assert_eq!(live.man.turrets.len(), replay.man.turrets.len());
+        assert_eq!(live.man.enemies.len(), replay.man.enemies.len());
+        for (a, b) in live.man.enemies.iter().zip(replay.man.enemies.iter()) {
+            assert_eq!(a.position, b.position);
+            assert_eq!(a.health, b.health);
+        }
+    }
+
+    fn press(state: &mut State, key: KEY) {
+        headless::set_key_down(key, true);
+        run_frames(state, 1);
+        headless::set_key_down(key, false);
+        run_frames(state, 1);
+    }
+
+    #[test]
+    fn test_save_and_quick_load() {
+        let mut state = init_and_load();
+        run_frames(&mut state, 200);
+        press(&mut state, KEY::K);
+
+        let life = state.life;
+        let enemies = state
+            .man
+            .enemies
+            .iter()
+            .map(|e| (e.id, e.position, e.health))
+            .collect::<Vec<_>>();
+        let turret_ids = state.man.turrets.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        // move on, then load back
+        run_frames(&mut state, 200);
+        let position = state.man.turrets[0].position;
+        click(&mut state, position);
+        assert_ne!(state.selected, NO_ID);
+        press(&mut state, KEY::L);
+        // the ids are the save's now, so nothing stays selected
+        assert_eq!(state.selected, NO_ID);
+
+        // loading happens in the first of the two frames, so the second one has run a
+        // simulation step on top of the loaded state. compare the things that don't move.
+        assert_eq!(state.life, life);
+        assert_eq!(
+            state.man.turrets.iter().map(|t| t.id).collect::<Vec<_>>(),
+            turret_ids
+        );
+        assert!(state.man.enemies.iter().all(|e| e.anim.is_some()));
+        assert!(enemies
+            .iter()
+            .all(|(id, _, _)| state.man.get_enemy(*id).is_some()));
+    }
+
+    #[test]
+    fn test_load_rejects_other_version() {
+        let mut state = init_and_load();
+        let mut data = save::save_state(&state);
+        data[4..8].copy_from_slice(&(save::SAVE_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            save::load_state(&mut state, &data),
+            Err(save::SaveError::VersionMismatch {
+                found: save::SAVE_VERSION + 1,
+                expected: save::SAVE_VERSION
+            })
+        );
+
+        let data = save::save_state(&state);
+        assert_eq!(
+            save::load_state(&mut state, &data[..data.len() - 4]),
+            Err(save::SaveError::Truncated)
+        );
+        assert_eq!(save::load_state(&mut state, &data), Ok(()));
+    }
+
+    #[test]
+    fn test_migrate_carries_state_over() {
+        let mut state = init_and_load();
+        run_frames(&mut state, 200);
+        let data = unsafe { game_save(&state) };
+
+        let old_layout = layout::state_layout();
+        let mut migrated = std::mem::MaybeUninit::<State>::uninit();
+        assert!(unsafe { game_migrate(&old_layout, &data, migrated.as_mut_ptr()) });
+        let mut migrated = unsafe { migrated.assume_init() };
+        assert_eq!(save::save_state(&migrated), data);
+
+        // the assets come back through the usual loading path
+        assert!(!bool::from(migrated.all_loaded));
+        while !bool::from(migrated.all_loaded) {
+            game_load(&mut migrated);
+            headless::advance(DT);
+        }
+        assert!(migrated.man.enemies.iter().all(|e| e.anim.is_some()));
+        run_frames(&mut migrated, 10);
+
+        let mut untouched = std::mem::MaybeUninit::<State>::uninit();
+        assert!(!unsafe { game_migrate(&old_layout, &data[..8], untouched.as_mut_ptr()) });
+        let err = unsafe { game_migrate_checked(&old_layout, &data[..8], untouched.as_mut_ptr()) };
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_frame_panic_is_caught() {
+        let mut state = init_and_load();
+        run_frames(&mut state, 100);
+        let before = save::save_state(&state);
+
+        // blows up after the simulation steps, so the state has already moved on
+        headless::set_panic_on_draw(true);
+        let input = unsafe { game_poll_input(&state) };
+        headless::advance(DT);
+        let message = unsafe { game_frame_checked(&mut state, &input) }.unwrap_err();
+        assert!(message.contains("panic on draw"), "{}", message);
+
+        // back to the last good state, and it still runs
+        assert_eq!(save::save_state(&state), before);
+        assert!(state.man.enemies.iter().all(|e| e.anim.is_some()));
+        let frame_count = state.frame_count;
+        let input = unsafe { game_poll_input(&state) };
+        headless::advance(DT);
+        assert_eq!(unsafe { game_frame_checked(&mut state, &input) }, Ok(()));
+        assert_eq!(state.frame_count, frame_count + 1);
+    }
+
+    #[test]
+    fn test_frame_panic_rolls_back_to_the_last_snapshot() {
+        let mut state = init_and_load();
+        let checked_frames = |state: &mut State, n: u32| {
+            for _ in 0..n {
+                let input = unsafe { game_poll_input(state) };
+                headless::advance(DT);
+                unsafe { game_frame_checked(state, &input) }.unwrap();
+            }
+        };
+        // snapshots at the first frame and SNAPSHOT_FRAMES later, not at every one
+        let start = state.frame_count;
+        checked_frames(&mut state, SNAPSHOT_FRAMES);
+        let at_snapshot = save::save_state(&state);
+        checked_frames(&mut state, 10);
+        assert_eq!(state.frame_count, start + SNAPSHOT_FRAMES + 10);
+
+        state.mouse_btn_pressed = true.into();
+        headless::set_panic_on_draw(true);
+        let input = unsafe { game_poll_input(&state) };
+        headless::advance(DT);
+        assert!(unsafe { game_frame_checked(&mut state, &input) }.is_err());
+        headless::set_panic_on_draw(false);
+
+        assert_eq!(state.frame_count, start + SNAPSHOT_FRAMES);
+        assert_eq!(save::save_state(&state), at_snapshot);
+        assert!(!bool::from(state.mouse_btn_pressed));
+        checked_frames(&mut state, 1);
+    }
+
+    #[test]
+    fn test_enemies_face_the_way_they_go() {
+        let mut state =
+            init_with_level("node 300 100\nnode 300 500\nwave 0\ngroup slime 1 1 0 50\n");
+        run_frames(&mut state, 2);
+        assert_eq!(state.man.enemies.len(), 1);
+
+        headless::set_record_draws(true);
+        run_frames(&mut state, 1);
+        let x = state.man.enemies[0].position.xy.x;
+        let rotation = headless::take_draw_calls()
+            .into_iter()
+            .find_map(|call| match call {
+                headless::DrawCall::Texture { dest, rotation, .. } if dest.x == x => Some(rotation),
+                _ => None,
+            });
+        // straight down
+        assert!(
+            rotation.is_some_and(|r| (r - 90.0).abs() < 1e-3),
+            "{:?}",
+            rotation
+        );
+    }
+
+    #[test]
+    fn test_draw_calls_are_recorded() {
+        let mut state = init_and_load();
+        headless::set_record_draws(true);
+        run_frames(&mut state, 1);
+        let calls = headless::take_draw_calls();
+        assert!(matches!(
+            calls.first(),
+            Some(headless::DrawCall::ClearBackground { .. })
+        ));
+    }
+}
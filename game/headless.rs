@@ -0,0 +1,540 @@
+// Headless platform backend. This is what `webhacks` talks to when built with the
+// `headless` feature, instead of raylib (native) or the js `ffi` module (web). Assets are
+// plain in-memory handles, draw calls are no-ops unless recording is switched on, input is
+// whatever the caller scripted, and time only moves when the caller advances it.
+//
+// Everything lives in a thread-local, so each test (which cargo runs on its own thread)
+// gets its own little world.
+//
+// A simulated frame looks like:
+//
+//   headless::set_key_down(KEY::W, true);
+//   unsafe { game_frame(&mut state) };
+//   headless::advance(1.0 / 60.0);
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use raylib_wasm::{Color, KeyboardKey, Rectangle};
+
+use crate::vec2::Vector2;
+
+pub type LogCallback = fn(i32, &str);
+
+#[derive(Debug, Clone)]
+pub enum DrawCall {
+    ClearBackground {
+        color: Color,
+    },
+    Line {
+        start: Vector2,
+        end: Vector2,
+        thickness: f32,
+        color: Color,
+    },
+    Circle {
+        position: Vector2,
+        radius: f32,
+        color: Color,
+    },
+    Rectangle {
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+        color: Color,
+    },
+    Text {
+        text: String,
+        position: Vector2,
+        size: i32,
+        color: Color,
+    },
+    Texture {
+        texture: u32,
+        dest: Rectangle,
+        rotation: f32,
+    },
+}
+
+#[derive(Clone)]
+struct ImageData {
+    width: usize,
+    height: usize,
+    colors: Vec<Color>,
+}
+
+impl ImageData {
+    // Stand-in for any image we were not told about up front. A single opaque square is
+    // a valid one-frame sprite sheet as far as `anim::find_blobs` is concerned.
+    fn placeholder() -> ImageData {
+        let (width, height) = (8, 8);
+        ImageData {
+            width,
+            height,
+            colors: vec![raylib_wasm::RAYWHITE; width * height],
+        }
+    }
+}
+
+struct Backend {
+    next_handle: u32,
+    images: HashMap<u32, ImageData>,
+    registered_images: HashMap<String, ImageData>,
+    textures: HashMap<u32, Vector2>,
+    fonts: HashSet<u32>,
+    music: HashSet<u32>,
+    files: HashMap<String, Vec<u8>>,
+
+    time: f64,
+    frame_time: f32,
+
+    keys_down: HashSet<i32>,
+    prev_keys_down: HashSet<i32>,
+    mouse_down: HashSet<i32>,
+    prev_mouse_down: HashSet<i32>,
+    mouse_position: Vector2,
+
+    rng_state: u32,
+
+    record_draws: bool,
+    draw_calls: Vec<DrawCall>,
+    panic_on_draw: bool,
+
+    log_level: i32,
+    log_callback: Option<LogCallback>,
+}
+
+impl Backend {
+    fn new() -> Backend {
+        Backend {
+            next_handle: 1, // 0 is the null handle
+            images: HashMap::new(),
+            registered_images: HashMap::new(),
+            textures: HashMap::new(),
+            fonts: HashSet::new(),
+            music: HashSet::new(),
+            files: HashMap::new(),
+            time: 0.0,
+            frame_time: 0.0,
+            keys_down: HashSet::new(),
+            prev_keys_down: HashSet::new(),
+            mouse_down: HashSet::new(),
+            prev_mouse_down: HashSet::new(),
+            mouse_position: Vector2::new(-1.0, -1.0),
+            rng_state: 1,
+            record_draws: false,
+            draw_calls: Vec::new(),
+            panic_on_draw: false,
+            log_level: crate::log::INFO,
+            log_callback: None,
+        }
+    }
+
+    fn gen_handle(&mut self) -> u32 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        handle
+    }
+
+    fn record(&mut self, call: DrawCall) {
+        if self.panic_on_draw {
+            self.panic_on_draw = false;
+            panic!("headless: panic on draw, as requested");
+        }
+        if self.record_draws {
+            self.draw_calls.push(call);
+        }
+    }
+
+    // xorshift32. Not raylib's generator, but deterministic for a given seed, which is
+    // all we care about here.
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+        x
+    }
+}
+
+thread_local! {
+    static BACKEND: RefCell<Backend> = RefCell::new(Backend::new());
+}
+
+fn with<T>(f: impl FnOnce(&mut Backend) -> T) -> T {
+    BACKEND.with(|backend| f(&mut backend.borrow_mut()))
+}
+
+//==================
+// Driving the backend (called by tests / CI runners)
+//==================
+
+// Throw away all the assets, input, recorded draws etc. and start from scratch.
+pub fn reset() {
+    with(|b| *b = Backend::new());
+}
+
+// Provide the pixels for an image path. Any later `load_image(path)` gets these instead of
+// the placeholder.
+pub fn register_image(path: &str, width: usize, height: usize, colors: Vec<Color>) {
+    assert_eq!(colors.len(), width * height, "image size mismatch");
+    with(|b| {
+        b.registered_images.insert(
+            path.to_string(),
+            ImageData {
+                width,
+                height,
+                colors,
+            },
+        )
+    });
+}
+
+pub fn set_time(time: f64) {
+    with(|b| b.time = time);
+}
+
+// End the current frame: the input state becomes the 'previous' state (for the *_pressed
+// queries) and the clock moves forward by `dt`.
+pub fn advance(dt: f32) {
+    with(|b| {
+        b.prev_keys_down = b.keys_down.clone();
+        b.prev_mouse_down = b.mouse_down.clone();
+        b.time += dt as f64;
+        b.frame_time = dt;
+    });
+}
+
+pub fn set_key_down(key: KeyboardKey, down: bool) {
+    with(|b| {
+        if down {
+            b.keys_down.insert(key as i32);
+        } else {
+            b.keys_down.remove(&(key as i32));
+        }
+    });
+}
+
+pub fn set_mouse_button_down(button: i32, down: bool) {
+    with(|b| {
+        if down {
+            b.mouse_down.insert(button);
+        } else {
+            b.mouse_down.remove(&button);
+        }
+    });
+}
+
+pub fn set_mouse_position(position: Vector2) {
+    with(|b| b.mouse_position = position);
+}
+
+pub fn set_record_draws(record: bool) {
+    with(|b| b.record_draws = record);
+}
+
+// Make the next shape draw call panic. For testing what happens when a frame blows up halfway
+pub fn set_panic_on_draw(panic: bool) {
+    with(|b| b.panic_on_draw = panic);
+}
+
+pub fn take_draw_calls() -> Vec<DrawCall> {
+    with(|b| std::mem::take(&mut b.draw_calls))
+}
+
+//==================
+// Platform functions (called by webhacks)
+//==================
+
+pub fn get_time() -> f64 {
+    with(|b| b.time)
+}
+
+pub fn get_frame_time() -> f32 {
+    with(|b| b.frame_time)
+}
+
+pub fn is_key_down(key: i32) -> bool {
+    with(|b| b.keys_down.contains(&key))
+}
+
+pub fn is_key_pressed(key: i32) -> bool {
+    with(|b| b.keys_down.contains(&key) && !b.prev_keys_down.contains(&key))
+}
+
+pub fn is_mouse_button_down(button: i32) -> bool {
+    with(|b| b.mouse_down.contains(&button))
+}
+
+pub fn is_mouse_button_pressed(button: i32) -> bool {
+    with(|b| b.mouse_down.contains(&button) && !b.prev_mouse_down.contains(&button))
+}
+
+pub fn get_mouse_position() -> Vector2 {
+    with(|b| b.mouse_position)
+}
+
+pub fn set_random_seed(seed: u32) {
+    // xorshift gets stuck on zero
+    with(|b| b.rng_state = if seed == 0 { 1 } else { seed });
+}
+
+// inclusive
+pub fn get_random_value(min: i32, max: i32) -> i32 {
+    if max <= min {
+        return min;
+    }
+    let range = (max as i64 - min as i64 + 1) as u64;
+    let value = with(|b| b.next_random()) as u64 % range;
+    (min as i64 + value as i64) as i32
+}
+
+pub fn load_image(path: &str) -> u32 {
+    with(|b| {
+        let data = b
+            .registered_images
+            .get(path)
+            .cloned()
+            .unwrap_or_else(ImageData::placeholder);
+        let handle = b.gen_handle();
+        b.images.insert(handle, data);
+        handle
+    })
+}
+
+pub fn unload_image(image: u32) {
+    with(|b| b.images.remove(&image));
+}
+
+pub fn is_image_loaded(image: u32) -> bool {
+    with(|b| b.images.contains_key(&image))
+}
+
+pub fn get_image_shape(image: u32) -> Vector2 {
+    with(|b| match b.images.get(&image) {
+        Some(data) => Vector2::new(data.width as f32, data.height as f32),
+        None => Vector2::zero(),
+    })
+}
+
+// Hands out an owned copy of the pixels. Must be given back to `unload_image_colors`.
+pub fn load_image_colors(image: u32) -> *mut Color {
+    let colors = with(|b| match b.images.get(&image) {
+        Some(data) => data.colors.clone(),
+        None => vec![],
+    });
+    Box::into_raw(colors.into_boxed_slice()) as *mut Color
+}
+
+// `n` is in bytes, same as the web version
+pub fn unload_image_colors(colors: *mut Color, n: usize) {
+    let len = n / std::mem::size_of::<Color>();
+    unsafe {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            colors, len,
+        )));
+    }
+}
+
+pub fn load_texture(path: &str) -> u32 {
+    let image = load_image(path);
+    let texture = load_texture_from_image(image);
+    unload_image(image);
+    texture
+}
+
+pub fn load_texture_from_image(image: u32) -> u32 {
+    let shape = get_image_shape(image);
+    with(|b| {
+        let handle = b.gen_handle();
+        b.textures.insert(handle, shape);
+        handle
+    })
+}
+
+pub fn is_texture_loaded(texture: u32) -> bool {
+    with(|b| b.textures.contains_key(&texture))
+}
+
+pub fn get_texture_shape(texture: u32) -> Vector2 {
+    with(|b| b.textures.get(&texture).copied().unwrap_or_default())
+}
+
+pub fn load_font(_path: &str) -> u32 {
+    with(|b| {
+        let handle = b.gen_handle();
+        b.fonts.insert(handle);
+        handle
+    })
+}
+
+pub fn is_font_loaded(font: u32) -> bool {
+    with(|b| b.fonts.contains(&font))
+}
+
+pub fn load_music_stream(_path: &str) -> u32 {
+    with(|b| {
+        let handle = b.gen_handle();
+        b.music.insert(handle);
+        handle
+    })
+}
+
+pub fn is_music_loaded(music: u32) -> bool {
+    with(|b| b.music.contains(&music))
+}
+
+// Rough monospace estimate. Good enough for anchoring text somewhere sensible.
+pub fn measure_text(text: &str, font_size: i32, spacing: f32) -> Vector2 {
+    let lines = text.split('\n').collect::<Vec<_>>();
+    let longest = lines
+        .iter()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0);
+    let char_width = font_size as f32 / 2.0;
+    let width = longest as f32 * char_width + (longest.saturating_sub(1)) as f32 * spacing;
+    Vector2::new(width, (lines.len() as i32 * font_size) as f32)
+}
+
+// Files never touch the disk. They live as long as the backend does.
+pub fn save_file_data(path: &str, data: &[u8]) {
+    with(|b| b.files.insert(path.to_string(), data.to_vec()));
+}
+
+pub fn load_file_data(path: &str) -> Option<Vec<u8>> {
+    with(|b| b.files.get(path).cloned())
+}
+
+pub fn clear_background(color: Color) {
+    with(|b| b.record(DrawCall::ClearBackground { color }));
+}
+
+pub fn draw_line_ex(start: Vector2, end: Vector2, thickness: f32, color: Color) {
+    with(|b| {
+        b.record(DrawCall::Line {
+            start,
+            end,
+            thickness,
+            color,
+        })
+    });
+}
+
+pub fn draw_circle(position: Vector2, radius: f32, color: Color) {
+    with(|b| {
+        b.record(DrawCall::Circle {
+            position,
+            radius,
+            color,
+        })
+    });
+}
+
+pub fn draw_rectangle(x: i32, y: i32, width: i32, height: i32, color: Color) {
+    with(|b| {
+        b.record(DrawCall::Rectangle {
+            x,
+            y,
+            width,
+            height,
+            color,
+        })
+    });
+}
+
+pub fn draw_text(text: &str, position: Vector2, size: i32, color: Color) {
+    with(|b| {
+        b.record(DrawCall::Text {
+            text: text.to_string(),
+            position,
+            size,
+            color,
+        })
+    });
+}
+
+pub fn draw_texture(texture: u32, dest: Rectangle, rotation: f32) {
+    with(|b| {
+        b.record(DrawCall::Texture {
+            texture,
+            dest,
+            rotation,
+        })
+    });
+}
+
+pub fn set_log_level(level: i32) {
+    with(|b| b.log_level = level);
+}
+
+pub fn set_log_callback(callback: Option<LogCallback>) {
+    with(|b| b.log_callback = callback);
+}
+
+pub fn log(level: i32, msg: &str) {
+    let (log_level, callback) = with(|b| (b.log_level, b.log_callback));
+    if level < log_level {
+        return;
+    }
+    match callback {
+        Some(callback) => callback(level, msg),
+        None => println!("{}", msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_pressed_only_on_first_frame() {
+        reset();
+        set_key_down(KeyboardKey::W, true);
+        assert!(is_key_pressed(KeyboardKey::W as i32));
+        advance(0.1);
+        assert!(!is_key_pressed(KeyboardKey::W as i32));
+        assert!(is_key_down(KeyboardKey::W as i32));
+        assert_eq!(get_frame_time(), 0.1);
+    }
+
+    #[test]
+    fn test_random_is_deterministic() {
+        reset();
+        set_random_seed(42);
+        let a = (0..10)
+            .map(|_| get_random_value(0, 100))
+            .collect::<Vec<_>>();
+        set_random_seed(42);
+        let b = (0..10)
+            .map(|_| get_random_value(0, 100))
+            .collect::<Vec<_>>();
+        assert_eq!(a, b);
+        assert!(a.iter().all(|&v| (0..=100).contains(&v)));
+    }
+
+    #[test]
+    fn test_image_colors_round_trip() {
+        reset();
+        let image = load_image("nothing/registered.png");
+        let shape = get_image_shape(image);
+        let n = (shape.x * shape.y) as usize;
+        let colors = load_image_colors(image);
+        assert_eq!(unsafe { *colors.add(n - 1) }.a, 255);
+        unload_image_colors(colors, n * std::mem::size_of::<Color>());
+    }
+
+    #[test]
+    fn test_panic_on_draw_fires_on_text() {
+        reset();
+        set_panic_on_draw(true);
+        let result = std::panic::catch_unwind(|| {
+            draw_text("hi", Vector2::zero(), 20, raylib_wasm::RAYWHITE)
+        });
+        assert!(result.is_err());
+        // only once
+        draw_text("hi", Vector2::zero(), 20, raylib_wasm::RAYWHITE);
+    }
+}
@@ -0,0 +1,262 @@
+// Per-frame input snapshot. The game only reads input through this, so a whole session can
+// be recorded to a file and replayed later with the exact same inputs (see `src/main.rs`).
+
+use raylib_wasm::{KeyboardKey as KEY, MouseButton};
+
+use crate::u32_bool::Bool;
+use crate::vec2::Vector2;
+use crate::webhacks;
+
+// Keys the game cares about. The index in this list is the bit in the key masks, so only
+// ever append to it, otherwise old recordings will replay with the wrong keys.
+pub const TRACKED_KEYS: [KEY; 21] = [
+    KEY::W,
+    KEY::A,
+    KEY::S,
+    KEY::D,
+    KEY::Space,
+    KEY::M,
+    KEY::P,
+    KEY::E,
+    KEY::K,
+    KEY::L,
+    KEY::One,
+    KEY::Two,
+    KEY::Three,
+    KEY::Four,
+    KEY::T,
+    KEY::Five,
+    KEY::Six,
+    KEY::U,
+    KEY::G,
+    KEY::N,
+    KEY::X,
+];
+
+#[repr(C, align(4))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameInput {
+    pub keys_down: u32,
+    pub keys_pressed: u32,
+    pub mouse_pos: Vector2,
+    pub mouse_btn: Bool,
+    pub mouse_btn_pressed: Bool,
+    pub dt: f32,
+}
+
+fn key_bit(key: KEY) -> u32 {
+    match TRACKED_KEYS.iter().position(|&k| k as i32 == key as i32) {
+        Some(i) => 1 << i,
+        None => panic!("key {} is not in TRACKED_KEYS", key as i32),
+    }
+}
+
+impl FrameInput {
+    pub fn empty(dt: f32) -> FrameInput {
+        FrameInput {
+            keys_down: 0,
+            keys_pressed: 0,
+            mouse_pos: Vector2::new(-1.0, -1.0),
+            mouse_btn: false.into(),
+            mouse_btn_pressed: false.into(),
+            dt,
+        }
+    }
+
+    // Read the live devices
+    pub fn poll(dt: f32) -> FrameInput {
+        let mut input = FrameInput::empty(dt);
+        for (i, &key) in TRACKED_KEYS.iter().enumerate() {
+            if webhacks::is_key_down(key) {
+                input.keys_down |= 1 << i;
+            }
+            if webhacks::is_key_pressed(key) {
+                input.keys_pressed |= 1 << i;
+            }
+        }
+        input.mouse_pos = webhacks::get_mouse_position();
+        input.mouse_btn = webhacks::is_mouse_button_down(MouseButton::Left as i32).into();
+        input.mouse_btn_pressed =
+            webhacks::is_mouse_button_pressed(MouseButton::Left as i32).into();
+        input
+    }
+
+    pub fn is_down(&self, key: KEY) -> bool {
+        self.keys_down & key_bit(key) != 0
+    }
+
+    pub fn is_pressed(&self, key: KEY) -> bool {
+        self.keys_pressed & key_bit(key) != 0
+    }
+}
+
+//==================
+// Recording file format. Little endian throughout.
+//
+//   header: magic "GREC" | version u8 | rng seed u32
+//   frame:  keys_down u32 | keys_pressed u32 | mouse x f32 | mouse y f32 | buttons u8 | dt f32
+//
+// `buttons` has bit 0 set for left-button-down and bit 1 for left-button-pressed.
+//==================
+
+pub const RECORDING_MAGIC: &[u8; 4] = b"GREC";
+pub const RECORDING_VERSION: u8 = 1;
+
+pub const HEADER_SIZE: usize = 9;
+pub const FRAME_SIZE: usize = 21;
+
+#[derive(Debug, PartialEq)]
+pub enum RecordingError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated { expected: usize, found: usize },
+}
+
+impl std::fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RecordingError::BadMagic => write!(f, "not a recording file (bad magic)"),
+            RecordingError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported recording version {} (expected {})",
+                v, RECORDING_VERSION
+            ),
+            RecordingError::Truncated { expected, found } => write!(
+                f,
+                "recording is truncated (expected {} bytes, found {})",
+                expected, found
+            ),
+        }
+    }
+}
+
+pub struct Recording {
+    pub seed: u32,
+    pub frames: Vec<FrameInput>,
+}
+
+pub fn encode_header(seed: u32) -> [u8; HEADER_SIZE] {
+    let mut out = [0; HEADER_SIZE];
+    out[0..4].copy_from_slice(RECORDING_MAGIC);
+    out[4] = RECORDING_VERSION;
+    out[5..9].copy_from_slice(&seed.to_le_bytes());
+    out
+}
+
+pub fn encode_frame(input: &FrameInput) -> [u8; FRAME_SIZE] {
+    let mut buttons = 0u8;
+    if input.mouse_btn.into() {
+        buttons |= 0b01;
+    }
+    if input.mouse_btn_pressed.into() {
+        buttons |= 0b10;
+    }
+
+    let mut out = [0; FRAME_SIZE];
+    out[0..4].copy_from_slice(&input.keys_down.to_le_bytes());
+    out[4..8].copy_from_slice(&input.keys_pressed.to_le_bytes());
+    out[8..12].copy_from_slice(&input.mouse_pos.x.to_le_bytes());
+    out[12..16].copy_from_slice(&input.mouse_pos.y.to_le_bytes());
+    out[16] = buttons;
+    out[17..21].copy_from_slice(&input.dt.to_le_bytes());
+    out
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_f32(bytes: &[u8]) -> f32 {
+    f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn decode_frame(bytes: &[u8]) -> FrameInput {
+    let buttons = bytes[16];
+    FrameInput {
+        keys_down: read_u32(&bytes[0..4]),
+        keys_pressed: read_u32(&bytes[4..8]),
+        mouse_pos: Vector2::new(read_f32(&bytes[8..12]), read_f32(&bytes[12..16])),
+        mouse_btn: (buttons & 0b01 != 0).into(),
+        mouse_btn_pressed: (buttons & 0b10 != 0).into(),
+        dt: read_f32(&bytes[17..21]),
+    }
+}
+
+pub fn decode_recording(bytes: &[u8]) -> Result<Recording, RecordingError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(RecordingError::Truncated {
+            expected: HEADER_SIZE,
+            found: bytes.len(),
+        });
+    }
+    if &bytes[0..4] != RECORDING_MAGIC {
+        return Err(RecordingError::BadMagic);
+    }
+    if bytes[4] != RECORDING_VERSION {
+        return Err(RecordingError::UnsupportedVersion(bytes[4]));
+    }
+    let seed = read_u32(&bytes[5..9]);
+
+    let body = &bytes[HEADER_SIZE..];
+    if !body.len().is_multiple_of(FRAME_SIZE) {
+        let frames = body.len() / FRAME_SIZE + 1;
+        return Err(RecordingError::Truncated {
+            expected: HEADER_SIZE + frames * FRAME_SIZE,
+            found: bytes.len(),
+        });
+    }
+
+    let frames = body.chunks_exact(FRAME_SIZE).map(decode_frame).collect();
+
+    Ok(Recording { seed, frames })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn some_input() -> FrameInput {
+        let mut input = FrameInput::empty(1.0 / 60.0);
+        input.keys_down = key_bit(KEY::W) | key_bit(KEY::Space);
+        input.keys_pressed = key_bit(KEY::Space);
+        input.mouse_pos = Vector2::new(123.5, 456.25);
+        input.mouse_btn = true.into();
+        input
+    }
+
+    #[test]
+    fn test_recording_round_trip() {
+        let mut bytes = encode_header(1234).to_vec();
+        bytes.extend_from_slice(&encode_frame(&some_input()));
+        bytes.extend_from_slice(&encode_frame(&FrameInput::empty(0.5)));
+
+        let recording = decode_recording(&bytes).unwrap();
+        assert_eq!(recording.seed, 1234);
+        assert_eq!(recording.frames, vec![some_input(), FrameInput::empty(0.5)]);
+        assert!(recording.frames[0].is_down(KEY::W));
+        assert!(!recording.frames[0].is_pressed(KEY::W));
+        assert!(recording.frames[0].is_pressed(KEY::Space));
+    }
+
+    #[test]
+    fn test_recording_rejects_bad_input() {
+        let mut bytes = encode_header(1).to_vec();
+        bytes.extend_from_slice(&encode_frame(&some_input())[..10]);
+        assert!(matches!(
+            decode_recording(&bytes),
+            Err(RecordingError::Truncated { .. })
+        ));
+
+        let mut bytes = encode_header(1).to_vec();
+        bytes[4] = RECORDING_VERSION + 1;
+        assert_eq!(
+            decode_recording(&bytes).err(),
+            Some(RecordingError::UnsupportedVersion(RECORDING_VERSION + 1))
+        );
+
+        assert_eq!(
+            decode_recording(b"nope, not a recording").err(),
+            Some(RecordingError::BadMagic)
+        );
+    }
+}
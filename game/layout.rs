@@ -0,0 +1,201 @@
+// Fingerprint of the in-memory layout of `State` and everything it owns.
+//
+// The native runner hot-reloads the game library but keeps the `State` it already has. That
+// is only fine if the new build lays `State` out the same way as the old one. The runner
+// compares `game_state_layout` before and after a reload, and if they differ it asks the new
+// library to rebuild the state with `game_migrate` (see `game.rs`).
+//
+// The hash covers the size, alignment, field names and field offsets of every type reachable
+// from `State`, the element types of its `Vec`s included, and the variants of its enums.
+// Each field list is checked against its struct (and each variant list against its enum) at
+// compile time, so a new field doesn't build until it's listed here. A new type that `State`
+// comes to own has to be added by hand. Changing the *meaning* of a field without moving
+// anything doesn't change the hash, so bump LAYOUT_VERSION by hand when doing that.
+
+use std::mem::{align_of, offset_of, size_of};
+
+use crate::anim::{Anim, AnimMeta, Blob};
+use crate::bullet::{Bullet, ProjectileKind};
+use crate::editor::{Drag, EditorTool};
+use crate::enemy::{Enemy, EnemyKind};
+use crate::entity_manager::{EntityManager, Kind, Slot};
+use crate::events::{EventQueue, GameEvent, Stats};
+use crate::level::{Level, LevelPath, PresetTurret};
+use crate::path::{Branch, Path, PathPosition, Paths, Sample};
+use crate::spatial::Grid;
+use crate::status::{Status, StatusKind, Statuses};
+use crate::turret::{Targeting, Turret, TurretKind};
+use crate::u32_bool::Bool;
+use crate::vec2::Vector2;
+use crate::waves::{Group, Wave, Waves};
+use crate::webhacks::{Font, Image, Music, Texture};
+use crate::State;
+
+pub const LAYOUT_VERSION: u32 = 1;
+
+// Passed between different builds of the game, so this struct itself must never change.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateLayout {
+    pub version: u32,
+    pub state_size: u32,
+    pub state_align: u32,
+    pub hash: u64,
+}
+
+impl std::fmt::Display for StateLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "v{} size {} align {} hash {:016x}",
+            self.version, self.state_size, self.state_align, self.hash
+        )
+    }
+}
+
+// FNV-1a
+struct Hasher(u64);
+
+impl Hasher {
+    fn add(&mut self, value: usize) {
+        for byte in (value as u64).to_le_bytes() {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn add_str(&mut self, value: &str) {
+        self.add(value.len());
+        for byte in value.bytes() {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn add_type<T>(&mut self) {
+        self.add(size_of::<T>());
+        self.add(align_of::<T>());
+    }
+}
+
+// The list has to name every field: the destructuring below has no `..`.
+macro_rules! add_offsets {
+    ($hasher:expr, $type:ident; $($field:ident),* $(,)?) => {
+        let _ = |value: &$type| {
+            let $type { $($field: _),* } = value;
+        };
+        $hasher.add_type::<$type>();
+        $(
+            $hasher.add_str(stringify!($field));
+            $hasher.add(offset_of!($type, $field));
+        )*
+    };
+}
+
+// The list has to cover every variant, each with all of its fields: it's an exhaustive match.
+macro_rules! add_variants {
+    ($hasher:expr, $type:ident; $($variant:pat),* $(,)?) => {
+        let _ = |value: &$type| match value {
+            $($variant => (),)*
+        };
+        $hasher.add_type::<$type>();
+        $( $hasher.add_str(stringify!($variant)); )*
+    };
+}
+
+pub fn state_layout() -> StateLayout {
+    let mut h = Hasher(0xcbf29ce484222325);
+
+    add_offsets!(h, State;
+        all_loaded, curr_time, prev_time, sim_time, sim_accumulator, frame_count, slime_pos,
+        mouse_pos, mouse_btn, mouse_btn_pressed, upgrade_pressed, sell_pressed, music, font,
+        slime_anim, bullet_anim, turret_anim, bkg, bkg_texture, paths, mute, debug, life, man,
+        editor, events, stats, waves, build_kind, selected, gold, snap, editor_tool,
+        editor_drag, level, level_loaded,
+    );
+    add_offsets!(h, Vector2; x, y);
+    add_offsets!(h, Bool; value);
+    // platform handles, plain ids on the web
+    h.add_type::<Music>();
+    h.add_type::<Font>();
+    h.add_type::<Image>();
+    h.add_type::<Texture>();
+    add_offsets!(h, Anim; image, texture, blobs, meta);
+    add_offsets!(h, Blob; x_min, y_min, x_max, y_max);
+    add_offsets!(h, AnimMeta; num_frames, max_width, max_height, avg_width, avg_height, pad_blob);
+
+    add_offsets!(h, Paths; paths, to_exit);
+    add_offsets!(h, Path; id, nodes, curved, total_length, branches, samples);
+    add_offsets!(h, Branch; path, at, weight);
+    add_offsets!(h, Sample; xy, linear, segment);
+
+    add_offsets!(h, EntityManager; turrets, enemies, bullets, slots, free, enemy_grid);
+    add_offsets!(h, Slot; generation, entry);
+    add_variants!(h, Kind; Kind::Turret, Kind::Enemy, Kind::Bullet);
+    add_offsets!(h, Grid; cell_size, origin, cols, rows, cell_start, items);
+    add_offsets!(h, Turret;
+        kind, targeting, target, tier, spent, preset, position, dead, hover, fire_cooldown, id,
+        facing, radius, anim,
+    );
+    add_variants!(h, TurretKind;
+        TurretKind::Gun, TurretKind::Rapid, TurretKind::Sniper, TurretKind::Cannon,
+        TurretKind::Frost, TurretKind::Venom,
+    );
+    add_variants!(h, Targeting;
+        Targeting::First, Targeting::Last, Targeting::Strongest, Targeting::Weakest,
+        Targeting::Closest,
+    );
+    add_offsets!(h, Enemy;
+        kind, position, prev_xy, health, max_health, statuses, spawn_time, dead, id, radius, anim,
+    );
+    add_variants!(h, EnemyKind;
+        EnemyKind::Slime, EnemyKind::Runner, EnemyKind::Brute, EnemyKind::Swarmling,
+    );
+    add_offsets!(h, PathPosition; xy, linear, path, tangent);
+    add_offsets!(h, Statuses; slots);
+    add_offsets!(h, Status; kind, remaining, strength, owed, source);
+    add_variants!(h, StatusKind;
+        StatusKind::Slow, StatusKind::Poison, StatusKind::Burn, StatusKind::Stun,
+    );
+    add_offsets!(h, Bullet;
+        kind, position, prev_position, velocity, aim, source, target, hit, dead, id, damage, anim,
+        radius,
+    );
+    add_variants!(h, ProjectileKind;
+        ProjectileKind::Bullet, ProjectileKind::Slug, ProjectileKind::Shell,
+        ProjectileKind::FrostBolt, ProjectileKind::Dart,
+    );
+
+    add_offsets!(h, EventQueue; events);
+    add_variants!(h, GameEvent;
+        GameEvent::EnemySpawned { id: _ },
+        GameEvent::EnemyKilled { id: _, by: _, bounty: _ },
+        GameEvent::BulletHit { bullet: _, target: _, damage: _ },
+        GameEvent::LifeLost { amount: _ },
+        GameEvent::TurretPlaced { id: _, position: _, cost: _ },
+        GameEvent::TurretSold { id: _, position: _, refund: _ },
+        GameEvent::TurretUpgraded { id: _, tier: _, cost: _ },
+        GameEvent::WaveStarted { wave: _ },
+        GameEvent::WaveCleared { wave: _ },
+    );
+    add_offsets!(h, Stats;
+        enemies_spawned, enemies_killed, bullets_hit, lives_lost, turrets_placed, turrets_sold,
+        turrets_upgraded, waves_cleared,
+    );
+    add_offsets!(h, Waves; waves, current, wave_time, spawned);
+    add_offsets!(h, Wave; pause, groups);
+    add_offsets!(h, Group; enemy, count, spacing, delay, spawn, path);
+
+    add_variants!(h, EditorTool; EditorTool::Path, EditorTool::Turrets);
+    add_variants!(h, Drag; Drag::None, Drag::Node(_, _), Drag::Turret(_));
+    add_offsets!(h, Level; life, gold, background, paths, turrets, waves);
+    add_offsets!(h, LevelPath; nodes, curved, branches);
+    add_offsets!(h, PresetTurret; kind, position);
+
+    StateLayout {
+        version: LAYOUT_VERSION,
+        state_size: size_of::<State>() as u32,
+        state_align: align_of::<State>() as u32,
+        hash: h.0,
+    }
+}
@@ -0,0 +1,497 @@
+// The level file: everything a level starts out with, in a text format meant to be edited by
+// hand as well as by the editor.
+//
+// One entry per line, `#` starts a comment:
+//
+//   life <n>                                          lives at the start, 20 if not given
+//   gold <n>                                          gold at the start, 100 if not given
+//   background <image path>                           drawn under everything, optional
+//   path                                              starts a new path, see `path.rs`
+//   curved                                            makes the last path a smooth curve
+//   node <x> <y>                                      the next node of the last path
+//   branch <path> <at> <weight>                       where the last path carries on
+//   turret <type> <x> <y>                             a turret that is there from the start
+//   wave <pause>                                      starts a new wave, see `waves.rs`
+//   group <enemy> <count> <spacing> <delay> <spawn> [path]
+//                                                     a group of the last wave, on path 0 if
+//                                                     not given
+//
+// Paths are numbered from 0 in the order they appear. Nodes and branches before the first
+// `path` start path 0 on their own, so a level with one path doesn't need the keyword.
+// Turret and enemy types go by their archetype names. Numbers have to be finite, and life,
+// gold, group counts and branch weights no more than the limits below. Errors point at the
+// line they are on.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::enemy::EnemyKind;
+use crate::path::Branch;
+use crate::turret::TurretKind;
+use crate::vec2::Vector2;
+use crate::waves::{Group, Wave};
+
+pub const LEVEL_PATH: &str = "assets/level.txt";
+
+// What the game falls back to when the level file is missing or broken
+pub const BUILTIN_LEVEL: &str = include_str!("../assets/level.txt");
+
+pub const DEFAULT_LIFE: u32 = 20;
+pub const DEFAULT_GOLD: u32 = 100;
+
+// Far more than any level needs, and far enough from u32::MAX that what the game adds up from
+// them (gold with bounties, the weights of all the branches of a path) can't overflow
+const MAX_LIFE: u32 = 1_000_000;
+const MAX_GOLD: u32 = 1_000_000;
+const MAX_COUNT: u32 = 10_000;
+const MAX_WEIGHT: u32 = 1_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PresetTurret {
+    pub kind: TurretKind,
+    pub position: Vector2,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelPath {
+    pub nodes: Vec<Vector2>,
+    pub curved: bool,
+    pub branches: Vec<Branch>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Level {
+    pub life: u32,
+    pub gold: u32,
+    pub background: Option<String>,
+    pub paths: Vec<LevelPath>,
+    pub turrets: Vec<PresetTurret>,
+    pub waves: Vec<Wave>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LevelError {
+    // 1-based
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for LevelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// The values after a keyword, which has to have exactly N of them
+fn values<'a, const N: usize>(keyword: &str, values: &[&'a str]) -> Result<[&'a str; N], String> {
+    values
+        .try_into()
+        .map_err(|_| format!("'{}' takes {} value(s), got {}", keyword, N, values.len()))
+}
+
+fn number<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse()
+        .map_err(|_| format!("'{}' is not a valid number", word))
+}
+
+// `nan` and `inf` parse as f32, but make no sense anywhere in a level
+fn finite(word: &str) -> Result<f32, String> {
+    let value: f32 = number(word)?;
+    if !value.is_finite() {
+        return Err(format!("'{}' is not a finite number", word));
+    }
+    Ok(value)
+}
+
+fn at_most(word: &str, max: u32) -> Result<u32, String> {
+    let value = number(word)?;
+    if value > max {
+        return Err(format!("'{}' is more than the limit of {}", word, max));
+    }
+    Ok(value)
+}
+
+fn turret_kind(name: &str) -> Result<TurretKind, String> {
+    TurretKind::ALL
+        .into_iter()
+        .find(|k| k.archetype().name == name)
+        .ok_or_else(|| format!("unknown turret type '{}'", name))
+}
+
+fn enemy_kind(name: &str) -> Result<EnemyKind, String> {
+    EnemyKind::ALL
+        .into_iter()
+        .find(|k| k.archetype().name == name)
+        .ok_or_else(|| format!("unknown enemy type '{}'", name))
+}
+
+impl Level {
+    pub fn parse(text: &str) -> Result<Level, LevelError> {
+        let mut level = Level {
+            life: DEFAULT_LIFE,
+            gold: DEFAULT_GOLD,
+            background: None,
+            paths: Vec::new(),
+            turrets: Vec::new(),
+            waves: Vec::new(),
+        };
+
+        // what can only be checked once the whole file is read, with the lines to blame:
+        // where each path starts, and the paths the branches and groups refer to
+        let mut path_lines = Vec::new();
+        let mut path_refs = Vec::new();
+        let mut n_lines = 0;
+        for (i, line) in text.lines().enumerate() {
+            n_lines = i + 1;
+            let mut refs = Vec::new();
+            let n_paths = level.paths.len();
+            level
+                .parse_line(line, &mut refs)
+                .map_err(|message| LevelError {
+                    line: i + 1,
+                    message,
+                })?;
+            if level.paths.len() > n_paths {
+                path_lines.push(i + 1);
+            }
+            path_refs.extend(refs.into_iter().map(|path| (i + 1, path)));
+        }
+
+        if level.paths.is_empty() {
+            return Err(LevelError {
+                line: n_lines.max(1),
+                message: "the level needs at least 1 path".to_string(),
+            });
+        }
+        for (i, path) in level.paths.iter().enumerate() {
+            if path.nodes.len() < 2 {
+                return Err(LevelError {
+                    line: path_lines[i],
+                    message: format!(
+                        "path {} needs at least 2 nodes, found {}",
+                        i,
+                        path.nodes.len()
+                    ),
+                });
+            }
+        }
+        if let Some(&(line, path)) = path_refs.iter().find(|r| r.1 >= level.paths.len()) {
+            return Err(LevelError {
+                line,
+                message: format!(
+                    "there is no path {}, the level has {}",
+                    path,
+                    level.paths.len()
+                ),
+            });
+        }
+        Ok(level)
+    }
+
+    // The path nodes and branches go on, starting path 0 if there is none yet
+    fn last_path(&mut self) -> &mut LevelPath {
+        if self.paths.is_empty() {
+            self.new_path();
+        }
+        self.paths.last_mut().unwrap()
+    }
+
+    fn new_path(&mut self) {
+        self.paths.push(LevelPath {
+            nodes: Vec::new(),
+            curved: false,
+            branches: Vec::new(),
+        });
+    }
+
+    // `path_refs` gets the indices of the paths the line refers to
+    fn parse_line(&mut self, line: &str, path_refs: &mut Vec<usize>) -> Result<(), String> {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let Some(keyword) = words.next() else {
+            return Ok(());
+        };
+        let args = words.collect::<Vec<_>>();
+
+        match keyword {
+            "life" => {
+                let [life] = values(keyword, &args)?;
+                self.life = at_most(life, MAX_LIFE)?;
+            }
+            "gold" => {
+                let [gold] = values(keyword, &args)?;
+                self.gold = at_most(gold, MAX_GOLD)?;
+            }
+            "background" => {
+                let [image] = values(keyword, &args)?;
+                self.background = Some(image.to_string());
+            }
+            "path" => {
+                let [] = values(keyword, &args)?;
+                self.new_path();
+            }
+            "curved" => {
+                let [] = values(keyword, &args)?;
+                self.last_path().curved = true;
+            }
+            "node" => {
+                let [x, y] = values(keyword, &args)?;
+                let node = Vector2::new(finite(x)?, finite(y)?);
+                self.last_path().nodes.push(node);
+            }
+            "branch" => {
+                let [path, at, weight] = values(keyword, &args)?;
+                let branch = Branch {
+                    path: number(path)?,
+                    at: finite(at)?,
+                    weight: at_most(weight, MAX_WEIGHT)?,
+                };
+                path_refs.push(branch.path);
+                self.last_path().branches.push(branch);
+            }
+            "turret" => {
+                let [kind, x, y] = values(keyword, &args)?;
+                self.turrets.push(PresetTurret {
+                    kind: turret_kind(kind)?,
+                    position: Vector2::new(finite(x)?, finite(y)?),
+                });
+            }
+            "wave" => {
+                let [pause] = values(keyword, &args)?;
+                self.waves.push(Wave {
+                    pause: finite(pause)?,
+                    groups: Vec::new(),
+                });
+            }
+            "group" => {
+                // the path is optional
+                let (args, path) = match args.len() {
+                    6 => (&args[..5], number(args[5])?),
+                    _ => (&args[..], 0),
+                };
+                let [enemy, count, spacing, delay, spawn] = values(keyword, args)?;
+                let group = Group {
+                    enemy: enemy_kind(enemy)?,
+                    count: at_most(count, MAX_COUNT)?,
+                    spacing: finite(spacing)?,
+                    delay: finite(delay)?,
+                    spawn: finite(spawn)?,
+                    path,
+                };
+                path_refs.push(path);
+                self.waves
+                    .last_mut()
+                    .ok_or("'group' has to come after a 'wave'")?
+                    .groups
+                    .push(group);
+            }
+            _ => return Err(format!("unknown keyword '{}'", keyword)),
+        }
+        Ok(())
+    }
+
+    pub fn write(&self) -> String {
+        let mut out = String::new();
+        // writing into a String can't fail
+        let _ = self.write_into(&mut out);
+        out
+    }
+
+    fn write_into(&self, out: &mut String) -> std::fmt::Result {
+        writeln!(out, "# Level file, see game/level.rs for the format")?;
+        writeln!(out)?;
+        writeln!(out, "life {}", self.life)?;
+        writeln!(out, "gold {}", self.gold)?;
+        if let Some(ref background) = self.background {
+            writeln!(out, "background {}", background)?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "# path")?;
+        writeln!(out, "# curved")?;
+        writeln!(out, "# node <x> <y>")?;
+        writeln!(out, "# branch <path> <at> <weight>")?;
+        for path in self.paths.iter() {
+            writeln!(out, "path")?;
+            if path.curved {
+                writeln!(out, "curved")?;
+            }
+            for node in path.nodes.iter() {
+                writeln!(out, "node {} {}", node.x, node.y)?;
+            }
+            for branch in path.branches.iter() {
+                writeln!(
+                    out,
+                    "branch {} {} {}",
+                    branch.path, branch.at, branch.weight
+                )?;
+            }
+        }
+
+        writeln!(out)?;
+        writeln!(out, "# turret <type> <x> <y>")?;
+        for turret in self.turrets.iter() {
+            writeln!(
+                out,
+                "turret {} {} {}",
+                turret.kind.archetype().name,
+                turret.position.x,
+                turret.position.y
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "# wave <pause>")?;
+        writeln!(
+            out,
+            "# group <enemy> <count> <spacing> <delay> <spawn> <path>"
+        )?;
+        for wave in self.waves.iter() {
+            writeln!(out, "wave {}", wave.pause)?;
+            for group in wave.groups.iter() {
+                writeln!(
+                    out,
+                    "group {} {} {} {} {} {}",
+                    group.enemy.archetype().name,
+                    group.count,
+                    group.spacing,
+                    group.delay,
+                    group.spawn,
+                    group.path
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> LevelError {
+        Level::parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn test_builtin_level_round_trip() {
+        let level = Level::parse(BUILTIN_LEVEL).unwrap();
+        assert!(level.paths.iter().all(|p| p.nodes.len() >= 2));
+        assert!(!level.waves.is_empty());
+        // the file is exactly what the editor would write
+        assert_eq!(level.write(), BUILTIN_LEVEL);
+        assert_eq!(Level::parse(&level.write()), Ok(level));
+    }
+
+    #[test]
+    fn test_parse_level() {
+        let text = "\
+            life 5 # not many\n\
+            background bkg.png\n\
+            node 0 10\n\
+            node 100.5 10\n\
+            \n\
+            turret sniper 50 60\n\
+            wave 2\n\
+            group runner 3 0.5 1 0\n\
+            wave 4\n";
+        let level = Level::parse(text).unwrap();
+        assert_eq!(level.life, 5);
+        assert_eq!(level.gold, DEFAULT_GOLD);
+        assert_eq!(level.background.as_deref(), Some("bkg.png"));
+        assert_eq!(level.paths.len(), 1);
+        assert_eq!(level.paths[0].nodes[1], Vector2::new(100.5, 10.0));
+        assert_eq!(level.turrets[0].kind, TurretKind::Sniper);
+        assert_eq!(level.waves.len(), 2);
+        assert_eq!(level.waves[0].groups[0].enemy, EnemyKind::Runner);
+        assert_eq!(level.waves[0].groups[0].path, 0);
+        assert!(level.waves[1].groups.is_empty());
+    }
+
+    #[test]
+    fn test_parse_paths() {
+        let text = "\
+            path\n\
+            node 0 0\n\
+            node 100 0\n\
+            branch 1 0 1\n\
+            branch 2 0 3\n\
+            path\n\
+            node 100 0\n\
+            node 300 0\n\
+            path\n\
+            curved\n\
+            node 100 0\n\
+            node 200 0\n\
+            branch 1 100 1\n\
+            wave 1\n\
+            group slime 1 1 0 0 2\n";
+        let level = Level::parse(text).unwrap();
+        assert_eq!(level.paths.len(), 3);
+        assert_eq!(level.paths[1].branches, vec![]);
+        assert_eq!(
+            level.paths.iter().map(|p| p.curved).collect::<Vec<_>>(),
+            [false, false, true]
+        );
+        assert_eq!(
+            level.paths[0].branches[1],
+            Branch {
+                path: 2,
+                at: 0.0,
+                weight: 3
+            }
+        );
+        assert_eq!(level.paths[2].branches[0].at, 100.0);
+        assert_eq!(level.waves[0].groups[0].path, 2);
+        assert_eq!(Level::parse(&level.write()), Ok(level));
+    }
+
+    #[test]
+    fn test_errors_have_line_numbers() {
+        let nodes = "node 0 0\nnode 1 1\n";
+        let e = error(&format!("{}\nlife x\n", nodes));
+        assert_eq!(e.line, 4);
+        assert_eq!(e.to_string(), "line 4: 'x' is not a valid number");
+
+        assert_eq!(error(&format!("{}node 1\n", nodes)).line, 3);
+        assert_eq!(error(&format!("{}turret laser 1 1\n", nodes)).line, 3);
+        assert_eq!(error("group slime 1 1 0 0\n").line, 1);
+        assert_eq!(error("# hi\nspawn 3\n").message, "unknown keyword 'spawn'");
+
+        let e = error("# empty\nnode 3 4\n");
+        assert_eq!(e.line, 2);
+        assert_eq!(e.message, "path 0 needs at least 2 nodes, found 1");
+        let e = error(&format!("{}path\n\npath\n{}", nodes, nodes));
+        assert_eq!(e.line, 3);
+        assert_eq!(e.message, "path 1 needs at least 2 nodes, found 0");
+        assert_eq!(
+            error("# nothing\n").message,
+            "the level needs at least 1 path"
+        );
+
+        let e = error(&format!("{}branch 1 0 1\n", nodes));
+        assert_eq!(e.line, 3);
+        assert_eq!(e.message, "there is no path 1, the level has 1");
+        let e = error(&format!("{}wave 1\ngroup slime 1 1 0 0 4\n", nodes));
+        assert_eq!(e.line, 4);
+        assert_eq!(error("path 1\n").line, 1);
+    }
+
+    #[test]
+    fn test_numbers_are_finite_and_bounded() {
+        let nodes = "node 0 0\nnode 1 1\n";
+        let e = error(&format!("{}node nan 1\n", nodes));
+        assert_eq!(e.to_string(), "line 3: 'nan' is not a finite number");
+        assert_eq!(error(&format!("{}turret gun inf 1\n", nodes)).line, 3);
+        assert_eq!(error(&format!("{}wave -inf\n", nodes)).line, 3);
+        assert_eq!(error(&format!("{}branch 0 NaN 1\n", nodes)).line, 3);
+
+        let e = error(&format!("{}branch 0 0 3000000000\n", nodes));
+        assert_eq!(e.line, 3);
+        assert_eq!(e.message, "'3000000000' is more than the limit of 1000000");
+        let e = error(&format!("{}wave 1\ngroup slime 20000 1 0 0\n", nodes));
+        assert_eq!(e.line, 4);
+        assert_eq!(error("gold 2000000\n").line, 1);
+        assert_eq!(error("life 4000000000\n").line, 1);
+    }
+}
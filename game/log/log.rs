@@ -14,7 +14,7 @@ pub const ERROR: i32 = 5;
 pub const FATAL: i32 = 6;
 pub const NONE: i32 = 999;
 
-#[cfg(feature = "native")]
+#[cfg(any(feature = "native", feature = "headless"))]
 mod color {
     pub const GREEN: &str = "\x1b[92m";
     pub const YELLOW: &str = "\x1b[93m";
@@ -94,11 +94,29 @@ pub fn web_log_callback(log_level: i32, text_ptr: *const i8) {
     }
 }
 
+#[cfg(feature = "headless")]
+pub fn headless_log_callback(log_level: i32, text: &str) {
+    let text = match log_level {
+        INFO => format!("[{}INFO{}] : {}", color::GREEN, color::RESET, text),
+        ERROR => format!("[{}ERROR{}] : {}", color::RED, color::RESET, text),
+        FATAL => format!("[{}FATAL{}] : {}", color::RED, color::RESET, text),
+        WARNING => format!("[{}WARN{}] : {}", color::YELLOW, color::RESET, text),
+        DEBUG => format!("[{}DEBUG{}] : {}", color::CYAN, color::RESET, text),
+        TRACE => format!("[{}TRACE{}] : {}", color::MAGENTA, color::RESET, text),
+        NONE => format!("{}", text),
+        _ => format!("{}", text),
+    };
+
+    println!("{}", text);
+}
+
 pub fn set_trace_log_callback() {
     #[cfg(feature = "native")]
     let callback = Some(native_log_callback as webhacks::LogCallback);
     #[cfg(feature = "web")]
     let callback = Some(web_log_callback as webhacks::LogCallback);
+    #[cfg(feature = "headless")]
+    let callback = Some(headless_log_callback as webhacks::LogCallback);
 
     webhacks::set_trace_log_callback(callback, "web_log_callback");
 }
@@ -1,53 +1,229 @@
+// The paths the enemies walk along.
+//
+// A level has one or more paths, each a line through its nodes. A path can carry on into other
+// paths once its end is reached, through its branches. Each branch names the path to carry on
+// along and the distance along it to carry on from, so
+//
+//   fork   - several branches, all at distance 0 of paths starting where this one ends
+//   merge  - one branch, at the distance where the other path passes this one's end
+//
+// A path is either straight lines between its nodes, or with `curved` set, a Catmull-Rom
+// curve through them. Either way it is looked up through a table of points along it with their
+// distance from the start, so that enemies move along curves at an even speed.
+//
+// At a fork each enemy picks one of the branches, weighted by `Branch::weight`. The pick only
+// depends on the enemy and the fork, so it is the same every time it is asked for, which is
+// what lets turrets lead their shots across forks. Paths without branches end in an exit.
+// Paths that no other path leads into are where enemies spawn.
+
 use crate::entity_manager::EntityId;
 use crate::vec2::Vector2;
 use crate::webhacks;
+use crate::words::{DecodeError, Reader, Writer};
 use crate::State;
 
 use raylib_wasm::RAYWHITE;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Branch {
+    // index of the path to carry on along
+    pub path: usize,
+    // distance along that path to carry on from
+    pub at: f32,
+    pub weight: u32,
+}
+
+// Points per segment between two nodes of a curved path
+pub const CURVE_SAMPLES: usize = 16;
+
+// A point of the lookup table
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Sample {
+    pub(crate) xy: Vector2,
+    // distance from the start of the path
+    pub(crate) linear: f32,
+    // index of the node starting the segment it's on
+    pub(crate) segment: usize,
+}
+
 pub struct Path {
     pub id: EntityId,
     pub nodes: Vec<Vector2>,
+    pub curved: bool,
     pub total_length: f32,
+    pub branches: Vec<Branch>,
+    // from the first node to the last, see the top of the file
+    pub(crate) samples: Vec<Sample>,
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct PathPosition {
     pub xy: Vector2,
+    // distance along the path it's on
     pub linear: f32,
-    // pub path: &Path,
+    // index of the path it's on
+    pub path: usize,
+    // unit vector in the direction of travel
+    pub tangent: Vector2,
+}
+
+// The point `t` of the way from `p1` to `p2` on the Catmull-Rom curve with `p0` before them and
+// `p3` after
+fn catmull_rom(p0: Vector2, p1: Vector2, p2: Vector2, p3: Vector2, t: f32) -> Vector2 {
+    let (t2, t3) = (t * t, t * t * t);
+    (p1 * 2.0
+        + (p2 - p0) * t
+        + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+        + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3)
+        * 0.5
 }
 
 impl Path {
     pub fn new(nodes: Vec<Vector2>) -> Path {
+        let mut path = Path {
+            id: 0,
+            nodes: Vec::new(),
+            curved: false,
+            total_length: 0.0,
+            branches: Vec::new(),
+            samples: Vec::new(),
+        };
+        path.set_nodes(nodes);
+        path
+    }
+
+    pub fn new_curved(nodes: Vec<Vector2>) -> Path {
+        let mut path = Path::new(nodes);
+        path.set_curved(true);
+        path
+    }
+
+    // Replace the nodes, keeping `total_length` in sync
+    pub fn set_nodes(&mut self, nodes: Vec<Vector2>) {
         if nodes.len() < 2 {
             panic!("Path must have at least 2 nodes");
         }
-        let total_length = nodes
-            .iter()
-            .fold((0.0, nodes[0]), |(acc, prev), &p| (acc + prev.dist(&p), p))
-            .0;
-        Path {
-            id: 0,
-            nodes,
-            total_length,
+        self.nodes = nodes;
+        self.update_samples();
+    }
+
+    pub fn set_curved(&mut self, curved: bool) {
+        self.curved = curved;
+        self.update_samples();
+    }
+
+    fn update_samples(&mut self) {
+        let nodes = &self.nodes;
+        let mut points = vec![(nodes[0], 0)];
+        for i in 0..nodes.len() - 1 {
+            if !self.curved {
+                points.push((nodes[i + 1], i));
+                continue;
+            }
+            // the ends repeat, so the curve starts and ends heading at the next node in
+            let p0 = nodes[i.saturating_sub(1)];
+            let p3 = nodes[(i + 2).min(nodes.len() - 1)];
+            for k in 1..=CURVE_SAMPLES {
+                let t = k as f32 / CURVE_SAMPLES as f32;
+                points.push((catmull_rom(p0, nodes[i], nodes[i + 1], p3, t), i));
+            }
         }
+
+        let mut linear = 0.0;
+        let mut prev = nodes[0];
+        self.samples = points
+            .into_iter()
+            .map(|(xy, segment)| {
+                linear += prev.dist(&xy);
+                prev = xy;
+                Sample {
+                    xy,
+                    linear,
+                    segment,
+                }
+            })
+            .collect();
+        self.total_length = linear;
     }
 
-    pub fn start(&self) -> PathPosition {
-        PathPosition {
-            xy: self.nodes[0],
-            linear: 0.0,
-            // path: self,
+    pub fn end_xy(&self) -> Vector2 {
+        self.nodes[self.nodes.len() - 1]
+    }
+
+    pub fn draw(&self, _state: &State) {
+        // Draw the path
+        for w in self.samples.windows(2) {
+            webhacks::draw_line_ex(w[0].xy, w[1].xy, 2.0, RAYWHITE);
+            // unsafe { raylib::DrawLineEx(p1, p2, 2.0, RAYWHITE) }
         }
     }
 
-    pub fn end(&self) -> PathPosition {
-        PathPosition {
-            xy: self.nodes[self.nodes.len() - 1],
-            linear: self.total_length,
-            // path: self,
+    // Index of the sample starting the stretch `linear_pos` is on
+    fn sample_index(&self, linear_pos: f32) -> usize {
+        let after = self.samples.partition_point(|s| s.linear < linear_pos);
+        after.clamp(1, self.samples.len() - 1) - 1
+    }
+
+    // The point `linear_pos` along the path, clamped to its ends
+    pub fn point_at(&self, linear_pos: f32) -> Vector2 {
+        if linear_pos <= 0.0 {
+            // underflow. return the first node
+            return self.nodes[0];
         }
+        if linear_pos >= self.total_length {
+            // overflow. return the last node
+            return self.end_xy();
+        }
+
+        let i = self.sample_index(linear_pos);
+        let (s1, s2) = (self.samples[i], self.samples[i + 1]);
+        let length = s2.linear - s1.linear;
+        if length > 0.0 {
+            s1.xy.lerp(&s2.xy, (linear_pos - s1.linear) / length)
+        } else {
+            s1.xy
+        }
+    }
+
+    // Direction of travel at `linear_pos`, as a unit vector. Zero for a path that doesn't go
+    // anywhere.
+    pub fn tangent_at(&self, linear_pos: f32) -> Vector2 {
+        let i = self.sample_index(linear_pos.clamp(0.0, self.total_length));
+        // the nearest stretch that has a direction, looking forward first
+        let stretches = self.samples.windows(2);
+        stretches
+            .clone()
+            .skip(i)
+            .chain(stretches.take(i).rev())
+            .map(|w| (w[1].xy - w[0].xy).normalize())
+            .find(|t| *t != Vector2::zero())
+            .unwrap_or(Vector2::zero())
+    }
+
+    // Shortest distance from `point` to the path
+    pub fn distance_to(&self, point: Vector2) -> f32 {
+        self.nearest_segment(point).2
+    }
+
+    // The segment closest to `point`, as (index of its first node, closest point on it, distance)
+    pub fn nearest_segment(&self, point: Vector2) -> (usize, Vector2, f32) {
+        let mut nearest = (0, self.nodes[0], f32::INFINITY);
+        for w in self.samples.windows(2) {
+            let (p1, p2) = (w[0].xy, w[1].xy);
+            let segment = p2 - p1;
+            let len2 = segment.mag2();
+            let t = if len2 > 0.0 {
+                ((point - p1).dot(&segment) / len2).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let closest = p1.lerp(&p2, t);
+            let distance = closest.dist(&point);
+            if distance < nearest.2 {
+                nearest = (w[1].segment, closest, distance);
+            }
+        }
+        nearest
     }
 }
 
@@ -59,73 +235,258 @@ impl From<PathPosition> for Vector2 {
 
 impl PartialEq for PathPosition {
     fn eq(&self, other: &Self) -> bool {
-        self.xy == other.xy && self.linear == other.linear
+        self.xy == other.xy && self.linear == other.linear && self.path == other.path
     }
 }
 
-impl Path {
-    pub fn draw(&self, _state: &State) {
-        // Draw the path
-        for i in 1..self.nodes.len() {
-            let p1 = self.nodes[i - 1];
-            let p2 = self.nodes[i];
-            webhacks::draw_line_ex(p1, p2, 2.0, RAYWHITE);
-            // unsafe { raylib::DrawLineEx(p1, p2, 2.0, RAYWHITE) }
+// Most branches followed in one go. Only matters for paths that loop back on themselves
+// through zero-length paths, which would otherwise never let go.
+const MAX_BRANCHES_PER_ADVANCE: usize = 16;
+
+pub struct Paths {
+    pub paths: Vec<Path>,
+    // for each path, the shortest distance from its end to an exit
+    pub(crate) to_exit: Vec<f32>,
+}
+
+impl Paths {
+    pub fn new(paths: Vec<Path>) -> Paths {
+        if paths.is_empty() {
+            panic!("A level must have at least 1 path");
         }
+        let mut paths = Paths {
+            paths,
+            to_exit: Vec::new(),
+        };
+        paths.update_distances();
+        paths
     }
-}
 
-impl Path {
-    pub fn lin_to_position(&self, linear_pos: f32) -> PathPosition {
-        if linear_pos < 0.0 {
-            // underflow. return the first node
-            return PathPosition {
-                xy: self.nodes[0],
-                linear: 0.0,
-                // path: self,
+    // Replace the nodes of path `index`
+    pub fn set_nodes(&mut self, index: usize, nodes: Vec<Vector2>) {
+        self.paths[index].set_nodes(nodes);
+        self.update_distances();
+    }
+
+    fn update_distances(&mut self) {
+        let n = self.paths.len();
+        self.to_exit = self
+            .paths
+            .iter()
+            .map(|p| {
+                if p.branches.is_empty() {
+                    0.0
+                } else {
+                    f32::INFINITY
+                }
+            })
+            .collect();
+        // every shortest route is at most n paths long
+        for _ in 0..n {
+            for i in 0..n {
+                for branch in self.paths[i].branches.iter() {
+                    let next = &self.paths[branch.path];
+                    let through =
+                        (next.total_length - branch.at).max(0.0) + self.to_exit[branch.path];
+                    self.to_exit[i] = self.to_exit[i].min(through);
+                }
+            }
+        }
+    }
+
+    pub fn start(&self, path: usize) -> PathPosition {
+        self.lin_to_position(path, 0.0)
+    }
+
+    // `linear_pos` along path `path`, without following any branches
+    pub fn lin_to_position(&self, path: usize, linear_pos: f32) -> PathPosition {
+        let p = &self.paths[path];
+        PathPosition {
+            xy: p.point_at(linear_pos),
+            linear: linear_pos.clamp(0.0, p.total_length),
+            path,
+            tangent: p.tangent_at(linear_pos),
+        }
+    }
+
+    // Move `distance` on from `position`, following branches past the end of a path. `seed`
+    // picks the branches at forks, see `choose_branch`.
+    pub fn advance(&self, position: PathPosition, distance: f32, seed: u32) -> PathPosition {
+        let mut path = position.path;
+        let mut linear = position.linear + distance;
+        for _ in 0..MAX_BRANCHES_PER_ADVANCE {
+            let current = &self.paths[path];
+            if linear <= current.total_length {
+                break;
+            }
+            let Some(branch) = choose_branch(&current.branches, path, seed) else {
+                break;
             };
+            linear = branch.at + (linear - current.total_length);
+            path = branch.path;
+        }
+        self.lin_to_position(path, linear)
+    }
+
+    // Whether `position` has made it out
+    pub fn is_exit(&self, position: &PathPosition) -> bool {
+        let path = &self.paths[position.path];
+        path.branches.is_empty() && position.linear >= path.total_length
+    }
+
+    // How far `position` is from the nearest exit it can still get to
+    pub fn remaining(&self, position: &PathPosition) -> f32 {
+        let path = &self.paths[position.path];
+        (path.total_length - position.linear).max(0.0) + self.to_exit[position.path]
+    }
+
+    pub fn exits(&self) -> impl Iterator<Item = Vector2> + '_ {
+        self.paths
+            .iter()
+            .filter(|p| p.branches.is_empty())
+            .map(|p| p.end_xy())
+    }
+
+    pub fn distance_to(&self, point: Vector2) -> f32 {
+        self.paths
+            .iter()
+            .map(|p| p.distance_to(point))
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    // Like `Path::nearest_segment`, over all paths. The path index goes first.
+    pub fn nearest_segment(&self, point: Vector2) -> (usize, usize, Vector2, f32) {
+        let mut nearest = (0, 0, self.paths[0].nodes[0], f32::INFINITY);
+        for (i, path) in self.paths.iter().enumerate() {
+            let (segment, closest, distance) = path.nearest_segment(point);
+            if distance < nearest.3 {
+                nearest = (i, segment, closest, distance);
+            }
+        }
+        nearest
+    }
+
+    pub fn write(&self, w: &mut Writer) {
+        w.u32(self.paths.len() as u32);
+        for path in self.paths.iter() {
+            w.u32(path.nodes.len() as u32);
+            path.nodes.iter().for_each(|&node| w.vec2(node));
+            w.u32(path.curved as u32);
+            w.u32(path.branches.len() as u32);
+            for branch in path.branches.iter() {
+                w.u32(branch.path as u32);
+                w.f32(branch.at);
+                w.u32(branch.weight);
+            }
         }
+    }
 
-        let mut current_path_length = 0.0;
-        for i in 1..self.nodes.len() {
-            let p1 = self.nodes[i - 1];
-            let p2 = self.nodes[i];
-            let segment_length = p1.dist(&p2);
-            if current_path_length + segment_length >= linear_pos {
-                let segment_pos = (linear_pos - current_path_length) / segment_length;
-                return PathPosition {
-                    xy: p1.lerp(&p2, segment_pos),
-                    linear: linear_pos,
-                    // path: self,
+    pub fn read(r: &mut Reader) -> Result<Paths, DecodeError> {
+        // at least 2 nodes, the curved flag and the branch count each
+        let n = r.len(6)?;
+        let mut paths = Vec::with_capacity(n);
+        for _ in 0..n {
+            let n_nodes = r.len(2)?;
+            if n_nodes < 2 {
+                return Err(DecodeError::Invalid("path must have at least 2 nodes"));
+            }
+            let nodes = (0..n_nodes)
+                .map(|_| r.vec2())
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut path = Path::new(nodes);
+            path.set_curved(r.u32()? != 0);
+            let n_branches = r.len(3)?;
+            for _ in 0..n_branches {
+                let branch = Branch {
+                    path: r.u32()? as usize,
+                    at: r.f32()?,
+                    weight: r.u32()?,
                 };
+                if branch.path >= n {
+                    return Err(DecodeError::Invalid("branch to a path that doesn't exist"));
+                }
+                path.branches.push(branch);
             }
-            current_path_length += segment_length;
+            paths.push(path);
+        }
+        if paths.is_empty() {
+            return Err(DecodeError::Invalid("there has to be at least 1 path"));
         }
+        Ok(Paths::new(paths))
+    }
 
-        // overflow. return the last node
-        // self.nodes[self.nodes.len() - 1]
-        PathPosition {
-            xy: self.nodes[self.nodes.len() - 1],
-            linear: self.total_length,
-            // path: self,
+    pub fn draw(&self, state: &State) {
+        for path in self.paths.iter() {
+            path.draw(state);
+            // the joints, which don't have to be at a node of the other path
+            for branch in path.branches.iter() {
+                let next = &self.paths[branch.path];
+                webhacks::draw_line_ex(path.end_xy(), next.point_at(branch.at), 2.0, RAYWHITE);
+            }
         }
     }
 }
 
-impl PathPosition {
-    pub fn linear_advance(&mut self, path: &Path, distance: f32) -> &mut Self {
-        let linear_pos = self.linear + distance;
-        let new_pos = path.lin_to_position(linear_pos);
-        self.xy = new_pos.xy;
-        self.linear = new_pos.linear;
-        self
+// Which branch an enemy takes at the end of path `path`. The same `seed` and `path` always
+// give the same branch, and over many seeds each branch comes up in proportion to its weight.
+// None at an exit.
+pub fn choose_branch(branches: &[Branch], path: usize, seed: u32) -> Option<&Branch> {
+    // in u64, since a few large weights can add up past u32::MAX
+    let total: u64 = branches.iter().map(|b| b.weight as u64).sum();
+    if total == 0 {
+        return branches.first();
+    }
+    let key = seed ^ (path as u32).wrapping_mul(0x9e37_79b9);
+    // 64 bits of roll, so that every part of the total can come up
+    let mut roll = ((mix(key) as u64) << 32 | mix(!key) as u64) % total;
+    for branch in branches {
+        if roll < branch.weight as u64 {
+            return Some(branch);
+        }
+        roll -= branch.weight as u64;
     }
+    None
+}
+
+// Scramble the bits, so that neighbouring seeds don't pick neighbouring rolls
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn path(nodes: &[(f32, f32)], branches: Vec<Branch>) -> Path {
+        let mut path = Path::new(nodes.iter().map(|&(x, y)| Vector2::new(x, y)).collect());
+        path.branches = branches;
+        path
+    }
+
+    fn branch(path: usize, at: f32, weight: u32) -> Branch {
+        Branch { path, at, weight }
+    }
+
+    // 0 forks into 1 and 2, and 2 merges back into 1 halfway along it
+    fn fork_and_merge() -> Paths {
+        Paths::new(vec![
+            path(
+                &[(0.0, 0.0), (100.0, 0.0)],
+                vec![branch(1, 0.0, 1), branch(2, 0.0, 3)],
+            ),
+            path(&[(100.0, 0.0), (300.0, 0.0)], vec![]),
+            path(
+                &[(100.0, 0.0), (150.0, 50.0), (200.0, 0.0)],
+                vec![branch(1, 100.0, 1)],
+            ),
+        ])
+    }
+
     #[test]
     fn test_path_pos_to_screen_pos() {
         let nodes = vec![
@@ -134,11 +495,158 @@ mod tests {
             Vector2::new(100.0, 100.0),
         ];
         let path = Path::new(nodes);
-        let pos: Vector2 = path.lin_to_position(0.0).into();
-        assert_eq!(pos, Vector2::new(0.0, 0.0));
-        let pos: Vector2 = path.lin_to_position(50.0).into();
-        assert_eq!(pos, Vector2::new(0.0, 50.0));
-        let pos: Vector2 = path.lin_to_position(150.0).into();
-        assert_eq!(pos, Vector2::new(50.0, 100.0));
+        assert_eq!(path.point_at(0.0), Vector2::new(0.0, 0.0));
+        assert_eq!(path.point_at(50.0), Vector2::new(0.0, 50.0));
+        assert_eq!(path.point_at(150.0), Vector2::new(50.0, 100.0));
+    }
+
+    #[test]
+    fn test_distance_to_path() {
+        let nodes = vec![
+            Vector2::new(0.0, 0.0),
+            Vector2::new(0.0, 100.0),
+            Vector2::new(100.0, 100.0),
+        ];
+        let path = Path::new(nodes);
+        assert_eq!(path.distance_to(Vector2::new(-30.0, 50.0)), 30.0);
+        assert_eq!(path.distance_to(Vector2::new(50.0, 80.0)), 20.0);
+        // past the end of the last segment
+        assert_eq!(path.distance_to(Vector2::new(130.0, 140.0)), 50.0);
+
+        let (i, closest, _) = path.nearest_segment(Vector2::new(50.0, 80.0));
+        assert_eq!((i, closest), (1, Vector2::new(50.0, 100.0)));
+    }
+
+    #[test]
+    fn test_set_nodes_updates_length() {
+        let mut path = Path::new(vec![Vector2::new(0.0, 0.0), Vector2::new(0.0, 100.0)]);
+        assert_eq!(path.total_length, 100.0);
+        let mut nodes = path.nodes.clone();
+        nodes.push(Vector2::new(30.0, 140.0));
+        path.set_nodes(nodes);
+        assert_eq!(path.total_length, 150.0);
+    }
+
+    #[test]
+    fn test_curves_go_through_the_nodes_at_an_even_speed() {
+        let nodes = vec![
+            Vector2::new(0.0, 0.0),
+            Vector2::new(100.0, 0.0),
+            Vector2::new(100.0, 100.0),
+        ];
+        let straight = Path::new(nodes.clone());
+        let path = Path::new_curved(nodes.clone());
+        // rounding the corner through the node makes it a bit longer
+        assert!(path.total_length > straight.total_length);
+        assert!(path.total_length < straight.total_length * 1.1);
+        for node in nodes {
+            assert!(path.distance_to(node) < 1e-3);
+        }
+
+        let n = 50;
+        let step = path.total_length / n as f32;
+        for i in 0..n {
+            let (p1, p2) = (
+                path.point_at(i as f32 * step),
+                path.point_at((i + 1) as f32 * step),
+            );
+            assert!((p1.dist(&p2) - step).abs() < 0.05 * step);
+        }
+        assert_eq!(path.point_at(path.total_length), Vector2::new(100.0, 100.0));
+
+        // the nodes stay where they were, so inserting goes by them
+        let (segment, _, _) = path.nearest_segment(Vector2::new(110.0, 60.0));
+        assert_eq!(segment, 1);
+    }
+
+    #[test]
+    fn test_tangents() {
+        let nodes = vec![
+            Vector2::new(0.0, 0.0),
+            Vector2::new(100.0, 0.0),
+            Vector2::new(100.0, 100.0),
+        ];
+        let straight = Path::new(nodes.clone());
+        assert_eq!(straight.tangent_at(50.0), Vector2::new(1.0, 0.0));
+        assert_eq!(straight.tangent_at(150.0), Vector2::new(0.0, 1.0));
+        assert_eq!(straight.tangent_at(500.0), Vector2::new(0.0, 1.0));
+
+        let path = Path::new_curved(nodes);
+        let start = path.tangent_at(0.0);
+        assert!(start.x > 0.99);
+        // turning right, from going along x to going along y
+        let middle = path.tangent_at(path.total_length / 2.0);
+        assert!(middle.x > 0.5 && middle.y > 0.5, "{:?}", middle);
+        assert!(path.tangent_at(path.total_length).y > 0.99);
+
+        let paths = Paths::new(vec![path]);
+        let position = paths.lin_to_position(0, 10.0);
+        assert_eq!(position.tangent, paths.paths[0].tangent_at(10.0));
+        assert!((position.tangent.mag() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_paths_round_trip() {
+        let mut paths = fork_and_merge();
+        paths.paths[2].set_curved(true);
+        let mut w = Writer::new();
+        paths.write(&mut w);
+
+        let loaded = Paths::read(&mut Reader::new(&w.words)).unwrap();
+        assert_eq!(loaded.paths.len(), 3);
+        assert_eq!(loaded.paths[0].branches, paths.paths[0].branches);
+        assert!(loaded.paths[2].curved);
+        assert_eq!(loaded.paths[2].total_length, paths.paths[2].total_length);
+        assert_eq!(loaded.remaining(&loaded.start(0)), 300.0);
+    }
+
+    #[test]
+    fn test_forks_follow_the_weights() {
+        let paths = fork_and_merge();
+        let mut taken = [0; 3];
+        for seed in 0..1000 {
+            let position = paths.advance(paths.start(0), 120.0, seed);
+            assert_eq!(position.linear, 20.0);
+            // and always the same way for the same seed
+            assert_eq!(paths.advance(paths.start(0), 120.0, seed), position);
+            taken[position.path] += 1;
+        }
+        assert_eq!(taken[0], 0);
+        assert!((650..850).contains(&taken[2]), "{:?}", taken);
+    }
+
+    #[test]
+    fn test_huge_weights_dont_overflow() {
+        let branches = [branch(1, 0.0, u32::MAX), branch(2, 0.0, u32::MAX)];
+        let mut taken = [0; 3];
+        for seed in 0..100 {
+            taken[choose_branch(&branches, 0, seed).unwrap().path] += 1;
+        }
+        assert!(taken[1] > 0 && taken[2] > 0, "{:?}", taken);
+    }
+
+    #[test]
+    fn test_merge_and_exit() {
+        let paths = fork_and_merge();
+        let on_2 = paths.lin_to_position(2, 0.0);
+        let length_2 = paths.paths[2].total_length;
+
+        // off the end of 2 and onto 1, halfway along it
+        let merged = paths.advance(on_2, length_2 + 10.0, 0);
+        assert_eq!((merged.path, merged.linear), (1, 110.0));
+        assert_eq!(merged.xy, Vector2::new(210.0, 0.0));
+        assert!(!paths.is_exit(&merged));
+
+        let out = paths.advance(merged, 500.0, 0);
+        assert!(paths.is_exit(&out));
+        assert_eq!(out.xy, Vector2::new(300.0, 0.0));
+
+        assert_eq!(paths.remaining(&merged), 90.0);
+        // the short way round, straight along 1
+        assert_eq!(paths.remaining(&paths.start(0)), 300.0);
+        assert_eq!(
+            paths.exits().collect::<Vec<_>>(),
+            vec![Vector2::new(300.0, 0.0)]
+        );
     }
 }
@@ -0,0 +1,87 @@
+// Where a new turret is allowed to go.
+//
+// A turret has to keep PATH_CLEARANCE between its edge and every segment of the paths, can't
+// overlap another turret, and has to be fully inside the play area. With snapping on, the
+// mouse position is first moved to the center of its GRID_SIZE cell.
+
+use crate::path::Paths;
+use crate::turret::Turret;
+use crate::vec2::Vector2;
+
+pub const PATH_CLEARANCE: f32 = 10.0;
+pub const GRID_SIZE: f32 = 40.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlacementError {
+    OnPath,
+    Overlaps,
+    OutOfBounds,
+}
+
+// Center of the grid cell `position` is in
+pub fn snap(position: Vector2) -> Vector2 {
+    let center = |v: f32| (v / GRID_SIZE).floor() * GRID_SIZE + GRID_SIZE / 2.0;
+    Vector2::new(center(position.x), center(position.y))
+}
+
+// Whether a turret of `radius` can go at `position`. `area` is the size of the play area,
+// which starts at the origin.
+pub fn check(
+    position: Vector2,
+    radius: f32,
+    area: Vector2,
+    paths: &Paths,
+    turrets: &[Turret],
+) -> Result<(), PlacementError> {
+    let inside = position.x - radius >= 0.0
+        && position.y - radius >= 0.0
+        && position.x + radius <= area.x
+        && position.y + radius <= area.y;
+    if !inside {
+        return Err(PlacementError::OutOfBounds);
+    }
+    if paths.distance_to(position) < radius + PATH_CLEARANCE {
+        return Err(PlacementError::OnPath);
+    }
+    let overlaps = turrets
+        .iter()
+        .any(|t| t.position.dist(&position) < t.radius + radius);
+    if overlaps {
+        return Err(PlacementError::Overlaps);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::Path;
+    use crate::turret::TurretKind;
+
+    fn paths() -> Paths {
+        Paths::new(vec![
+            Path::new(vec![Vector2::new(0.0, 100.0), Vector2::new(400.0, 100.0)]),
+            Path::new(vec![Vector2::new(300.0, 0.0), Vector2::new(300.0, 50.0)]),
+        ])
+    }
+
+    #[test]
+    fn test_snap_to_cell_center() {
+        assert_eq!(snap(Vector2::new(5.0, 79.0)), Vector2::new(20.0, 60.0));
+        assert_eq!(snap(Vector2::new(40.0, 0.0)), Vector2::new(60.0, 20.0));
+    }
+
+    #[test]
+    fn test_placement_rules() {
+        let area = Vector2::new(400.0, 300.0);
+        let turrets = vec![Turret::new(TurretKind::Gun, Vector2::new(200.0, 200.0))];
+        let check = |x, y| check(Vector2::new(x, y), 20.0, area, &paths(), &turrets);
+
+        assert_eq!(check(100.0, 200.0), Ok(()));
+        assert_eq!(check(100.0, 125.0), Err(PlacementError::OnPath));
+        assert_eq!(check(320.0, 40.0), Err(PlacementError::OnPath));
+        assert_eq!(check(230.0, 200.0), Err(PlacementError::Overlaps));
+        assert_eq!(check(390.0, 200.0), Err(PlacementError::OutOfBounds));
+        assert_eq!(check(100.0, 290.0), Err(PlacementError::OutOfBounds));
+    }
+}
@@ -0,0 +1,173 @@
+// Versioned save format for the `State`.
+//
+// Only the game data goes in: life, paths, entities, flags and timers. Asset handles (anims,
+// textures, font, music) are never written out. On load they are re-attached from the ones
+// the running game already has, so a save is fine to load in a different session or build.
+//
+// The file is a stream of little-endian u32 words:
+//
+//   magic "GSAV" | version | frame_count | sim_time | slime_pos | flags | life | gold | stats | waves | paths | entities
+//
+// Bump SAVE_VERSION whenever any of this changes. Old saves are then refused with an error
+// rather than being misread.
+
+use crate::editor::Drag;
+use crate::entity_manager::{EntityManager, NO_ID};
+use crate::events::{EventQueue, Stats};
+use crate::path::Paths;
+use crate::waves::Waves;
+use crate::words::{DecodeError, Reader, Writer};
+use crate::State;
+
+pub const SAVE_MAGIC: u32 = u32::from_le_bytes(*b"GSAV");
+pub const SAVE_VERSION: u32 = 15;
+
+pub const SAVE_PATH: &str = "save.dat";
+
+#[derive(Debug, PartialEq)]
+pub enum SaveError {
+    BadMagic,
+    VersionMismatch { found: u32, expected: u32 },
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SaveError::BadMagic => write!(f, "not a save file (bad magic)"),
+            SaveError::VersionMismatch { found, expected } => write!(
+                f,
+                "save file is version {}, but this build reads version {}",
+                found, expected
+            ),
+            SaveError::Truncated => write!(f, "save file is truncated"),
+            SaveError::Invalid(what) => write!(f, "save file is invalid: {}", what),
+        }
+    }
+}
+
+impl From<DecodeError> for SaveError {
+    fn from(err: DecodeError) -> SaveError {
+        match err {
+            DecodeError::Truncated => SaveError::Truncated,
+            DecodeError::Invalid(what) => SaveError::Invalid(what),
+        }
+    }
+}
+
+//==================
+// State
+//==================
+
+pub fn save_state(state: &State) -> Vec<u8> {
+    let mut w = Writer::new();
+
+    w.u32(SAVE_MAGIC);
+    w.u32(SAVE_VERSION);
+
+    w.u32(state.frame_count);
+    w.f32(state.sim_time);
+    w.vec2(state.slime_pos);
+    w.bool(state.mute.into());
+    w.bool(state.debug.into());
+    w.bool(state.editor.into());
+    w.bool(state.snap.into());
+    w.u32(state.life);
+    w.u32(state.gold);
+    state.stats.write(&mut w);
+    state.waves.write(&mut w);
+
+    state.paths.write(&mut w);
+
+    // the entities go last and take up the rest of the file
+    w.words.extend_from_slice(&state.man.to_state());
+
+    w.words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+// Everything is decoded before anything is written into `state`, so on error the state is
+// left exactly as it was.
+pub fn load_state(state: &mut State, bytes: &[u8]) -> Result<(), SaveError> {
+    if !bytes.len().is_multiple_of(4) {
+        return Err(SaveError::Truncated);
+    }
+    let words = bytes
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect::<Vec<u32>>();
+    let mut r = Reader::new(&words);
+
+    if r.u32()? != SAVE_MAGIC {
+        return Err(SaveError::BadMagic);
+    }
+    let version = r.u32()?;
+    if version != SAVE_VERSION {
+        return Err(SaveError::VersionMismatch {
+            found: version,
+            expected: SAVE_VERSION,
+        });
+    }
+
+    let frame_count = r.u32()?;
+    let sim_time = r.f32()?;
+    let slime_pos = r.vec2()?;
+    let mute = r.bool()?;
+    let debug = r.bool()?;
+    let editor = r.bool()?;
+    let snap = r.bool()?;
+    let life = r.u32()?;
+    let gold = r.u32()?;
+    let stats = Stats::read(&mut r)?;
+    let mut waves = Waves::new(state.waves.waves.clone());
+    waves.read_progress(&mut r)?;
+
+    let paths = Paths::read(&mut r)?;
+
+    let man = EntityManager::from_state(&words[words.len() - r.remaining()..])?;
+    if man
+        .enemies
+        .iter()
+        .any(|e| e.position.path >= paths.paths.len())
+    {
+        return Err(SaveError::Invalid("enemy on a path that doesn't exist"));
+    }
+
+    state.frame_count = frame_count;
+    state.sim_time = sim_time;
+    state.sim_accumulator = 0.0;
+    state.slime_pos = slime_pos;
+    state.mute = mute.into();
+    state.debug = debug.into();
+    state.editor = editor.into();
+    state.snap = snap.into();
+    state.life = life;
+    state.gold = gold;
+    state.stats = stats;
+    state.waves = waves;
+    state.events = EventQueue::new();
+    state.paths = paths;
+    state.man = man;
+    // ids from before the load may now belong to other entities, or none
+    state.selected = NO_ID;
+    state.editor_drag = Drag::None;
+
+    attach_anims(state);
+
+    Ok(())
+}
+
+// Point all the entities at the anims from the state's own assets
+pub fn attach_anims(state: &mut State) {
+    for i in 0..state.man.turrets.len() {
+        let sprite = state.man.turrets[i].archetype().sprite;
+        state.man.turrets[i].anim = Some(state.turret_anim_for(sprite).clone());
+    }
+    for i in 0..state.man.enemies.len() {
+        let sprite = state.man.enemies[i].archetype().sprite;
+        state.man.enemies[i].anim = Some(state.enemy_anim(sprite).clone());
+    }
+    for bullet in state.man.bullets.iter_mut() {
+        bullet.anim = Some(state.bullet_anim.clone());
+    }
+}
@@ -0,0 +1,252 @@
+// Uniform grid over a set of points, for the "what's near here" questions (turret targeting,
+// debug lines). It is rebuilt from scratch every simulation step, which is cheap: one pass to
+// count how many points go in each cell and one to drop them in (a counting sort), so there
+// is no per-cell allocation.
+//
+// The grid only knows points by their index in whatever list it was built from. Queries hand
+// back candidate indices and the caller does the exact checks on the real entities.
+
+use crate::vec2::Vector2;
+
+pub const CELL_SIZE: f32 = 64.0;
+
+pub struct Grid {
+    pub(crate) cell_size: f32,
+    pub(crate) origin: Vector2,
+    pub(crate) cols: i32,
+    pub(crate) rows: i32,
+    // the points in cell `c` are `items[cell_start[c]..cell_start[c + 1]]`
+    pub(crate) cell_start: Vec<u32>,
+    pub(crate) items: Vec<u32>,
+}
+
+impl Grid {
+    pub fn new(cell_size: f32) -> Grid {
+        Grid {
+            cell_size,
+            origin: Vector2::zero(),
+            cols: 0,
+            rows: 0,
+            cell_start: vec![0],
+            items: Vec::new(),
+        }
+    }
+
+    // Fit the grid around `positions` and bin them
+    pub fn rebuild(&mut self, positions: &[Vector2]) {
+        self.items.clear();
+        self.cell_start.clear();
+
+        if positions.is_empty() {
+            self.cols = 0;
+            self.rows = 0;
+            self.cell_start.push(0);
+            return;
+        }
+
+        let mut min = positions[0];
+        let mut max = positions[0];
+        for p in positions.iter() {
+            min = Vector2::new(min.x.min(p.x), min.y.min(p.y));
+            max = Vector2::new(max.x.max(p.x), max.y.max(p.y));
+        }
+        self.origin = min;
+        self.cols = ((max.x - min.x) / self.cell_size) as i32 + 1;
+        self.rows = ((max.y - min.y) / self.cell_size) as i32 + 1;
+
+        let n_cells = (self.cols * self.rows) as usize;
+        self.cell_start.resize(n_cells + 1, 0);
+        for p in positions.iter() {
+            let c = self.cell_index(self.cell_of(*p));
+            self.cell_start[c + 1] += 1;
+        }
+        for c in 0..n_cells {
+            self.cell_start[c + 1] += self.cell_start[c];
+        }
+
+        let mut next = self.cell_start.clone();
+        self.items.resize(positions.len(), 0);
+        for (i, p) in positions.iter().enumerate() {
+            let c = self.cell_index(self.cell_of(*p));
+            self.items[next[c] as usize] = i as u32;
+            next[c] += 1;
+        }
+    }
+
+    // Cell coordinates, not clamped. Can be outside of the grid
+    fn cell_of(&self, position: Vector2) -> (i32, i32) {
+        (
+            ((position.x - self.origin.x) / self.cell_size).floor() as i32,
+            ((position.y - self.origin.y) / self.cell_size).floor() as i32,
+        )
+    }
+
+    fn cell_index(&self, (x, y): (i32, i32)) -> usize {
+        (y * self.cols + x) as usize
+    }
+
+    fn cell(&self, x: i32, y: i32) -> &[u32] {
+        if x < 0 || y < 0 || x >= self.cols || y >= self.rows {
+            return &[];
+        }
+        let c = self.cell_index((x, y));
+        &self.items[self.cell_start[c] as usize..self.cell_start[c + 1] as usize]
+    }
+
+    // Every point which might be within `radius` of `position`. Also gives some further away,
+    // so check the distance.
+    pub fn candidates(&self, position: Vector2, radius: f32, mut f: impl FnMut(usize)) {
+        let (x0, y0) = self.cell_of(position - Vector2::new(radius, radius));
+        let (x1, y1) = self.cell_of(position + Vector2::new(radius, radius));
+        for y in y0.max(0)..=y1.min(self.rows - 1) {
+            for x in x0.max(0)..=x1.min(self.cols - 1) {
+                self.cell(x, y).iter().for_each(|&i| f(i as usize));
+            }
+        }
+    }
+
+    // The point for which `dist` is smallest, out of those within `max_dist` of `position`.
+    // `dist(i)` has to be the distance from `position` to point `i`. Searches rings of cells
+    // outwards from `position` and stops once no closer point can turn up.
+    pub fn nearest(
+        &self,
+        position: Vector2,
+        max_dist: f32,
+        dist: impl Fn(usize) -> f32,
+    ) -> Option<usize> {
+        if self.items.is_empty() {
+            return None;
+        }
+
+        let (cx, cy) = self.cell_of(position);
+        // far enough out to have covered the whole grid
+        let last_ring = [cx, self.cols - 1 - cx, cy, self.rows - 1 - cy]
+            .iter()
+            .map(|d| d.abs())
+            .max()
+            .unwrap();
+
+        let mut best: Option<(usize, f32)> = None;
+        for r in 0..=last_ring {
+            // everything in ring r is at least (r - 1) cells away
+            let ring_dist = (r - 1).max(0) as f32 * self.cell_size;
+            if ring_dist > max_dist || best.is_some_and(|(_, d)| d <= ring_dist) {
+                break;
+            }
+
+            let mut visit = |x: i32, y: i32| {
+                for &i in self.cell(x, y) {
+                    let d = dist(i as usize);
+                    if d <= max_dist && best.is_none_or(|(_, bd)| d < bd) {
+                        best = Some((i as usize, d));
+                    }
+                }
+            };
+            if r == 0 {
+                visit(cx, cy);
+                continue;
+            }
+            for x in cx - r..=cx + r {
+                visit(x, cy - r);
+                visit(x, cy + r);
+            }
+            for y in cy - r + 1..cy + r {
+                visit(cx - r, y);
+                visit(cx + r, y);
+            }
+        }
+
+        best.map(|(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> Vec<Vector2> {
+        // a deterministic scatter, with a few on top of each other
+        let mut points = Vec::new();
+        let mut x: u32 = 12345;
+        for _ in 0..300 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            let px = (x % 1000) as f32 - 100.0;
+            let py = ((x / 1000) % 700) as f32 - 50.0;
+            points.push(Vector2::new(px, py));
+        }
+        points.push(Vector2::new(10.0, 10.0));
+        points.push(Vector2::new(10.0, 10.0));
+        points
+    }
+
+    fn brute_nearest(points: &[Vector2], position: Vector2, max_dist: f32) -> Option<usize> {
+        let mut best: Option<(usize, f32)> = None;
+        for (i, p) in points.iter().enumerate() {
+            let d = p.dist(&position);
+            if d <= max_dist && best.is_none_or(|(_, bd)| d < bd) {
+                best = Some((i, d));
+            }
+        }
+        best.map(|(i, _)| i)
+    }
+
+    #[test]
+    fn test_nearest_matches_brute_force() {
+        let points = points();
+        let mut grid = Grid::new(CELL_SIZE);
+        grid.rebuild(&points);
+
+        let queries = [
+            Vector2::new(0.0, 0.0),
+            Vector2::new(10.0, 10.0),
+            Vector2::new(450.0, 300.0),
+            Vector2::new(-500.0, -500.0),
+            Vector2::new(2000.0, 100.0),
+        ];
+        for q in queries {
+            for max_dist in [30.0, 150.0, f32::MAX] {
+                let found = grid.nearest(q, max_dist, |i| points[i].dist(&q));
+                let expected = brute_nearest(&points, q, max_dist);
+                assert_eq!(
+                    found.map(|i| points[i].dist(&q)),
+                    expected.map(|i| points[i].dist(&q)),
+                    "query {:?} max_dist {}",
+                    q,
+                    max_dist
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_candidates_cover_radius() {
+        let points = points();
+        let mut grid = Grid::new(CELL_SIZE);
+        grid.rebuild(&points);
+
+        let q = Vector2::new(300.0, 200.0);
+        let mut found = Vec::new();
+        grid.candidates(q, 150.0, |i| {
+            if points[i].dist(&q) < 150.0 {
+                found.push(i)
+            }
+        });
+        found.sort();
+
+        let expected = (0..points.len())
+            .filter(|&i| points[i].dist(&q) < 150.0)
+            .collect::<Vec<_>>();
+        assert!(!expected.is_empty());
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_empty_grid() {
+        let mut grid = Grid::new(CELL_SIZE);
+        grid.rebuild(&[]);
+        assert_eq!(grid.nearest(Vector2::zero(), f32::MAX, |_| 0.0), None);
+        grid.candidates(Vector2::zero(), 100.0, |_| panic!("nothing in here"));
+    }
+}
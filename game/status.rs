@@ -0,0 +1,295 @@
+// Status effects on enemies: slow, poison, burn and stun.
+//
+// Each enemy has a fixed number of slots. How a new effect lands depends on its kind:
+//
+//   slow, burn  - one at a time. a new one refreshes it, keeping the longer duration and the
+//                 stronger strength
+//   stun        - one at a time, and only ever extended
+//   poison      - stacks, up to MAX_POISON_STACKS, each one ticking on its own. past that the
+//                 stack closest to running out is refreshed instead
+//
+// So there is always a slot for a new effect.
+
+use raylib_wasm::{Color, LIME, ORANGE, SKYBLUE, YELLOW};
+
+use crate::entity_manager::EntityId;
+use crate::words::{DecodeError, Reader, Writer};
+
+pub const MAX_POISON_STACKS: usize = 3;
+// one of each, bar poison which can have several
+pub const MAX_STATUSES: usize = 3 + MAX_POISON_STACKS;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatusKind {
+    Slow,
+    Poison,
+    Burn,
+    Stun,
+}
+
+impl StatusKind {
+    pub const ALL: [StatusKind; 4] = [
+        StatusKind::Slow,
+        StatusKind::Poison,
+        StatusKind::Burn,
+        StatusKind::Stun,
+    ];
+
+    pub fn from_u32(value: u32) -> Option<StatusKind> {
+        StatusKind::ALL.get(value as usize).copied()
+    }
+
+    // For the icons over the enemy
+    pub fn color(self) -> Color {
+        match self {
+            StatusKind::Slow => SKYBLUE,
+            StatusKind::Poison => LIME,
+            StatusKind::Burn => ORANGE,
+            StatusKind::Stun => YELLOW,
+        }
+    }
+}
+
+// What a projectile puts on the enemies it hits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatusEffect {
+    pub kind: StatusKind,
+    // seconds
+    pub duration: f32,
+    // slow: fraction of the speed taken away. poison and burn: damage per second. stun: unused
+    pub strength: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Status {
+    pub kind: StatusKind,
+    pub remaining: f32,
+    pub strength: f32,
+    // damage owed but not dealt yet, since health only goes down in whole points
+    pub owed: f32,
+    // the turret that applied it, to be credited with the kill
+    pub source: EntityId,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Statuses {
+    pub slots: [Option<Status>; MAX_STATUSES],
+}
+
+// Damage dealt by a status in one tick
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatusDamage {
+    pub damage: u32,
+    pub source: EntityId,
+}
+
+impl Statuses {
+    pub fn new() -> Statuses {
+        Statuses {
+            slots: [None; MAX_STATUSES],
+        }
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = &Status> {
+        self.slots.iter().flatten()
+    }
+
+    pub fn has(&self, kind: StatusKind) -> bool {
+        self.active().any(|s| s.kind == kind)
+    }
+
+    pub fn apply(&mut self, effect: StatusEffect, source: EntityId) {
+        let new = Status {
+            kind: effect.kind,
+            remaining: effect.duration,
+            strength: effect.strength,
+            owed: 0.0,
+            source,
+        };
+
+        let same = |s: &Option<Status>| s.is_some_and(|s| s.kind == effect.kind);
+        let stacks = self.slots.iter().filter(|s| same(s)).count();
+        let refresh = match effect.kind {
+            StatusKind::Poison => stacks >= MAX_POISON_STACKS,
+            _ => stacks > 0,
+        };
+
+        if refresh {
+            // the one closest to running out
+            let existing = self
+                .slots
+                .iter_mut()
+                .flatten()
+                .filter(|s| s.kind == effect.kind)
+                .min_by(|a, b| a.remaining.total_cmp(&b.remaining))
+                .unwrap();
+            existing.remaining = existing.remaining.max(new.remaining);
+            if effect.kind != StatusKind::Stun {
+                existing.strength = existing.strength.max(new.strength);
+            }
+            existing.source = source;
+        } else if let Some(slot) = self.slots.iter_mut().find(|s| s.is_none()) {
+            *slot = Some(new);
+        }
+    }
+
+    // Multiplier for the distance moved along the path
+    pub fn speed_factor(&self) -> f32 {
+        if self.has(StatusKind::Stun) {
+            return 0.0;
+        }
+        let slow = self
+            .active()
+            .filter(|s| s.kind == StatusKind::Slow)
+            .map(|s| s.strength)
+            .fold(0.0, f32::max);
+        (1.0 - slow).clamp(0.0, 1.0)
+    }
+
+    // Run the effects for `dt` seconds. Expired ones are removed.
+    pub fn tick(&self, dt: f32) -> (Statuses, Vec<StatusDamage>) {
+        let mut next = *self;
+        let mut damage = Vec::new();
+        for slot in next.slots.iter_mut() {
+            let Some(status) = slot else {
+                continue;
+            };
+            if let StatusKind::Poison | StatusKind::Burn = status.kind {
+                status.owed += status.strength * dt.min(status.remaining);
+                let whole = status.owed.floor();
+                if whole >= 1.0 {
+                    status.owed -= whole;
+                    damage.push(StatusDamage {
+                        damage: whole as u32,
+                        source: status.source,
+                    });
+                }
+            }
+            status.remaining -= dt;
+            if status.remaining <= 0.0 {
+                *slot = None;
+            }
+        }
+        (next, damage)
+    }
+
+    pub fn write(&self, w: &mut Writer) {
+        for slot in self.slots.iter() {
+            match slot {
+                Some(status) => {
+                    w.u32(status.kind as u32 + 1);
+                    w.f32(status.remaining);
+                    w.f32(status.strength);
+                    w.f32(status.owed);
+                    w.u32(status.source);
+                }
+                None => (0..STATUS_WORDS).for_each(|_| w.u32(0)),
+            }
+        }
+    }
+
+    pub fn read(r: &mut Reader) -> Result<Statuses, DecodeError> {
+        let mut statuses = Statuses::new();
+        for slot in statuses.slots.iter_mut() {
+            let kind = r.u32()?;
+            let remaining = r.f32()?;
+            let strength = r.f32()?;
+            let owed = r.f32()?;
+            let source = r.u32()?;
+            if kind == 0 {
+                continue;
+            }
+            let kind = StatusKind::from_u32(kind - 1)
+                .ok_or(DecodeError::Invalid("unknown status kind"))?;
+            *slot = Some(Status {
+                kind,
+                remaining,
+                strength,
+                owed,
+                source,
+            });
+        }
+        Ok(statuses)
+    }
+}
+
+const STATUS_WORDS: usize = 5;
+pub const STATUSES_WORDS: usize = STATUS_WORDS * MAX_STATUSES;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn effect(kind: StatusKind, duration: f32, strength: f32) -> StatusEffect {
+        StatusEffect {
+            kind,
+            duration,
+            strength,
+        }
+    }
+
+    #[test]
+    fn test_slow_refreshes_instead_of_stacking() {
+        let mut statuses = Statuses::new();
+        statuses.apply(effect(StatusKind::Slow, 1.0, 0.5), 1);
+        statuses.apply(effect(StatusKind::Slow, 3.0, 0.25), 2);
+        assert_eq!(statuses.active().count(), 1);
+        let slow = statuses.active().next().unwrap();
+        assert_eq!((slow.remaining, slow.strength), (3.0, 0.5));
+        assert_eq!(statuses.speed_factor(), 0.5);
+
+        statuses.apply(effect(StatusKind::Stun, 0.5, 0.0), 1);
+        assert_eq!(statuses.speed_factor(), 0.0);
+    }
+
+    #[test]
+    fn test_poison_stacks_and_ticks() {
+        let mut statuses = Statuses::new();
+        for _ in 0..MAX_POISON_STACKS + 2 {
+            statuses.apply(effect(StatusKind::Poison, 2.0, 1.0), 7);
+        }
+        assert_eq!(statuses.active().count(), MAX_POISON_STACKS);
+
+        // three stacks of 1 per second, for the 2 seconds they last
+        let mut total = 0;
+        for _ in 0..24 {
+            let (next, damage) = statuses.tick(0.125);
+            statuses = next;
+            assert!(damage.iter().all(|d| d.source == 7));
+            total += damage.iter().map(|d| d.damage).sum::<u32>();
+        }
+        assert_eq!(total, 6);
+        assert_eq!(statuses.active().count(), 0);
+    }
+
+    #[test]
+    fn test_full_poison_refreshes_the_oldest_stack() {
+        let mut statuses = Statuses::new();
+        for kind in StatusKind::ALL {
+            statuses.apply(effect(kind, 1.0, 1.0), 1);
+        }
+        statuses.apply(effect(StatusKind::Poison, 2.0, 1.0), 1);
+        statuses.apply(effect(StatusKind::Poison, 3.0, 1.0), 1);
+        assert_eq!(statuses.active().count(), MAX_STATUSES);
+
+        statuses.apply(effect(StatusKind::Poison, 5.0, 1.0), 2);
+        let mut poison = statuses
+            .active()
+            .filter(|s| s.kind == StatusKind::Poison)
+            .map(|s| (s.remaining, s.source))
+            .collect::<Vec<_>>();
+        poison.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert_eq!(poison, vec![(2.0, 1), (3.0, 1), (5.0, 2)]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut statuses = Statuses::new();
+        statuses.apply(effect(StatusKind::Burn, 1.5, 2.0), 3);
+        statuses.apply(effect(StatusKind::Slow, 0.5, 0.3), 4);
+        let mut w = Writer::new();
+        statuses.write(&mut w);
+        assert_eq!(w.words.len(), STATUSES_WORDS);
+        assert_eq!(Statuses::read(&mut Reader::new(&w.words)), Ok(statuses));
+    }
+}
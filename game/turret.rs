@@ -1,22 +1,309 @@
-use raylib_wasm::PINK;
+use raylib_wasm::{GOLD, PINK};
 
 use crate::vec2::Vector2;
 // use crate::vec2::Vector2Ext;
 
 use crate::anim;
-use crate::entity_manager::{EntityId, HasId};
+use crate::bullet::{Bullet, ProjectileKind};
+use crate::entity_manager::{Behaviour, Effects, EntityId, HasId, TurretUpgrade, NO_ID};
 use crate::webhacks;
 use crate::State;
 
 use crate::u32_bool::Bool;
 
-use crate::ACTIVE_RADIUS;
 use crate::ALPHA_BEIGE;
 
-const FIRE_COOLDOWN: f32 = 0.5; // seconds
+// Share of the gold spent on a turret that selling it gets back
+pub const SELL_REFUND: f32 = 0.7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TurretKind {
+    Gun,
+    Rapid,
+    Sniper,
+    Cannon,
+    Frost,
+    Venom,
+}
+
+// Which of the state's anims a turret is drawn with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TurretSprite {
+    Turret,
+}
+
+// Everything about a turret that changes with its upgrade tier
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tier {
+    // to build (tier 0) or to upgrade to this tier
+    pub cost: u32,
+    pub range: f32,
+    pub fire_cooldown: f32, // seconds
+    pub damage: u32,
+    pub projectile: ProjectileKind,
+}
+
+pub struct TurretArchetype {
+    pub name: &'static str,
+    // the first one is what gets built, the rest are bought one after the other
+    pub tiers: &'static [Tier],
+    pub sprite: TurretSprite,
+    pub radius: f32,
+}
+
+impl TurretArchetype {
+    pub fn build_cost(&self) -> u32 {
+        self.tiers[0].cost
+    }
+}
+
+// Indexed by `TurretKind`
+const ARCHETYPES: [TurretArchetype; 6] = [
+    TurretArchetype {
+        name: "gun",
+        tiers: &[
+            Tier {
+                cost: 25,
+                range: 150.0,
+                fire_cooldown: 0.5,
+                damage: 1,
+                projectile: ProjectileKind::Bullet,
+            },
+            Tier {
+                cost: 30,
+                range: 170.0,
+                fire_cooldown: 0.4,
+                damage: 2,
+                projectile: ProjectileKind::Bullet,
+            },
+            Tier {
+                cost: 60,
+                range: 190.0,
+                fire_cooldown: 0.35,
+                damage: 3,
+                projectile: ProjectileKind::Slug,
+            },
+        ],
+        sprite: TurretSprite::Turret,
+        radius: 20.0,
+    },
+    TurretArchetype {
+        name: "rapid",
+        tiers: &[
+            Tier {
+                cost: 40,
+                range: 120.0,
+                fire_cooldown: 0.15,
+                damage: 1,
+                projectile: ProjectileKind::Bullet,
+            },
+            Tier {
+                cost: 45,
+                range: 130.0,
+                fire_cooldown: 0.1,
+                damage: 1,
+                projectile: ProjectileKind::Bullet,
+            },
+            Tier {
+                cost: 80,
+                range: 140.0,
+                fire_cooldown: 0.08,
+                damage: 2,
+                projectile: ProjectileKind::Bullet,
+            },
+        ],
+        sprite: TurretSprite::Turret,
+        radius: 16.0,
+    },
+    TurretArchetype {
+        name: "sniper",
+        tiers: &[
+            Tier {
+                cost: 60,
+                range: 400.0,
+                fire_cooldown: 2.0,
+                damage: 6,
+                projectile: ProjectileKind::Slug,
+            },
+            Tier {
+                cost: 70,
+                range: 480.0,
+                fire_cooldown: 1.6,
+                damage: 9,
+                projectile: ProjectileKind::Slug,
+            },
+            Tier {
+                cost: 120,
+                range: 560.0,
+                fire_cooldown: 1.2,
+                damage: 14,
+                projectile: ProjectileKind::Slug,
+            },
+        ],
+        sprite: TurretSprite::Turret,
+        radius: 20.0,
+    },
+    TurretArchetype {
+        name: "cannon",
+        tiers: &[
+            Tier {
+                cost: 70,
+                range: 180.0,
+                fire_cooldown: 1.2,
+                damage: 3,
+                projectile: ProjectileKind::Shell,
+            },
+            Tier {
+                cost: 80,
+                range: 200.0,
+                fire_cooldown: 1.0,
+                damage: 4,
+                projectile: ProjectileKind::Shell,
+            },
+            Tier {
+                cost: 140,
+                range: 220.0,
+                fire_cooldown: 0.9,
+                damage: 6,
+                projectile: ProjectileKind::Shell,
+            },
+        ],
+        sprite: TurretSprite::Turret,
+        radius: 26.0,
+    },
+    TurretArchetype {
+        name: "frost",
+        tiers: &[
+            Tier {
+                cost: 45,
+                range: 140.0,
+                fire_cooldown: 0.8,
+                damage: 0,
+                projectile: ProjectileKind::FrostBolt,
+            },
+            Tier {
+                cost: 50,
+                range: 160.0,
+                fire_cooldown: 0.6,
+                damage: 0,
+                projectile: ProjectileKind::FrostBolt,
+            },
+            Tier {
+                cost: 90,
+                range: 180.0,
+                fire_cooldown: 0.5,
+                damage: 1,
+                projectile: ProjectileKind::FrostBolt,
+            },
+        ],
+        sprite: TurretSprite::Turret,
+        radius: 18.0,
+    },
+    TurretArchetype {
+        name: "venom",
+        tiers: &[
+            Tier {
+                cost: 50,
+                range: 160.0,
+                fire_cooldown: 0.7,
+                damage: 0,
+                projectile: ProjectileKind::Dart,
+            },
+            Tier {
+                cost: 55,
+                range: 175.0,
+                fire_cooldown: 0.5,
+                damage: 0,
+                projectile: ProjectileKind::Dart,
+            },
+            Tier {
+                cost: 100,
+                range: 190.0,
+                fire_cooldown: 0.4,
+                damage: 1,
+                projectile: ProjectileKind::Dart,
+            },
+        ],
+        sprite: TurretSprite::Turret,
+        radius: 18.0,
+    },
+];
+
+impl TurretKind {
+    pub const ALL: [TurretKind; 6] = [
+        TurretKind::Gun,
+        TurretKind::Rapid,
+        TurretKind::Sniper,
+        TurretKind::Cannon,
+        TurretKind::Frost,
+        TurretKind::Venom,
+    ];
+
+    pub fn archetype(self) -> &'static TurretArchetype {
+        &ARCHETYPES[self as usize]
+    }
+
+    pub fn from_u32(value: u32) -> Option<TurretKind> {
+        TurretKind::ALL.get(value as usize).copied()
+    }
+}
+
+// Which enemy in range a turret shoots at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Targeting {
+    // furthest along the path
+    First,
+    // least far along the path
+    Last,
+    // most health left
+    Strongest,
+    // least health left
+    Weakest,
+    Closest,
+}
+
+impl Targeting {
+    pub const ALL: [Targeting; 5] = [
+        Targeting::First,
+        Targeting::Last,
+        Targeting::Strongest,
+        Targeting::Weakest,
+        Targeting::Closest,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Targeting::First => "first",
+            Targeting::Last => "last",
+            Targeting::Strongest => "strongest",
+            Targeting::Weakest => "weakest",
+            Targeting::Closest => "closest",
+        }
+    }
+
+    // The one after this, for cycling through them from the UI
+    pub fn next(self) -> Targeting {
+        Targeting::ALL[(self as usize + 1) % Targeting::ALL.len()]
+    }
+
+    pub fn from_u32(value: u32) -> Option<Targeting> {
+        Targeting::ALL.get(value as usize).copied()
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Turret {
+    pub kind: TurretKind,
+    pub targeting: Targeting,
+    // what we aimed at in the last step. NO_ID if nothing was in range
+    pub target: EntityId,
+    // index into the archetype's tiers
+    pub tier: u32,
+    // gold paid for it so far, upgrades included. selling gives back a part of this
+    pub spent: u32,
+    // placed by the level (or in the editor) rather than bought. only these go into the level
+    // file when the editor saves it
+    pub preset: Bool,
     pub position: Vector2,
     pub dead: Bool,
     pub hover: Bool,
@@ -29,9 +316,11 @@ pub struct Turret {
 pub struct TurretUpdate {
     pub id: EntityId, // to match up with the turret
     pub dead: bool,
+    pub target: EntityId,
+    pub tier: u32,
+    pub spent: u32,
     pub fire_cooldown: f32,
     pub hover: bool,
-    pub bullet_request: Option<BulletRequest>,
     pub facing: Vector2,
 }
 
@@ -40,35 +329,61 @@ impl From<&Turret> for TurretUpdate {
         TurretUpdate {
             id: turret.id,
             dead: turret.dead.into(),
+            target: turret.target,
+            tier: turret.tier,
+            spent: turret.spent,
             fire_cooldown: turret.fire_cooldown,
             hover: turret.hover.into(),
-            bullet_request: None,
             facing: turret.facing,
         }
     }
 }
 
-pub struct BulletRequest {
-    pub position: Vector2,
-    pub source: EntityId,
-    pub target: Option<EntityId>,
-}
-
 impl Turret {
-    pub fn new(position: Vector2) -> Turret {
+    pub fn new(kind: TurretKind, position: Vector2) -> Turret {
+        let archetype = kind.archetype();
         Turret {
+            kind,
+            targeting: Targeting::Closest,
+            target: NO_ID,
+            tier: 0,
+            spent: archetype.build_cost(),
+            preset: false.into(),
             position,
             dead: false.into(),
             hover: false.into(),
-            fire_cooldown: FIRE_COOLDOWN,
+            fire_cooldown: archetype.tiers[0].fire_cooldown,
             id: 0,
             facing: Vector2::new(1.0, 0.0), // facing right
-            radius: 20.0,
+            radius: archetype.radius,
             anim: None,
         }
     }
 
-    pub fn update(&self, state: &State) -> TurretUpdate {
+    pub fn archetype(&self) -> &'static TurretArchetype {
+        self.kind.archetype()
+    }
+
+    // Range, damage and so on at the current tier
+    pub fn stats(&self) -> &'static Tier {
+        &self.archetype().tiers[self.tier as usize]
+    }
+
+    // The tier it can be upgraded to, if it isn't maxed out yet
+    pub fn next_tier(&self) -> Option<&'static Tier> {
+        self.archetype().tiers.get(self.tier as usize + 1)
+    }
+
+    // What selling it gets back
+    pub fn refund(&self) -> u32 {
+        (self.spent as f32 * SELL_REFUND) as u32
+    }
+}
+
+impl Behaviour for Turret {
+    type Update = TurretUpdate;
+
+    fn update(&self, state: &State, effects: &mut Effects) -> TurretUpdate {
         let mouse_pos = state.mouse_pos;
         let mouse_btn_pressed = state.mouse_btn_pressed;
         let dt = state.dt();
@@ -85,41 +400,79 @@ impl Turret {
         }
 
         if update.hover && mouse_btn_pressed.into() {
+            // open its panel
+            effects.click_used = true;
+            effects.selected = Some(self.id);
+        }
+
+        // the panel's buttons (and U) act on the selected turret
+        let selected = state.selected == self.id;
+        if selected && state.sell_pressed.into() {
             // despawn the turret
             update.dead = true;
         }
+        if let Some(next) = self.next_tier() {
+            let affordable = state.gold >= effects.gold_spent + next.cost;
+            if selected && affordable && state.upgrade_pressed.into() {
+                update.tier += 1;
+                update.spent += next.cost;
+                effects.gold_spent += next.cost;
+                effects.upgrades.push(TurretUpgrade {
+                    id: self.id,
+                    tier: update.tier,
+                    cost: next.cost,
+                });
+            }
+        }
 
         update.fire_cooldown -= dt;
-        if let Some(enemy) = state.man.closest_enemy(self.position) {
-            if self.position.dist(&enemy.position.into()) < ACTIVE_RADIUS {
-                update.facing = enemy.position.xy - self.position;
-                if update.fire_cooldown <= 0.0 {
-                    update.bullet_request = Some(BulletRequest {
-                        position: self.position,
-                        source: self.id,
-                        target: Some(enemy.id),
-                    });
-                    update.fire_cooldown = FIRE_COOLDOWN;
-                }
+        let stats = self.stats();
+        let target =
+            state
+                .man
+                .target_enemy(&state.paths, self.position, stats.range, self.targeting);
+        update.target = target.map_or(NO_ID, |enemy| enemy.id);
+        if let Some(enemy) = target {
+            update.facing = enemy.position.xy - self.position;
+            if update.fire_cooldown <= 0.0 {
+                let mut bullet = Bullet::aimed(
+                    stats.projectile,
+                    self.position,
+                    self.id,
+                    enemy,
+                    &state.paths,
+                );
+                bullet.damage = stats.damage;
+                bullet.anim = Some(state.bullet_anim.clone());
+                effects.spawns.push(bullet.into());
+                update.fire_cooldown = stats.fire_cooldown;
             }
         }
 
         update
     }
 
-    pub fn apply(&mut self, update: &TurretUpdate) {
+    fn apply(&mut self, update: &TurretUpdate) {
         debug_assert_eq!(self.id, update.id);
         self.dead = update.dead.into();
+        self.target = update.target;
+        self.tier = update.tier;
+        self.spent = update.spent;
         self.fire_cooldown = update.fire_cooldown;
         self.hover = update.hover.into();
         self.facing = update.facing;
     }
 
-    pub fn draw_debug(&self, _state: &State) {
-        webhacks::draw_circle(self.position, ACTIVE_RADIUS, ALPHA_BEIGE);
+    fn is_dead(&self) -> bool {
+        self.dead.into()
+    }
+
+    // Turrets don't move, so there is nothing to interpolate
+    fn draw_debug(&self, _state: &State, _alpha: f32) {
+        webhacks::draw_circle(self.position, self.stats().range, ALPHA_BEIGE);
     }
 
-    pub fn draw_foreground(&self, state: &State) {
+    fn draw_foreground(&self, state: &State, _alpha: f32) {
         let radius = if self.hover.into() {
             self.radius * 1.5
         } else {
@@ -141,6 +494,14 @@ impl Turret {
                 webhacks::draw_circle(self.position, radius, PINK);
             }
         }
+
+        // one pip per upgrade bought, in a row under the sprite
+        let pip = 3.0;
+        let left = self.position.x - (self.tier as f32 - 1.0) * 1.5 * pip;
+        for i in 0..self.tier {
+            let center = Vector2::new(left + i as f32 * 3.0 * pip, self.position.y + radius);
+            webhacks::draw_circle(center, pip, GOLD);
+        }
     }
 }
 
@@ -0,0 +1,294 @@
+// The level as a list of waves, each of them a few groups of enemies.
+//
+// A wave starts `pause` seconds after the previous one is cleared (or after the game starts,
+// for the first one). Once it has started, each of its groups waits `delay` seconds and then
+// spawns `count` enemies, one every `spacing` seconds. The wave is cleared once all of its
+// groups are done spawning and there are no enemies left. Clearing the last wave wins the game.
+//
+// Only the progress through the waves is game state. The waves themselves are data.
+
+use crate::enemy::EnemyKind;
+use crate::words::{DecodeError, Reader, Writer};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Group {
+    pub enemy: EnemyKind,
+    pub count: u32,
+    // seconds between two enemies of the group
+    pub spacing: f32,
+    // seconds from the start of the wave to the first enemy
+    pub delay: f32,
+    // distance along the path at which the enemies appear
+    pub spawn: f32,
+    // index of the path they appear on
+    pub path: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Wave {
+    // seconds between the previous wave being cleared and this one starting
+    pub pause: f32,
+    pub groups: Vec<Group>,
+}
+
+pub struct Waves {
+    pub waves: Vec<Wave>,
+    // index of the wave being played (or counted down to). `waves.len()` once all are cleared
+    pub current: usize,
+    // seconds since the current wave started. negative while counting down to it
+    pub wave_time: f32,
+    // how many enemies each group of the current wave has spawned so far
+    pub spawned: Vec<u32>,
+}
+
+pub struct WavesUpdate {
+    pub current: usize,
+    pub wave_time: f32,
+    pub spawned: Vec<u32>,
+    // (group, enemy kind, path, spawn distance) for each enemy to spawn this step
+    pub spawns: Vec<(usize, EnemyKind, usize, f32)>,
+    pub started: Option<usize>,
+    pub cleared: Option<usize>,
+}
+
+impl Waves {
+    pub fn new(waves: Vec<Wave>) -> Waves {
+        let mut waves = Waves {
+            waves,
+            current: 0,
+            wave_time: 0.0,
+            spawned: Vec::new(),
+        };
+        waves.enter(0);
+        waves
+    }
+
+    // Reset the progress to the countdown of wave `index`
+    fn enter(&mut self, index: usize) {
+        self.current = index;
+        self.wave_time = -self.waves.get(index).map_or(0.0, |w| w.pause);
+        self.spawned = vec![0; self.waves.get(index).map_or(0, |w| w.groups.len())];
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.current >= self.waves.len()
+    }
+
+    // Seconds until the current wave starts, if it hasn't yet
+    pub fn time_to_next(&self) -> Option<f32> {
+        if self.is_complete() || self.wave_time >= 0.0 {
+            None
+        } else {
+            Some(-self.wave_time)
+        }
+    }
+
+    // Advance by `dt`. `enemies_left` is whether there are any enemies on the field.
+    pub fn update(&self, dt: f32, enemies_left: bool) -> WavesUpdate {
+        let mut update = WavesUpdate {
+            current: self.current,
+            wave_time: self.wave_time,
+            spawned: self.spawned.clone(),
+            spawns: Vec::new(),
+            started: None,
+            cleared: None,
+        };
+        let Some(wave) = self.waves.get(self.current) else {
+            return update;
+        };
+
+        update.wave_time += dt;
+        if self.wave_time < 0.0 && update.wave_time >= 0.0 {
+            update.started = Some(self.current);
+        }
+        if update.wave_time < 0.0 {
+            return update;
+        }
+
+        for (i, group) in wave.groups.iter().enumerate() {
+            let since_first = update.wave_time - group.delay;
+            if since_first < 0.0 {
+                continue;
+            }
+            let due = if group.spacing > 0.0 {
+                ((since_first / group.spacing).floor() as u32).saturating_add(1)
+            } else {
+                group.count
+            };
+            let due = due.min(group.count);
+            for _ in update.spawned[i]..due {
+                update
+                    .spawns
+                    .push((i, group.enemy, group.path, group.spawn));
+            }
+            update.spawned[i] = update.spawned[i].max(due);
+        }
+
+        let all_spawned = wave
+            .groups
+            .iter()
+            .zip(update.spawned.iter())
+            .all(|(g, &n)| n >= g.count);
+        if all_spawned && update.spawns.is_empty() && !enemies_left {
+            update.cleared = Some(self.current);
+        }
+
+        update
+    }
+
+    pub fn apply(&mut self, update: &WavesUpdate) {
+        match update.cleared {
+            Some(index) => self.enter(index + 1),
+            None => {
+                self.current = update.current;
+                self.wave_time = update.wave_time;
+                self.spawned = update.spawned.clone();
+            }
+        }
+    }
+
+    // Progress only, see `save.rs`. The waves have to be the same ones on load.
+    pub fn write(&self, w: &mut Writer) {
+        w.u32(self.current as u32);
+        w.f32(self.wave_time);
+        w.u32(self.spawned.len() as u32);
+        self.spawned.iter().for_each(|&n| w.u32(n));
+    }
+
+    pub fn read_progress(&mut self, r: &mut Reader) -> Result<(), DecodeError> {
+        let current = r.u32()? as usize;
+        let wave_time = r.f32()?;
+        let n = r.len(1)?;
+        let spawned = (0..n).map(|_| r.u32()).collect::<Result<Vec<_>, _>>()?;
+
+        if current > self.waves.len() {
+            return Err(DecodeError::Invalid("wave index out of range"));
+        }
+        let n_groups = self.waves.get(current).map_or(0, |w| w.groups.len());
+        if spawned.len() != n_groups {
+            return Err(DecodeError::Invalid("wave group count doesn't match"));
+        }
+
+        self.current = current;
+        self.wave_time = wave_time;
+        self.spawned = spawned;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.1;
+
+    fn two_waves() -> Waves {
+        Waves::new(vec![
+            Wave {
+                pause: 1.0,
+                groups: vec![
+                    Group {
+                        enemy: EnemyKind::Slime,
+                        count: 3,
+                        spacing: 0.5,
+                        delay: 0.0,
+                        spawn: 0.0,
+                        path: 0,
+                    },
+                    Group {
+                        enemy: EnemyKind::Slime,
+                        count: 2,
+                        spacing: 0.0,
+                        delay: 1.0,
+                        spawn: 100.0,
+                        path: 1,
+                    },
+                ],
+            },
+            Wave {
+                pause: 2.0,
+                groups: vec![],
+            },
+        ])
+    }
+
+    // Run for `seconds`, returning the spawns
+    fn run(
+        waves: &mut Waves,
+        seconds: f32,
+        enemies_left: bool,
+    ) -> Vec<(usize, EnemyKind, usize, f32)> {
+        let mut spawns = Vec::new();
+        for _ in 0..(seconds / DT).round() as u32 {
+            let update = waves.update(DT, enemies_left);
+            spawns.extend(update.spawns.iter().cloned());
+            waves.apply(&update);
+        }
+        spawns
+    }
+
+    #[test]
+    fn test_groups_spawn_on_schedule() {
+        let mut waves = two_waves();
+        assert_eq!(waves.time_to_next(), Some(1.0));
+
+        // still counting down
+        assert!(run(&mut waves, 0.9, false).is_empty());
+
+        // the first of the first group, right as the wave starts
+        let spawns = run(&mut waves, 0.2, true);
+        assert_eq!(spawns, vec![(0, EnemyKind::Slime, 0, 0.0)]);
+        assert_eq!(waves.time_to_next(), None);
+
+        // the rest of the first group, and the whole second one at once
+        let spawns = run(&mut waves, 1.5, true);
+        assert_eq!(spawns.iter().filter(|s| s.0 == 0).count(), 2);
+        assert_eq!(spawns.iter().filter(|s| s.0 == 1).count(), 2);
+        assert!(spawns
+            .iter()
+            .filter(|s| s.0 == 1)
+            .all(|s| (s.2, s.3) == (1, 100.0)));
+        assert_eq!(waves.spawned, vec![3, 2]);
+
+        // not cleared while there are enemies left
+        run(&mut waves, 1.0, true);
+        assert_eq!(waves.current, 0);
+    }
+
+    #[test]
+    fn test_clearing_waves_completes_the_level() {
+        let mut waves = two_waves();
+        run(&mut waves, 3.0, true);
+        assert_eq!(waves.current, 0);
+
+        run(&mut waves, DT, false);
+        assert_eq!(waves.current, 1);
+        assert!(waves.time_to_next().is_some_and(|t| (t - 2.0).abs() < 1e-4));
+
+        // the empty wave is cleared as soon as it starts
+        run(&mut waves, 2.2, false);
+        assert!(waves.is_complete());
+        assert!(run(&mut waves, 5.0, false).is_empty());
+    }
+
+    #[test]
+    fn test_progress_round_trip() {
+        let mut waves = two_waves();
+        run(&mut waves, 1.6, true);
+
+        let mut w = Writer::new();
+        waves.write(&mut w);
+
+        let mut loaded = two_waves();
+        loaded.read_progress(&mut Reader::new(&w.words)).unwrap();
+        assert_eq!(loaded.current, waves.current);
+        assert_eq!(loaded.wave_time, waves.wave_time);
+        assert_eq!(loaded.spawned, waves.spawned);
+
+        // group counts not matching the waves
+        let mut bad = w.words.clone();
+        bad[2] = 1;
+        bad.truncate(4);
+        assert!(two_waves().read_progress(&mut Reader::new(&bad)).is_err());
+    }
+}
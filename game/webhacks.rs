@@ -1,4 +1,6 @@
-use raylib::{cstr, Color};
+#[cfg(any(feature = "web", feature = "native"))]
+use raylib::cstr;
+use raylib::Color;
 use raylib_wasm::{self as raylib};
 
 #[cfg(feature = "native")]
@@ -7,24 +9,27 @@ use crate::log::VaList;
 use crate::vec2::Vector2;
 
 #[cfg(feature = "web")]
-use std::ptr::addr_of;
+use std::ptr::{addr_of, addr_of_mut};
 
-#[cfg(feature = "web")]
+#[cfg(feature = "headless")]
+use crate::headless;
+
+#[cfg(any(feature = "web", feature = "headless"))]
 pub type Image = u32;
 #[cfg(feature = "native")]
 pub type Image = raylib::Image;
 
-#[cfg(feature = "web")]
+#[cfg(any(feature = "web", feature = "headless"))]
 pub type Music = u32;
 #[cfg(feature = "native")]
 pub type Music = raylib::Music;
 
-#[cfg(feature = "web")]
+#[cfg(any(feature = "web", feature = "headless"))]
 pub type Font = u32;
 #[cfg(feature = "native")]
 pub type Font = raylib::Font;
 
-#[cfg(feature = "web")]
+#[cfg(any(feature = "web", feature = "headless"))]
 pub type Texture = u32;
 #[cfg(feature = "native")]
 pub type Texture = raylib::Texture;
@@ -110,6 +115,10 @@ pub mod ffi {
         pub fn SetRandomSeed(seed: u32);
         pub fn GetRandomValue(min: i32, max: i32) -> i32;
         pub fn GetMousePosition() -> Vector2;
+        pub fn SaveFileData(file_path: *const i8, data: *const u8, size: usize) -> bool;
+        pub fn LoadFileData(file_path: *const i8, size: *mut usize) -> *mut u8;
+        pub fn FetchFile(file_path: *const i8) -> i32;
+        pub fn LoadFetchedFile(file_path: *const i8, size: *mut usize) -> *mut u8;
     }
 }
 
@@ -129,6 +138,18 @@ pub fn draw_texture_ex(
     unsafe {
         raylib::DrawTextureEx(texture, position.into(), rotation, scale, tint)
     }
+    #[cfg(feature = "headless")]
+    {
+        let _ = tint;
+        let shape = headless::get_texture_shape(texture);
+        let dest = raylib::Rectangle {
+            x: position.x,
+            y: position.y,
+            width: shape.x * scale,
+            height: shape.y * scale,
+        };
+        headless::draw_texture(texture, dest, rotation);
+    }
 }
 
 #[cfg(feature = "web")]
@@ -149,7 +170,7 @@ pub fn _console_log_args(msg: &str, args: Option<Vec<&str>>) {
         unsafe { ffi::ConsoleLog(cstr!(msg), c_args as *const i8) };
     }
     // we should not use this function in native mode, but lets not fall over
-    #[cfg(feature = "native")]
+    #[cfg(any(feature = "native", feature = "headless"))]
     panic!("console_log should not be called in native mode! use the game::log module instead");
 }
 
@@ -160,7 +181,7 @@ pub fn _console_log(msg: &str) {
     unsafe {
         ffi::ConsoleLog(cstr!(msg), std::ptr::null());
     };
-    #[cfg(feature = "native")]
+    #[cfg(any(feature = "native", feature = "headless"))]
     panic!("console_log should not be called in native mode! use the game::log module instead");
 }
 
@@ -173,6 +194,8 @@ pub fn log(level: i32, msg: &str) {
     unsafe {
         raylib::TraceLog(level, cstr!(msg));
     }
+    #[cfg(feature = "headless")]
+    headless::log(level, msg);
 }
 
 pub fn draw_text(font: Font, text: &str, position: Vector2, size: i32, spacing: f32, color: Color) {
@@ -198,6 +221,11 @@ pub fn draw_text(font: Font, text: &str, position: Vector2, size: i32, spacing:
             addr_of!(color),
         )
     }
+    #[cfg(feature = "headless")]
+    {
+        let _ = (font, spacing);
+        headless::draw_text(text, position, size, color);
+    }
 }
 
 pub fn update_music_stream(music: Music) {
@@ -209,6 +237,8 @@ pub fn update_music_stream(music: Music) {
     unsafe {
         raylib::UpdateMusicStream(music)
     };
+    #[cfg(feature = "headless")]
+    let _ = music;
 }
 
 pub fn get_texture_shape(texture: Texture) -> Vector2 {
@@ -224,6 +254,9 @@ pub fn get_texture_shape(texture: Texture) -> Vector2 {
             y: texture.height as f32,
         }
     }
+
+    #[cfg(feature = "headless")]
+    headless::get_texture_shape(texture)
 }
 
 pub fn is_mouse_button_down(button: i32) -> bool {
@@ -235,6 +268,8 @@ pub fn is_mouse_button_down(button: i32) -> bool {
     unsafe {
         raylib::IsMouseButtonDown(button)
     }
+    #[cfg(feature = "headless")]
+    headless::is_mouse_button_down(button)
 }
 
 pub fn is_mouse_button_pressed(button: i32) -> bool {
@@ -246,6 +281,8 @@ pub fn is_mouse_button_pressed(button: i32) -> bool {
     unsafe {
         raylib::IsMouseButtonPressed(button)
     }
+    #[cfg(feature = "headless")]
+    headless::is_mouse_button_pressed(button)
 }
 
 #[allow(dead_code)]
@@ -258,6 +295,8 @@ pub fn load_texture(file_path: &str) -> Texture {
     unsafe {
         raylib::LoadTexture(cstr!(file_path))
     }
+    #[cfg(feature = "headless")]
+    headless::load_texture(file_path)
 }
 
 pub fn load_font(file_path: &str) -> Font {
@@ -269,6 +308,8 @@ pub fn load_font(file_path: &str) -> Font {
     unsafe {
         raylib::LoadFont(cstr!(file_path))
     }
+    #[cfg(feature = "headless")]
+    headless::load_font(file_path)
 }
 
 pub fn play_music_stream(music: Music) {
@@ -280,6 +321,8 @@ pub fn play_music_stream(music: Music) {
     unsafe {
         raylib::PlayMusicStream(music)
     }
+    #[cfg(feature = "headless")]
+    let _ = music;
 }
 
 pub fn load_music_stream(file_path: &str) -> Music {
@@ -291,6 +334,8 @@ pub fn load_music_stream(file_path: &str) -> Music {
     unsafe {
         raylib::LoadMusicStream(cstr!(file_path))
     }
+    #[cfg(feature = "headless")]
+    headless::load_music_stream(file_path)
 }
 
 pub fn init_audio_device() {
@@ -313,6 +358,8 @@ pub fn get_time() -> f64 {
     unsafe {
         raylib::GetTime()
     }
+    #[cfg(feature = "headless")]
+    headless::get_time()
 }
 
 pub fn load_image_colors(image: Image) -> *mut Color {
@@ -320,6 +367,8 @@ pub fn load_image_colors(image: Image) -> *mut Color {
     return unsafe { ffi::LoadImageColors(image) };
     #[cfg(feature = "native")]
     return unsafe { raylib::LoadImageColors(image) };
+    #[cfg(feature = "headless")]
+    return headless::load_image_colors(image);
 }
 
 pub fn unload_image_colors(colors: *mut Color, #[allow(unused)] n: usize) {
@@ -331,6 +380,8 @@ pub fn unload_image_colors(colors: *mut Color, #[allow(unused)] n: usize) {
     unsafe {
         raylib::UnloadImageColors(colors);
     };
+    #[cfg(feature = "headless")]
+    headless::unload_image_colors(colors, n);
 }
 
 pub fn get_image_shape(image: Image) -> Vector2 {
@@ -341,6 +392,8 @@ pub fn get_image_shape(image: Image) -> Vector2 {
         x: image.width as f32,
         y: image.height as f32,
     };
+    #[cfg(feature = "headless")]
+    return headless::get_image_shape(image);
 }
 
 pub fn draw_texture_pro(
@@ -372,6 +425,11 @@ pub fn draw_texture_pro(
             raylib::RAYWHITE,
         );
     };
+    #[cfg(feature = "headless")]
+    {
+        let _ = (source_rec, origin);
+        headless::draw_texture(texture, dest_rec, rotation.to_degrees());
+    }
 }
 
 #[allow(dead_code)]
@@ -384,6 +442,8 @@ pub fn unload_image(image: Image) {
     unsafe {
         raylib::UnloadImage(image);
     };
+    #[cfg(feature = "headless")]
+    headless::unload_image(image);
 }
 
 pub fn load_texture_from_image(image: Image) -> Texture {
@@ -395,6 +455,8 @@ pub fn load_texture_from_image(image: Image) -> Texture {
     unsafe {
         return raylib::LoadTextureFromImage(image);
     };
+    #[cfg(feature = "headless")]
+    return headless::load_texture_from_image(image);
 }
 
 pub fn load_image(file_path: &str) -> Image {
@@ -406,6 +468,8 @@ pub fn load_image(file_path: &str) -> Image {
     unsafe {
         return raylib::LoadImage(cstr!(file_path));
     };
+    #[cfg(feature = "headless")]
+    return headless::load_image(file_path);
 }
 
 #[derive(PartialEq)]
@@ -438,6 +502,11 @@ pub fn get_music_status(#[allow(unused)] music: Music) -> MusicStatus {
     }
     #[cfg(feature = "native")]
     return MusicStatus::Loaded;
+    #[cfg(feature = "headless")]
+    return match headless::is_music_loaded(music) {
+        true => MusicStatus::Loaded,
+        false => MusicStatus::NotLoaded,
+    };
 }
 
 pub fn is_font_loaded(#[allow(unused)] font: Font) -> bool {
@@ -448,6 +517,8 @@ pub fn is_font_loaded(#[allow(unused)] font: Font) -> bool {
     return unsafe { ffi::IsFontLoaded(font) };
     #[cfg(feature = "native")]
     return true;
+    #[cfg(feature = "headless")]
+    return headless::is_font_loaded(font);
 }
 
 pub fn is_image_loaded(#[allow(unused)] image: Image) -> bool {
@@ -458,6 +529,8 @@ pub fn is_image_loaded(#[allow(unused)] image: Image) -> bool {
     return unsafe { ffi::IsImageLoaded(image) };
     #[cfg(feature = "native")]
     return true;
+    #[cfg(feature = "headless")]
+    return headless::is_image_loaded(image);
 }
 
 #[allow(unused)]
@@ -469,11 +542,13 @@ pub fn is_texture_loaded(texture: Texture) -> bool {
     return unsafe { ffi::IsTextureLoaded(texture) };
     #[cfg(feature = "native")]
     return texture.id != 0;
+    #[cfg(feature = "headless")]
+    return headless::is_texture_loaded(texture);
 }
 
 #[allow(unused)]
 pub fn null_font() -> Font {
-    #[cfg(feature = "web")]
+    #[cfg(any(feature = "web", feature = "headless"))]
     return 0;
     #[cfg(feature = "native")]
     return raylib::Font {
@@ -488,7 +563,7 @@ pub fn null_font() -> Font {
 
 #[allow(unused)]
 pub fn is_null_font(font: Font) -> bool {
-    #[cfg(feature = "web")]
+    #[cfg(any(feature = "web", feature = "headless"))]
     return font == 0;
     #[cfg(feature = "native")]
     return font.baseSize == 0;
@@ -496,7 +571,7 @@ pub fn is_null_font(font: Font) -> bool {
 
 #[allow(unused)]
 pub fn null_texture() -> Texture {
-    #[cfg(feature = "web")]
+    #[cfg(any(feature = "web", feature = "headless"))]
     return 0;
     #[cfg(feature = "native")]
     return raylib::Texture {
@@ -510,7 +585,7 @@ pub fn null_texture() -> Texture {
 
 #[allow(unused)]
 pub fn is_null_texture(texture: Texture) -> bool {
-    #[cfg(feature = "web")]
+    #[cfg(any(feature = "web", feature = "headless"))]
     return texture == 0;
     #[cfg(feature = "native")]
     return texture.id == 0;
@@ -518,7 +593,7 @@ pub fn is_null_texture(texture: Texture) -> bool {
 
 #[allow(unused)]
 pub fn null_music() -> Music {
-    #[cfg(feature = "web")]
+    #[cfg(any(feature = "web", feature = "headless"))]
     return 0;
     #[cfg(feature = "native")]
     return raylib::Music {
@@ -538,7 +613,7 @@ pub fn null_music() -> Music {
 
 #[allow(unused)]
 pub fn is_null_music(music: Music) -> bool {
-    #[cfg(feature = "web")]
+    #[cfg(any(feature = "web", feature = "headless"))]
     return music == 0;
     #[cfg(feature = "native")]
     return music.stream.buffer == std::ptr::null_mut();
@@ -546,7 +621,7 @@ pub fn is_null_music(music: Music) -> bool {
 
 #[allow(unused)]
 pub fn null_image() -> Image {
-    #[cfg(feature = "web")]
+    #[cfg(any(feature = "web", feature = "headless"))]
     return 0;
     #[cfg(feature = "native")]
     return raylib::Image {
@@ -560,7 +635,7 @@ pub fn null_image() -> Image {
 
 #[allow(unused)]
 pub fn is_null_image(image: Image) -> bool {
-    #[cfg(feature = "web")]
+    #[cfg(any(feature = "web", feature = "headless"))]
     return image == 0;
     #[cfg(feature = "native")]
     return image.data == std::ptr::null_mut();
@@ -580,10 +655,82 @@ pub fn draw_line_ex(start_pos: Vector2, end_pos: Vector2, thickness: f32, color:
     unsafe {
         raylib::DrawLineEx(start_pos.into(), end_pos.into(), thickness, color);
     }
+    #[cfg(feature = "headless")]
+    headless::draw_line_ex(start_pos, end_pos, thickness, color);
 }
 
 pub fn draw_circle(position: Vector2, radius: f32, color: Color) {
-    unsafe { raylib::DrawCircle(position.x as i32, position.y as i32, radius, color) }
+    #[cfg(any(feature = "web", feature = "native"))]
+    unsafe {
+        raylib::DrawCircle(position.x as i32, position.y as i32, radius, color)
+    }
+    #[cfg(feature = "headless")]
+    headless::draw_circle(position, radius, color);
+}
+
+pub fn draw_rectangle(x: i32, y: i32, width: i32, height: i32, color: Color) {
+    #[cfg(any(feature = "web", feature = "native"))]
+    unsafe {
+        raylib::DrawRectangle(x, y, width, height, color)
+    }
+    #[cfg(feature = "headless")]
+    headless::draw_rectangle(x, y, width, height, color);
+}
+
+pub fn clear_background(color: Color) {
+    #[cfg(any(feature = "web", feature = "native"))]
+    unsafe {
+        raylib::ClearBackground(color)
+    }
+    #[cfg(feature = "headless")]
+    headless::clear_background(color);
+}
+
+pub fn begin_drawing() {
+    #[cfg(any(feature = "web", feature = "native"))]
+    unsafe {
+        raylib::BeginDrawing()
+    }
+}
+
+pub fn end_drawing() {
+    #[cfg(any(feature = "web", feature = "native"))]
+    unsafe {
+        raylib::EndDrawing()
+    }
+}
+
+pub fn init_window(width: i32, height: i32, title: &str) {
+    #[cfg(any(feature = "web", feature = "native"))]
+    raylib::init_window(width, height, title);
+    #[cfg(feature = "headless")]
+    let _ = (width, height, title);
+}
+
+pub fn close_window() {
+    #[cfg(any(feature = "web", feature = "native"))]
+    unsafe {
+        raylib::CloseWindow()
+    }
+}
+
+#[allow(dead_code)]
+pub fn get_frame_time() -> f32 {
+    #[cfg(any(feature = "web", feature = "native"))]
+    unsafe {
+        raylib::GetFrameTime()
+    }
+    #[cfg(feature = "headless")]
+    headless::get_frame_time()
+}
+
+pub fn is_key_down(key: raylib::KeyboardKey) -> bool {
+    #[cfg(any(feature = "web", feature = "native"))]
+    unsafe {
+        raylib::IsKeyDown(key)
+    }
+    #[cfg(feature = "headless")]
+    headless::is_key_down(key as i32)
 }
 
 pub fn get_mouse_position() -> Vector2 {
@@ -592,7 +739,11 @@ pub fn get_mouse_position() -> Vector2 {
         Vector2::from(ffi::GetMousePosition())
     }
     #[cfg(feature = "native")]
-    unsafe { raylib::GetMousePosition() }.into()
+    {
+        unsafe { raylib::GetMousePosition() }.into()
+    }
+    #[cfg(feature = "headless")]
+    headless::get_mouse_position()
 }
 
 pub fn set_music_volume(music: Music, volume: f32) {
@@ -604,6 +755,8 @@ pub fn set_music_volume(music: Music, volume: f32) {
     unsafe {
         raylib::SetMusicVolume(music, volume);
     }
+    #[cfg(feature = "headless")]
+    let _ = (music, volume);
 }
 
 pub fn is_key_pressed(key: raylib::KeyboardKey) -> bool {
@@ -615,6 +768,8 @@ pub fn is_key_pressed(key: raylib::KeyboardKey) -> bool {
     unsafe {
         raylib::IsKeyPressed(key)
     }
+    #[cfg(feature = "headless")]
+    headless::is_key_pressed(key as i32)
 }
 
 pub fn measure_text(font: Font, text: &str, font_size: i32, spacing: f32) -> Vector2 {
@@ -623,7 +778,14 @@ pub fn measure_text(font: Font, text: &str, font_size: i32, spacing: f32) -> Vec
         ffi::MeasureTextEx(font, cstr!(text), font_size, spacing).into()
     }
     #[cfg(feature = "native")]
-    unsafe { raylib::MeasureTextEx(font, cstr!(text), font_size as f32, spacing) }.into()
+    {
+        unsafe { raylib::MeasureTextEx(font, cstr!(text), font_size as f32, spacing) }.into()
+    }
+    #[cfg(feature = "headless")]
+    {
+        let _ = font;
+        headless::measure_text(text, font_size, spacing)
+    }
 }
 
 #[cfg(feature = "native")]
@@ -632,6 +794,9 @@ pub type LogCallback = unsafe extern "C" fn(i32, *const i8, *mut VaList);
 #[cfg(feature = "web")]
 pub type LogCallback = fn(i32, *const i8);
 
+#[cfg(feature = "headless")]
+pub type LogCallback = headless::LogCallback;
+
 #[allow(unused)]
 pub fn set_trace_log_callback(callback: Option<LogCallback>, callback_name: &str) {
     #[cfg(feature = "web")]
@@ -643,6 +808,9 @@ pub fn set_trace_log_callback(callback: Option<LogCallback>, callback_name: &str
     unsafe {
         raylib::SetTraceLogCallback(callback);
     }
+
+    #[cfg(feature = "headless")]
+    headless::set_log_callback(callback);
 }
 
 #[allow(unused)]
@@ -655,6 +823,8 @@ pub fn set_log_level(level: i32) {
     unsafe {
         raylib::SetTraceLogLevel(level);
     }
+    #[cfg(feature = "headless")]
+    headless::set_log_level(level);
 }
 
 pub fn set_random_seed(seed: u32) {
@@ -666,9 +836,12 @@ pub fn set_random_seed(seed: u32) {
     unsafe {
         raylib::SetRandomSeed(seed);
     }
+    #[cfg(feature = "headless")]
+    headless::set_random_seed(seed);
 }
 
 // inclusive
+#[allow(dead_code)]
 pub fn get_random_value(min: i32, max: i32) -> i32 {
     #[cfg(feature = "web")]
     {
@@ -678,4 +851,94 @@ pub fn get_random_value(min: i32, max: i32) -> i32 {
     {
         unsafe { raylib::GetRandomValue(min, max) }
     }
+    #[cfg(feature = "headless")]
+    {
+        headless::get_random_value(min, max)
+    }
+}
+
+// Write a whole file. On the web this goes to the browser's local storage.
+pub fn save_file_data(file_path: &str, data: &[u8]) -> bool {
+    #[cfg(feature = "web")]
+    unsafe {
+        ffi::SaveFileData(cstr!(file_path), data.as_ptr(), data.len())
+    }
+    #[cfg(feature = "native")]
+    {
+        std::fs::write(file_path, data).is_ok()
+    }
+    #[cfg(feature = "headless")]
+    {
+        headless::save_file_data(file_path, data);
+        true
+    }
+}
+
+pub enum FileFetch {
+    // only ever on the web
+    #[allow(dead_code)]
+    Pending,
+    Ready(Vec<u8>),
+    Failed,
+}
+
+// Read a file that ships with the game, like the images. On the web it is fetched from the
+// server like the other assets, so it can take a few calls to be `Ready`, unless a copy was
+// written with `save_file_data`: that one is in local storage and wins over the server's.
+// Everywhere else it is read right away.
+pub fn fetch_file(file_path: &str) -> FileFetch {
+    #[cfg(feature = "web")]
+    {
+        if let Some(data) = load_file_data(file_path) {
+            return FileFetch::Ready(data);
+        }
+        match unsafe { ffi::FetchFile(cstr!(file_path)) } {
+            0 => FileFetch::Pending,
+            1 => {
+                let mut size: usize = 0;
+                let ptr = unsafe { ffi::LoadFetchedFile(cstr!(file_path), addr_of_mut!(size)) };
+                let data = unsafe { std::slice::from_raw_parts(ptr, size) }.to_vec();
+                crate::from_js_free(ptr, size);
+                FileFetch::Ready(data)
+            }
+            _ => FileFetch::Failed,
+        }
+    }
+    #[cfg(feature = "native")]
+    {
+        match std::fs::read(file_path) {
+            Ok(data) => FileFetch::Ready(data),
+            Err(_) => FileFetch::Failed,
+        }
+    }
+    #[cfg(feature = "headless")]
+    {
+        match headless::load_file_data(file_path) {
+            Some(data) => FileFetch::Ready(data),
+            None => FileFetch::Failed,
+        }
+    }
+}
+
+// Read a whole file. None if it does not exist (or can't be read).
+pub fn load_file_data(file_path: &str) -> Option<Vec<u8>> {
+    #[cfg(feature = "web")]
+    {
+        let mut size: usize = 0;
+        let ptr = unsafe { ffi::LoadFileData(cstr!(file_path), addr_of_mut!(size)) };
+        if ptr.is_null() {
+            return None;
+        }
+        let data = unsafe { std::slice::from_raw_parts(ptr, size) }.to_vec();
+        crate::from_js_free(ptr, size);
+        Some(data)
+    }
+    #[cfg(feature = "native")]
+    {
+        std::fs::read(file_path).ok()
+    }
+    #[cfg(feature = "headless")]
+    {
+        headless::load_file_data(file_path)
+    }
 }
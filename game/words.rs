@@ -0,0 +1,93 @@
+// Little helpers for flat u32-word encodings (see `save.rs` and `EntityManager::to_state`).
+// Floats go in as their bits and bools as 0/1, so everything is a plain u32.
+
+use crate::vec2::Vector2;
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "data is truncated"),
+            DecodeError::Invalid(what) => write!(f, "data is invalid: {}", what),
+        }
+    }
+}
+
+pub struct Writer {
+    pub words: Vec<u32>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { words: Vec::new() }
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.words.push(value);
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.words.push(value.to_bits());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.words.push(value as u32);
+    }
+
+    pub fn vec2(&mut self, value: Vector2) {
+        self.f32(value.x);
+        self.f32(value.y);
+    }
+}
+
+pub struct Reader<'a> {
+    words: &'a [u32],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(words: &'a [u32]) -> Reader<'a> {
+        Reader { words, offset: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.words.len() - self.offset
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        let value = *self.words.get(self.offset).ok_or(DecodeError::Truncated)?;
+        self.offset += 1;
+        Ok(value)
+    }
+
+    pub fn f32(&mut self) -> Result<f32, DecodeError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.u32()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Invalid("bool is neither 0 nor 1")),
+        }
+    }
+
+    pub fn vec2(&mut self) -> Result<Vector2, DecodeError> {
+        Ok(Vector2::new(self.f32()?, self.f32()?))
+    }
+
+    // Length prefix for an array of `item_size` words. Checked against what is left in the
+    // buffer so a corrupt length can't make us allocate the world.
+    pub fn len(&mut self, item_size: usize) -> Result<usize, DecodeError> {
+        let len = self.u32()? as usize;
+        if len.saturating_mul(item_size) > self.remaining() {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(len)
+    }
+}
@@ -179,6 +179,7 @@ function tryToPlayAudio() {
 }
 
 let IMAGES = new Map();
+let FETCHED_FILES = new Map();
 let TEXTURES = new Map();
 let FONTS = new Map();
 
@@ -907,6 +908,78 @@ let OtherFuncs = {
         CTX.closePath();
         CTX.lineWidth = 1;
     },
+    // pub fn SaveFileData(file_path: *const i8, data: *const u8, size: usize) -> bool;
+    SaveFileData: (file_path_ptr, data_ptr, size) => {
+        const buffer = WF.memory.buffer;
+        const file_path = getString(buffer, file_path_ptr);
+        const data = new Uint8Array(buffer, data_ptr, size);
+        // local storage only holds strings
+        let binary = "";
+        for (let i = 0; i < data.length; i++) binary += String.fromCharCode(data[i]);
+        try {
+            localStorage.setItem("file:" + file_path, btoa(binary));
+        } catch (e) {
+            error("SaveFileData failed: file_path={0}, error={1}".format(file_path, e));
+            return false;
+        }
+        info("Saved file: file_path={0}, size={1}".format(file_path, size));
+        return true;
+    },
+    // pub fn LoadFileData(file_path: *const i8, size: *mut usize) -> *mut u8;
+    LoadFileData: (file_path_ptr, size_ptr) => {
+        const file_path = getString(WF.memory.buffer, file_path_ptr);
+        const stored = localStorage.getItem("file:" + file_path);
+        if (stored === null) {
+            return 0;
+        }
+        const binary = atob(stored);
+        // an empty file still gets a (dangling, non-null) pointer, see from_js_malloc
+        const ptr = WF.from_js_malloc(binary.length);
+        // NOTE: re-fetch the buffer. malloc might have grown the memory
+        const data = new Uint8Array(WF.memory.buffer, ptr, binary.length);
+        for (let i = 0; i < binary.length; i++) data[i] = binary.charCodeAt(i);
+        new Uint32Array(WF.memory.buffer, size_ptr, 1)[0] = binary.length;
+        info("Loaded file: file_path={0}, size={1}".format(file_path, binary.length));
+        return ptr;
+    },
+    // pub fn FetchFile(file_path: *const i8) -> i32;
+    // Starts the download on the first call. 0 while in flight, 1 once the data is there to be
+    // taken with LoadFetchedFile, -1 if it failed.
+    FetchFile: (file_path_ptr) => {
+        const file_path = getString(WF.memory.buffer, file_path_ptr);
+        let fetched = FETCHED_FILES.get(file_path);
+        if (fetched === undefined) {
+            info("Fetching file: file_path={0}".format(file_path));
+            fetched = { status: 0, data: null };
+            FETCHED_FILES.set(file_path, fetched);
+            fetch(file_path)
+                .then(response => {
+                    if (!response.ok) throw new Error(response.status);
+                    return response.arrayBuffer();
+                })
+                .then(buffer => {
+                    fetched.data = new Uint8Array(buffer);
+                    fetched.status = 1;
+                })
+                .catch(e => {
+                    error("FetchFile failed: file_path={0}, error={1}".format(file_path, e));
+                    fetched.status = -1;
+                });
+        }
+        return fetched.status;
+    },
+    // pub fn LoadFetchedFile(file_path: *const i8, size: *mut usize) -> *mut u8;
+    LoadFetchedFile: (file_path_ptr, size_ptr) => {
+        const file_path = getString(WF.memory.buffer, file_path_ptr);
+        const data = FETCHED_FILES.get(file_path).data;
+        FETCHED_FILES.delete(file_path);
+        // an empty file still gets a (dangling, non-null) pointer, see from_js_malloc
+        const ptr = WF.from_js_malloc(data.length);
+        // NOTE: re-fetch the buffer. malloc might have grown the memory
+        new Uint8Array(WF.memory.buffer, ptr, data.length).set(data);
+        new Uint32Array(WF.memory.buffer, size_ptr, 1)[0] = data.length;
+        return ptr;
+    },
     // pub fn SetRandomSeed(seed: u32);
     SetRandomSeed: (seed) => set_seed(seed),
     // pub fn GetRandomValue(min: i32, max: i32) -> i32
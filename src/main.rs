@@ -5,11 +5,28 @@ use game;
 
 #[cfg(feature = "native")]
 mod native_runner {
-    use libloading::{Library, Symbol};
+    use std::alloc::Layout;
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+    use std::mem::ManuallyDrop;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant, SystemTime};
 
-    use raylib_wasm::{IsKeyPressed, KeyboardKey as Key, WindowShouldClose};
+    use libloading::Library;
 
-    use game::{GameFrame, GameInit, GameLoad};
+    use raylib_wasm::{
+        BeginDrawing, ClearBackground, DrawText, EndDrawing, IsKeyPressed, KeyboardKey as Key,
+        WindowShouldClose, MAROON, RAYWHITE,
+    };
+
+    use game::input::{self, FrameInput};
+    use game::layout::StateLayout;
+    use game::{
+        GameFrameChecked, GameInitSeeded, GameLoadChecked, GameMigrateChecked,
+        GamePollInputChecked, GameReloaded, GameSaveChecked, GameStateLayout, State,
+        SNAPSHOT_FRAMES,
+    };
 
     const fn get_game_path() -> &'static str {
         #[cfg(target_os = "linux")]
@@ -40,19 +57,282 @@ mod native_runner {
 
     const GAME_PATH: &str = get_game_path();
 
-    #[inline]
-    fn load_lib(file_path: &str) -> Library {
-        unsafe { Library::new(file_path).expect("failed to load the library") }
+    // A copy of the game library, loaded from a temp file. Loading the copy rather than
+    // GAME_PATH itself leaves the original free for the linker to overwrite while we run, and
+    // means the new build gets a path the loader hasn't seen yet, so we can have the old and
+    // the new one loaded at the same time.
+    struct TempLib {
+        lib: ManuallyDrop<Library>,
+        path: PathBuf,
+    }
+
+    impl TempLib {
+        fn load(file_path: &str) -> Result<TempLib, String> {
+            static COPIES: AtomicU32 = AtomicU32::new(0);
+            let n = COPIES.fetch_add(1, Ordering::Relaxed);
+            let ext = Path::new(file_path)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .unwrap_or("so");
+            let path =
+                std::env::temp_dir().join(format!("libgame-{}-{}.{}", std::process::id(), n, ext));
+
+            std::fs::copy(file_path, &path).map_err(|err| {
+                format!("failed to copy {file_path} to {}: {err}", path.display())
+            })?;
+            match unsafe { Library::new(&path) } {
+                Ok(lib) => Ok(TempLib {
+                    lib: ManuallyDrop::new(lib),
+                    path,
+                }),
+                Err(err) => {
+                    let _ = std::fs::remove_file(&path);
+                    Err(format!("failed to load {file_path}: {err}"))
+                }
+            }
+        }
+    }
+
+    impl Drop for TempLib {
+        fn drop(&mut self) {
+            // unload before removing the file (windows won't delete a loaded dll)
+            unsafe { ManuallyDrop::drop(&mut self.lib) };
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn load_fn<T: Copy>(lib: &Library, symbol: &str) -> Result<T, String> {
+        unsafe { lib.get::<T>(symbol.as_bytes()) }
+            .map(|f| *f)
+            .map_err(|err| format!("{symbol}: {err}"))
+    }
+
+    // The loaded library and the functions we need from it. The fn pointers are only valid
+    // for as long as `lib` is alive. Everything that runs game code is a `_checked` export, so
+    // a panic comes back as an error instead of unwinding into us.
+    struct Game {
+        poll_input: GamePollInputChecked,
+        frame: GameFrameChecked,
+        load: GameLoadChecked,
+        save: GameSaveChecked,
+        state_layout: GameStateLayout,
+        migrate: Option<GameMigrateChecked>,
+        reloaded: Option<GameReloaded>,
+        lib: TempLib,
     }
 
-    #[inline]
-    fn load_fn<'lib, T>(lib: &'lib Library, symbol: &str) -> Symbol<'lib, T> {
-        unsafe { lib.get(symbol.as_bytes()) }
-            .map_err(|err| {
-                eprintln!("{err}");
-                err
+    impl Game {
+        fn load(file_path: &str) -> Result<Game, String> {
+            let lib = TempLib::load(file_path)?;
+            Ok(Game {
+                poll_input: load_fn(&lib.lib, "game_poll_input_checked")?,
+                frame: load_fn(&lib.lib, "game_frame_checked")?,
+                load: load_fn(&lib.lib, "game_load_checked")?,
+                save: load_fn(&lib.lib, "game_save_checked")?,
+                state_layout: load_fn(&lib.lib, "game_state_layout")?,
+                migrate: load_fn(&lib.lib, "game_migrate_checked").ok(),
+                reloaded: load_fn(&lib.lib, "game_reloaded").ok(),
+                lib,
             })
-            .unwrap()
+        }
+    }
+
+    // The state lives in a buffer sized by the library rather than by the `State` this runner
+    // was compiled against, so that a reload can swap it for one with a different layout. The
+    // contents are never dropped: their drop glue belongs to whichever build created them.
+    struct StateBuf {
+        ptr: *mut State,
+        layout: Layout,
+    }
+
+    impl StateBuf {
+        fn new(state_layout: &StateLayout) -> StateBuf {
+            let layout = Layout::from_size_align(
+                state_layout.state_size as usize,
+                state_layout.state_align as usize,
+            )
+            .expect("bad state layout");
+            let ptr = unsafe { std::alloc::alloc_zeroed(layout) } as *mut State;
+            assert!(!ptr.is_null(), "failed to allocate the state");
+            StateBuf { ptr, layout }
+        }
+
+        // `all_loaded` is the first field in every build (the web side relies on this too)
+        fn all_loaded(&self) -> bool {
+            unsafe { *(self.ptr as *const u32) != 0 }
+        }
+    }
+
+    impl Drop for StateBuf {
+        fn drop(&mut self) {
+            unsafe { std::alloc::dealloc(self.ptr as *mut u8, self.layout) }
+        }
+    }
+
+    // Swap in a fresh build of the game. If the state layout changed, the new build gets to
+    // rebuild the state from a save of the old one. If it can't, or the new build doesn't load
+    // at all, we keep running the old one.
+    fn reload(game: Game, state: StateBuf) -> (Game, StateBuf) {
+        let new_game = match Game::load(GAME_PATH) {
+            Ok(new_game) => new_game,
+            Err(err) => {
+                eprintln!("Reload failed, keeping the old build: {err}");
+                return (game, state);
+            }
+        };
+
+        let old_layout = (game.state_layout)();
+        let new_layout = (new_game.state_layout)();
+        if new_layout == old_layout {
+            drop(game);
+            if let Some(reloaded) = new_game.reloaded {
+                reloaded();
+            }
+            println!("Reloaded {GAME_PATH}");
+            return (new_game, state);
+        }
+
+        println!("State layout changed on reload: {old_layout} -> {new_layout}");
+        let Some(migrate) = new_game.migrate else {
+            eprintln!("{GAME_PATH} does not export game_migrate_checked. Refusing the reload, keeping the old build.");
+            return (game, state);
+        };
+
+        // both point at states sized and aligned for their builds, see StateBuf
+        let saved = match unsafe { (game.save)(state.ptr) } {
+            Ok(saved) => saved,
+            Err(message) => {
+                eprintln!(
+                    "game_save panicked, refusing the reload and keeping the old build:\n{message}"
+                );
+                return (game, state);
+            }
+        };
+        let new_state = StateBuf::new(&new_layout);
+        if let Err(message) = unsafe { migrate(&old_layout, &saved, new_state.ptr) } {
+            eprintln!("game_migrate could not carry the state over, refusing the reload and keeping the old build:\n{message}");
+            return (game, state);
+        }
+
+        // old contents are leaked on purpose, see StateBuf
+        drop(state);
+        drop(game);
+        if let Some(reloaded) = new_game.reloaded {
+            reloaded();
+        }
+        println!("Reloaded {GAME_PATH} and migrated the state to the new layout");
+        (new_game, new_state)
+    }
+
+    // Polls GAME_PATH for a new build. cargo writes the library in several goes, so a change
+    // only counts once the file has stopped changing for SETTLE_TIME.
+    struct Watcher {
+        loaded: Option<(SystemTime, u64)>,
+        pending: Option<(SystemTime, u64, Instant)>,
+        last_poll: Instant,
+    }
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+    const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+    impl Watcher {
+        fn new() -> Watcher {
+            Watcher {
+                loaded: Watcher::stat(),
+                pending: None,
+                last_poll: Instant::now(),
+            }
+        }
+
+        fn stat() -> Option<(SystemTime, u64)> {
+            let meta = std::fs::metadata(GAME_PATH).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        }
+
+        // Whatever is on disk now counts as loaded
+        fn mark_loaded(&mut self) {
+            self.loaded = Watcher::stat();
+            self.pending = None;
+        }
+
+        // True once there is a new, completely written build to load
+        fn poll(&mut self) -> bool {
+            if self.last_poll.elapsed() < POLL_INTERVAL {
+                return false;
+            }
+            self.last_poll = Instant::now();
+
+            // missing while the linker replaces it
+            let Some((modified, len)) = Watcher::stat() else {
+                self.pending = None;
+                return false;
+            };
+            if self.loaded == Some((modified, len)) {
+                self.pending = None;
+                return false;
+            }
+
+            match self.pending {
+                Some((m, l, since)) if m == modified && l == len => since.elapsed() >= SETTLE_TIME,
+                _ => {
+                    self.pending = Some((modified, len, Instant::now()));
+                    false
+                }
+            }
+        }
+    }
+
+    // Shown instead of the game after a frame panicked, until the next reload
+    fn draw_error_screen(message: &str) {
+        let text = format!(
+            "The game panicked:\n\n{message}\n\nFix it and rebuild, or press F5 to reload."
+        );
+        let text = std::ffi::CString::new(text.replace('\0', "")).unwrap();
+        unsafe {
+            BeginDrawing();
+            ClearBackground(MAROON);
+            DrawText(text.as_ptr(), 20, 20, 20, RAYWHITE);
+            EndDrawing();
+        }
+    }
+
+    enum InputMode {
+        Live,
+        Record(BufWriter<File>),
+        Replay(std::vec::IntoIter<FrameInput>),
+    }
+
+    struct Args {
+        seed: u32,
+        record: Option<String>,
+        replay: Option<String>,
+    }
+
+    // cargo run --features=native -- [--seed <n>] [--record <file> | --replay <file>]
+    fn parse_args() -> Args {
+        let mut args = Args {
+            seed: game::DEFAULT_SEED,
+            record: None,
+            replay: None,
+        };
+        let mut it = std::env::args().skip(1);
+        while let Some(arg) = it.next() {
+            match arg.as_str() {
+                "--seed" => {
+                    args.seed = it
+                        .next()
+                        .and_then(|s| s.parse().ok())
+                        .expect("--seed needs a number");
+                }
+                "--record" => args.record = Some(it.next().expect("--record needs a file")),
+                "--replay" => args.replay = Some(it.next().expect("--replay needs a file")),
+                _ => panic!("unknown argument: {arg}"),
+            }
+        }
+        if args.record.is_some() && args.replay.is_some() {
+            panic!("--record and --replay are mutually exclusive");
+        }
+        args
     }
 
     pub fn run() {
@@ -62,32 +342,116 @@ mod native_runner {
         // log::set_trace_log_callback();
         // log::set_log_level(log::ALL);
 
-        let mut lib = load_lib(GAME_PATH);
+        let args = parse_args();
 
-        let mut game_frame = load_fn::<Symbol<GameFrame>>(&lib, "game_frame");
-        let mut game_load = load_fn::<Symbol<GameLoad>>(&lib, "game_load");
-        let game_init = load_fn::<Symbol<GameInit>>(&lib, "game_init");
+        let mut seed = args.seed;
+        let mut mode = InputMode::Live;
+        if let Some(path) = &args.replay {
+            let bytes = std::fs::read(path)
+                .unwrap_or_else(|err| panic!("failed to read recording {path}: {err}"));
+            let recording = input::decode_recording(&bytes)
+                .unwrap_or_else(|err| panic!("failed to load recording {path}: {err}"));
+            println!(
+                "Replaying {path}: {} frames, seed {}",
+                recording.frames.len(),
+                recording.seed
+            );
+            seed = recording.seed;
+            mode = InputMode::Replay(recording.frames.into_iter());
+        } else if let Some(path) = &args.record {
+            let file = File::create(path)
+                .unwrap_or_else(|err| panic!("failed to create recording {path}: {err}"));
+            let mut writer = BufWriter::new(file);
+            writer
+                .write_all(&input::encode_header(seed))
+                .expect("failed to write recording header");
+            println!("Recording input to {path}");
+            mode = InputMode::Record(writer);
+        }
 
-        let mut state = game_init();
+        let mut watcher = Watcher::new();
+        let mut game = Game::load(GAME_PATH).unwrap_or_else(|err| panic!("{err}"));
+
+        // `game_init_seeded` returns a `State` by value, so to start with the library has to
+        // agree with the `State` this runner was compiled against
+        let lib_layout = (game.state_layout)();
+        let our_layout = game::layout::state_layout();
+        if lib_layout != our_layout {
+            panic!("{GAME_PATH} has state layout {lib_layout} but the runner expects {our_layout}. Rebuild the runner.");
+        }
+
+        let mut state = StateBuf::new(&lib_layout);
+        {
+            let game_init_seeded: GameInitSeeded =
+                load_fn(&game.lib.lib, "game_init_seeded").unwrap_or_else(|err| panic!("{err}"));
+            unsafe { std::ptr::write(state.ptr, game_init_seeded(seed)) };
+        }
 
         // log::user("Starting game loop");
 
-        // log::user("Press 'P' to hot-reload the game");
+        // The game reloads by itself when a new build lands in GAME_PATH. F5 forces a reload.
+        // (F5 since the letter keys belong to the game.)
+        let mut crashed: Option<String> = None;
         while !unsafe { WindowShouldClose() } {
-            if unsafe { IsKeyPressed(Key::P) } {
-                drop(game_frame);
-                drop(game_load);
-                drop(lib);
-                lib = load_lib(GAME_PATH);
-                game_frame = load_fn(&lib, "game_frame");
-                game_load = load_fn(&lib, "game_load");
+            if watcher.poll() || unsafe { IsKeyPressed(Key::F5) } {
+                watcher.mark_loaded();
+                (game, state) = reload(game, state);
+                crashed = None;
             }
 
-            if state.all_loaded.into() {
-                game_frame(&mut state);
-            } else {
-                game_load(&mut state);
+            if let Some(message) = &crashed {
+                // nothing runs until the next reload. A panicking frame has already put the
+                // state back to its last snapshot, anything else left it as it was.
+                draw_error_screen(message);
+                continue;
             }
+
+            if state.all_loaded() {
+                let input = match &mut mode {
+                    InputMode::Replay(frames) => frames.next(),
+                    _ => None,
+                };
+                let input = match input {
+                    Some(input) => input,
+                    None => {
+                        if let InputMode::Replay(_) = mode {
+                            println!("Replay finished. Switching to live input");
+                            mode = InputMode::Live;
+                        }
+                        // the state is ours, see StateBuf
+                        match unsafe { (game.poll_input)(state.ptr) } {
+                            Ok(input) => input,
+                            Err(message) => {
+                                eprintln!("Polling the input panicked, stopping until the next reload:\n{message}");
+                                crashed = Some(message);
+                                continue;
+                            }
+                        }
+                    }
+                };
+
+                if let InputMode::Record(writer) = &mut mode {
+                    writer
+                        .write_all(&input::encode_frame(&input))
+                        .expect("failed to write recording");
+                }
+
+                if let Err(message) = unsafe { (game.frame)(state.ptr, &input) } {
+                    eprintln!("Frame panicked, stopping until the next reload:\n{message}");
+                    // the frame put the state back to its last snapshot, not to the start of
+                    // the frame that panicked
+                    crashed = Some(format!(
+                        "{message}\n\nThe game went back to where it was up to {SNAPSHOT_FRAMES} frames before the panic."
+                    ));
+                }
+            } else if let Err(message) = (game.load)(state.ptr) {
+                eprintln!("Loading panicked, stopping until the next reload:\n{message}");
+                crashed = Some(message);
+            }
+        }
+
+        if let InputMode::Record(mut writer) = mode {
+            writer.flush().expect("failed to flush recording");
         }
     }
 }